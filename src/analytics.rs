@@ -0,0 +1,283 @@
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::{core_types::OrderBookState, upstream_types::Market};
+
+const BPS: Decimal = Decimal::from_parts(10_000, 0, 0, false, 0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Side {
+    /// Takes the asks.
+    Buy,
+    /// Takes the bids.
+    Sell,
+}
+
+/// Resting size on both sides within some distance of the mid.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Depth {
+    pub bps: Decimal,
+    pub bids: Decimal,
+    pub asks: Decimal,
+}
+
+impl Depth {
+    /// From -1 when there are only asks to 1 when there are only bids.
+    pub fn imbalance(&self) -> Option<Decimal> {
+        let total = self.bids + self.asks;
+        if total.is_zero() {
+            return None;
+        }
+        Some(((self.bids - self.asks) / total).normalize())
+    }
+}
+
+/// How a market order would have been filled against the book.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Fill {
+    pub side: Side,
+    /// Less than asked for when the book is too thin.
+    pub size: Decimal,
+    pub notional: Decimal,
+    /// The volume weighted average price of the fill.
+    pub average_price: Decimal,
+    /// The last level the order reached.
+    pub worst_price: Decimal,
+}
+
+/// What the `stats` channel computes from a book, besides the market itself.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StatsParams {
+    /// Distance from the mid to sum the depth and imbalance within.
+    pub depth_bps: Decimal,
+    /// Size of the hypothetical market orders, none are computed without it.
+    pub fill_size: Option<Decimal>,
+}
+
+impl Default for StatsParams {
+    fn default() -> Self {
+        Self {
+            depth_bps: Decimal::TEN,
+            fill_size: None,
+        }
+    }
+}
+
+/// Everything derived from one book, sent as `{"type":"stats",...}`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename = "stats")]
+pub struct Stats {
+    pub market: Market,
+    pub mid: Option<Decimal>,
+    pub spread: Option<Decimal>,
+    pub spread_bps: Option<Decimal>,
+    pub microprice: Option<Decimal>,
+    pub depth: Option<Depth>,
+    pub imbalance: Option<Decimal>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub buy: Option<Fill>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sell: Option<Fill>,
+    /// Of the markets channel, once it has one for the market.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub oracle_price: Option<Decimal>,
+    /// How far the mid is above the oracle price, in basis points.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub premium_bps: Option<Decimal>,
+}
+
+impl OrderBookState {
+    /// The spread relative to the mid, in basis points.
+    pub fn spread_bps(&self) -> Option<Decimal> {
+        let mid = self.mid()?;
+        if mid.is_zero() {
+            return None;
+        }
+        Some((self.spread()? / mid * BPS).normalize())
+    }
+
+    /// The mid weighted by the opposite top level sizes, so that it leans
+    /// towards the side more likely to be taken out next.
+    pub fn microprice(&self) -> Option<Decimal> {
+        let (bid, bid_size) = self.best_bid()?;
+        let (ask, ask_size) = self.best_ask()?;
+        let total = bid_size + ask_size;
+        if total.is_zero() {
+            return None;
+        }
+        Some(((bid * ask_size + ask * bid_size) / total).normalize())
+    }
+
+    /// Sums the sizes of the levels at most `bps` basis points away from the
+    /// mid, `None` if that distance is beyond what a decimal holds.
+    pub fn depth_within_bps(&self, bps: Decimal) -> Option<Depth> {
+        let mid = self.mid()?;
+        let distance = mid.checked_mul(bps)? / BPS;
+        let bids = self
+            .bids
+            .range(mid.checked_sub(distance)?..)
+            .map(|(_, size)| *size)
+            .sum::<Decimal>();
+        let asks = self
+            .asks
+            .range(..=mid.checked_add(distance)?)
+            .map(|(_, size)| *size)
+            .sum::<Decimal>();
+        Some(Depth {
+            bps,
+            bids: bids.normalize(),
+            asks: asks.normalize(),
+        })
+    }
+
+    /// Walks the book with a market order of `size`, `None` if the side is empty.
+    pub fn market_order(&self, side: Side, size: Decimal) -> Option<Fill> {
+        let levels: Box<dyn Iterator<Item = (&Decimal, &Decimal)>> = match side {
+            Side::Buy => Box::new(self.asks.iter()),
+            Side::Sell => Box::new(self.bids.iter().rev()),
+        };
+        let mut filled = Decimal::ZERO;
+        let mut notional = Decimal::ZERO;
+        let mut worst_price = None;
+        for (price, level_size) in levels {
+            if filled >= size {
+                break;
+            }
+            let taken = (*level_size).min(size - filled);
+            filled += taken;
+            notional += taken * price;
+            worst_price = Some(*price);
+        }
+        if filled.is_zero() {
+            return None;
+        }
+        Some(Fill {
+            side,
+            size: filled.normalize(),
+            notional: notional.normalize(),
+            average_price: (notional / filled).normalize(),
+            worst_price: worst_price?,
+        })
+    }
+
+    /// The mid relative to the `oracle` price, in basis points.
+    pub fn premium_bps(&self, oracle: Decimal) -> Option<Decimal> {
+        if oracle.is_zero() {
+            return None;
+        }
+        Some(((self.mid()? - oracle) / oracle * BPS).normalize())
+    }
+
+    pub fn stats(&self, params: &StatsParams, oracle_price: Option<Decimal>) -> Stats {
+        let depth = self.depth_within_bps(params.depth_bps);
+        let fill = |side| {
+            params
+                .fill_size
+                .and_then(|size| self.market_order(side, size))
+        };
+        Stats {
+            market: self.market.clone(),
+            mid: self.mid(),
+            spread: self.spread(),
+            spread_bps: self.spread_bps(),
+            microprice: self.microprice(),
+            imbalance: depth.as_ref().and_then(Depth::imbalance),
+            depth,
+            buy: fill(Side::Buy),
+            sell: fill(Side::Sell),
+            oracle_price,
+            premium_bps: oracle_price.and_then(|oracle| self.premium_bps(oracle)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use crate::core_types::Offer;
+
+    use super::*;
+
+    fn dec(s: &str) -> Decimal {
+        Decimal::from_str(s).unwrap()
+    }
+
+    fn offer(price: &str, size: &str) -> Offer {
+        Offer {
+            price: dec(price),
+            size: dec(size),
+        }
+    }
+
+    /// Bids 99 x 3, 98 x 5, 90 x 10 and asks 101 x 1, 102 x 2, 110 x 4.
+    fn book() -> OrderBookState {
+        OrderBookState::construct_from(
+            vec![offer("101", "1"), offer("102", "2"), offer("110", "4")],
+            vec![offer("99", "3"), offer("98", "5"), offer("90", "10")],
+            1,
+            Market::from_str("ETH-USD").unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_prices() {
+        let book = book();
+        assert_eq!(book.mid(), Some(dec("100")));
+        assert_eq!(book.spread(), Some(dec("2")));
+        // 2 / 100 of the mid
+        assert_eq!(book.spread_bps(), Some(dec("200")));
+        // (99 * 1 + 101 * 3) / 4
+        assert_eq!(book.microprice(), Some(dec("100.5")));
+        // 20 above an oracle of 80, 25 below one of 125
+        assert_eq!(book.premium_bps(dec("80")), Some(dec("2500")));
+        assert_eq!(book.premium_bps(dec("125")), Some(dec("-2000")));
+    }
+
+    #[test]
+    fn test_depth_and_imbalance() {
+        let book = book();
+        // 100 +- 2: bids 99 and 98, asks 101 and 102
+        let depth = book.depth_within_bps(dec("200")).unwrap();
+        assert_eq!((depth.bids, depth.asks), (dec("8"), dec("3")));
+        // (8 - 3) / 11
+        assert_eq!(depth.imbalance(), Some((dec("5") / dec("11")).normalize()));
+        // 100 +- 1: just the top levels
+        let depth = book.depth_within_bps(dec("100")).unwrap();
+        assert_eq!((depth.bids, depth.asks), (dec("3"), dec("1")));
+        assert_eq!(depth.imbalance(), Some(dec("0.5")));
+        // 100 +- 0.5: nothing
+        let depth = book.depth_within_bps(dec("50")).unwrap();
+        assert_eq!(depth.imbalance(), None);
+        // too far to tell rather than a panic
+        assert_eq!(book.depth_within_bps(Decimal::MAX), None);
+    }
+
+    #[test]
+    fn test_market_orders() {
+        let book = book();
+        // 1 @ 101 + 2 @ 102 + 1 @ 110 = 415
+        let buy = book.market_order(Side::Buy, dec("4")).unwrap();
+        assert_eq!(buy.size, dec("4"));
+        assert_eq!(buy.notional, dec("415"));
+        assert_eq!(buy.average_price, dec("103.75"));
+        assert_eq!(buy.worst_price, dec("110"));
+
+        // 3 @ 99 + 1 @ 98 = 395
+        let sell = book.market_order(Side::Sell, dec("4")).unwrap();
+        assert_eq!(sell.average_price, dec("98.75"));
+        assert_eq!(sell.worst_price, dec("98"));
+
+        // more than the book holds fills only what is there
+        let sell = book.market_order(Side::Sell, dec("100")).unwrap();
+        assert_eq!(sell.size, dec("18"));
+        // 297 + 490 + 900 = 1687
+        assert_eq!(sell.notional, dec("1687"));
+
+        let empty =
+            OrderBookState::construct_from(vec![], vec![], 1, Market::from_str("ETH-USD").unwrap());
+        assert_eq!(empty.market_order(Side::Buy, dec("1")), None);
+        assert_eq!(empty.stats(&StatsParams::default(), None).mid, None);
+    }
+}
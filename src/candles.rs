@@ -0,0 +1,237 @@
+use std::collections::{BTreeMap, VecDeque};
+
+use anyhow::Context;
+use serde::Serialize;
+
+use crate::upstream_types::{
+    Candle, CandleResolution, CandlesChannelBatchData, CandlesId, CandlesIncomingMessages, Market,
+};
+
+/// Candles kept per subscription, about as many as the indexer sends when subscribing.
+const RECENT_CANDLES: usize = 100;
+
+/// The recent candles of one market and resolution after an upstream
+/// message, shared by every client following them.
+#[derive(Debug)]
+pub struct CandlesUpdate {
+    pub id: CandlesId,
+    /// The newest first, the first one is still going on.
+    pub recent: Vec<Candle>,
+}
+
+impl CandlesUpdate {
+    /// The candles that changed since `last`, the one that was going on then
+    /// included. `None` if that is too long ago to tell.
+    pub fn since(&self, last: &CandlesUpdate) -> Option<&[Candle]> {
+        let Some(newest) = last.recent.first() else {
+            return (self.recent.len() < RECENT_CANDLES).then_some(&self.recent[..]);
+        };
+        let current = self
+            .recent
+            .iter()
+            .position(|candle| candle.started_at == newest.started_at)?;
+        if self.recent[current] == *newest {
+            return Some(&self.recent[..current]);
+        }
+        Some(&self.recent[..=current])
+    }
+
+    /// Renders the candles that changed since `last` for a client, the
+    /// recent candles as a snapshot if there is no `last` to build on.
+    /// `None` if nothing changed.
+    pub fn json_since(&self, last: Option<&CandlesUpdate>) -> Option<String> {
+        let (snapshot, candles) = match last.map(|last| self.since(last)) {
+            Some(Some([])) => return None,
+            Some(Some(candles)) => (false, candles),
+            Some(None) | None => (true, &self.recent[..]),
+        };
+        let message = CandlesMessage {
+            market: &self.id.market,
+            resolution: self.id.resolution,
+            snapshot,
+            candles,
+        };
+        Some(serde_json::to_string(&message).expect("candles should serialize"))
+    }
+}
+
+/// Candles as sent to clients, `{"type":"candles",...}`. A snapshot has every
+/// recent candle and replaces whatever the client had, otherwise a candle
+/// replaces the one of the client that started at the same time.
+#[derive(Serialize)]
+#[serde(tag = "type", rename = "candles")]
+struct CandlesMessage<'a> {
+    market: &'a Market,
+    resolution: CandleResolution,
+    snapshot: bool,
+    candles: &'a [Candle],
+}
+
+/// Keeps the recent candles of every market and resolution subscribed to.
+#[derive(Debug, Default)]
+pub struct CandlesFolder {
+    recent: BTreeMap<CandlesId, VecDeque<Candle>>,
+}
+
+impl CandlesFolder {
+    pub fn reset(&mut self) {
+        self.recent.clear();
+    }
+
+    pub fn forget(&mut self, id: &CandlesId) {
+        let _ = self.recent.remove(id);
+    }
+
+    /// The recent candles, if their subscription has been seen yet.
+    pub fn snapshot(&self, id: &CandlesId) -> Option<CandlesUpdate> {
+        let recent = self.recent.get(id)?;
+        Some(CandlesUpdate {
+            id: id.clone(),
+            recent: recent.iter().cloned().collect(),
+        })
+    }
+
+    /// Folds the message into the recent candles, returning them if they changed.
+    pub fn consume(
+        &mut self,
+        msg: CandlesIncomingMessages,
+    ) -> anyhow::Result<Option<CandlesUpdate>> {
+        let id = match msg {
+            CandlesIncomingMessages::Subscribed(subscribed) => {
+                let mut candles: VecDeque<Candle> = subscribed.contents.candles.into();
+                candles.truncate(RECENT_CANDLES);
+                let _ = self.recent.insert(subscribed.id.clone(), candles);
+                subscribed.id
+            }
+            CandlesIncomingMessages::ChannelData(data) => return self.consume_batch(data.into()),
+            CandlesIncomingMessages::ChannelBatchData(batch) => return self.consume_batch(batch),
+            CandlesIncomingMessages::Unsubscribed(unsubscribed) => {
+                self.forget(&unsubscribed.id);
+                return Ok(None);
+            }
+        };
+        Ok(self.snapshot(&id))
+    }
+
+    fn consume_batch(
+        &mut self,
+        batch: CandlesChannelBatchData,
+    ) -> anyhow::Result<Option<CandlesUpdate>> {
+        let recent = self.recent.get_mut(&batch.id).context(format!(
+            "The {} candles have not seen a snapshot yet, got an update",
+            batch.id
+        ))?;
+        let mut changed = false;
+        for candle in batch.contents {
+            match recent.front_mut() {
+                Some(current) if current.started_at == candle.started_at => {
+                    changed |= *current != candle;
+                    *current = candle;
+                }
+                Some(current) if current.started_at > candle.started_at => anyhow::bail!(
+                    "Got the {} candle of {} after the one of {}",
+                    batch.id,
+                    candle.started_at,
+                    current.started_at
+                ),
+                _ => {
+                    recent.push_front(candle);
+                    changed = true;
+                }
+            }
+        }
+        recent.truncate(RECENT_CANDLES);
+        if !changed {
+            return Ok(None);
+        }
+        Ok(self.snapshot(&batch.id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use rust_decimal::Decimal;
+
+    use crate::upstream_types::IncomingMessage;
+
+    use super::*;
+
+    fn frame(kind: &str, message_id: usize, contents: &str) -> String {
+        format!(
+            r#"{{"type":"{}","connection_id":"c","message_id":{},"channel":"v4_candles","id":"ETH-USD/1MIN","contents":{}}}"#,
+            kind, message_id, contents
+        )
+    }
+
+    fn candle(started_at: &str, close: &str) -> String {
+        format!(
+            r#"{{"startedAt":"{}","ticker":"ETH-USD","resolution":"1MIN","low":"3000","high":"3100","open":"3050","close":"{}","baseTokenVolume":"1","usdVolume":"3050","trades":1,"startingOpenInterest":"10"}}"#,
+            started_at, close
+        )
+    }
+
+    fn consume(folder: &mut CandlesFolder, frame: &str) -> anyhow::Result<Option<CandlesUpdate>> {
+        let Ok(IncomingMessage::Candles(message)) = IncomingMessage::from_str(frame) else {
+            panic!("expected a candles message");
+        };
+        folder.consume(message)
+    }
+
+    #[test]
+    fn test_fold_candles() {
+        let mut folder = CandlesFolder::default();
+        let update = frame(
+            "channel_data",
+            2,
+            &candle("2024-05-01T13:31:00.000Z", "3060"),
+        );
+        assert!(consume(&mut folder, &update).is_err());
+
+        let subscribed = frame(
+            "subscribed",
+            1,
+            &format!(
+                r#"{{"candles":[{},{}]}}"#,
+                candle("2024-05-01T13:30:00.000Z", "3051"),
+                candle("2024-05-01T13:29:00.000Z", "3050")
+            ),
+        );
+        let first = consume(&mut folder, &subscribed).unwrap().unwrap();
+        assert_eq!(first.recent.len(), 2);
+        assert!(first
+            .json_since(None)
+            .unwrap()
+            .starts_with(r#"{"type":"candles","market":"ETH-USD","resolution":"1MIN","snapshot":true,"candles":[{"started_at":"2024-05-01T13:30:00.000Z","open":"3050","#));
+
+        // the current candle changes, then the next one starts
+        let update = frame(
+            "channel_data",
+            2,
+            &candle("2024-05-01T13:30:00.000Z", "3055"),
+        );
+        let second = consume(&mut folder, &update).unwrap().unwrap();
+        assert_eq!(second.since(&first).unwrap().len(), 1);
+        assert!(consume(&mut folder, &update).unwrap().is_none());
+        let update = frame(
+            "channel_batch_data",
+            3,
+            &format!("[{}]", candle("2024-05-01T13:31:00.000Z", "3060")),
+        );
+        let third = consume(&mut folder, &update).unwrap().unwrap();
+        let changed = third.since(&first).unwrap();
+        assert_eq!(changed.len(), 2);
+        assert_eq!(changed[0].close, Decimal::from_str("3060").unwrap());
+        assert_eq!(changed[1].close, Decimal::from_str("3055").unwrap());
+        assert_eq!(third.since(&second).unwrap().len(), 1);
+        assert_eq!(third.json_since(Some(&third)), None);
+
+        let stale = frame(
+            "channel_data",
+            4,
+            &candle("2024-05-01T13:29:00.000Z", "3000"),
+        );
+        assert!(consume(&mut folder, &stale).is_err());
+    }
+}
@@ -0,0 +1,286 @@
+use std::{path::PathBuf, str::FromStr};
+
+use clap::{Parser, ValueEnum};
+
+use crate::{
+    recorder::RecordingOptions,
+    replay::{parse_timestamp, ReplayOptions, ReplaySpeed},
+};
+
+const TESTNET_INDEXER_WS_HOST: &str = "wss://dydx-testnet.imperator.co/v4/ws";
+const PROD_INDEXER_WS_HOST: &str = "wss://indexer.dydx.trade/v4/ws";
+
+/// An indexer websocket to proxy, served under `/{name}`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Network {
+    pub name: String,
+    pub ws_url: String,
+}
+
+impl Network {
+    /// The indexer REST API living next to the websocket, e.g.
+    /// `wss://indexer.dydx.trade/v4/ws` becomes `https://indexer.dydx.trade/v4`.
+    pub fn rest_url(&self) -> String {
+        let url = self.ws_url.trim_end_matches('/');
+        let url = url.strip_suffix("/ws").unwrap_or(url);
+        match url.strip_prefix("wss://") {
+            Some(rest) => format!("https://{}", rest),
+            None => format!("http://{}", url.trim_start_matches("ws://")),
+        }
+    }
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    /// Accepts the well-known `mainnet` and `testnet`, or `name=url` for
+    /// anything else, e.g. `local=ws://127.0.0.1:9000/v4/ws`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, ws_url) = match s.split_once('=') {
+            Some((name, ws_url)) => (name, ws_url),
+            None => match s {
+                "mainnet" => (s, PROD_INDEXER_WS_HOST),
+                "testnet" => (s, TESTNET_INDEXER_WS_HOST),
+                _ => {
+                    return Err(format!(
+                        "unknown network '{}', expected mainnet, testnet or name=url",
+                        s
+                    ))
+                }
+            },
+        };
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            return Err(format!("invalid network name '{}'", name));
+        }
+        if !(ws_url.starts_with("ws://") || ws_url.starts_with("wss://")) {
+            return Err(format!(
+                "network url must be ws:// or wss://, got '{}'",
+                ws_url
+            ));
+        }
+        Ok(Self {
+            name: name.to_string(),
+            ws_url: ws_url.to_string(),
+        })
+    }
+}
+
+/// What to do with a book whose best bid has reached its best ask.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum CrossedBookPolicy {
+    /// Serve the book as the indexer has it
+    PassThrough,
+    /// Serve the book, with a `crossed` field describing the crossing
+    #[default]
+    Flag,
+    /// Drop the crossed levels of the side updated less recently
+    Trim,
+    /// Refetch the snapshot of the market
+    Resync,
+}
+
+#[derive(Parser, Debug)]
+#[command(
+    version,
+    about = "Proxies dYdX v4 indexer orderbooks to websocket clients"
+)]
+pub struct Config {
+    /// Port to listen on
+    #[arg(long, env = "PORT", default_value_t = 80)]
+    pub port: u16,
+
+    /// Indexer networks to serve: mainnet, testnet or name=ws-url. Each is
+    /// served under /{name}, the first one also under /
+    #[arg(
+        long = "network",
+        env = "CHESTER_NETWORKS",
+        value_delimiter = ',',
+        default_value = "mainnet"
+    )]
+    pub networks: Vec<Network>,
+
+    /// What to do when a book is crossed or locked
+    #[arg(
+        long,
+        env = "CHESTER_CROSSED_BOOK_POLICY",
+        value_enum,
+        default_value_t = CrossedBookPolicy::Flag
+    )]
+    pub crossed_book_policy: CrossedBookPolicy,
+
+    /// Record every raw upstream frame to hourly files in this directory
+    #[arg(long, env = "CHESTER_RECORD_DIR")]
+    pub record_dir: Option<PathBuf>,
+
+    /// Compress recordings with zstd
+    #[arg(long, env = "CHESTER_RECORD_ZSTD", requires = "record_dir")]
+    pub record_zstd: bool,
+
+    /// Serve recordings instead of connecting to the indexer: files, or
+    /// directories of them, played in the order given
+    #[arg(long, env = "CHESTER_REPLAY", value_delimiter = ',')]
+    pub replay: Vec<PathBuf>,
+
+    /// How fast to replay: a factor of the recorded pace like 1 or 10x, or max
+    #[arg(
+        long,
+        env = "CHESTER_REPLAY_SPEED",
+        default_value = "1",
+        requires = "replay"
+    )]
+    pub replay_speed: ReplaySpeed,
+
+    /// Where to start replaying, e.g. 2024-05-01T13:30:00Z. The books are
+    /// built from everything recorded before without delay
+    #[arg(long, env = "CHESTER_REPLAY_FROM", value_parser = parse_timestamp, requires = "replay")]
+    pub replay_from: Option<u64>,
+
+    /// Let clients follow the orders, positions and fills of any subaccount
+    /// they know the address of, over the subaccount and ladder channels
+    #[arg(long, env = "CHESTER_SUBACCOUNTS")]
+    pub subaccounts: bool,
+}
+
+impl Config {
+    pub fn recording(&self) -> Option<RecordingOptions> {
+        let dir = self.record_dir.clone()?;
+        Some(RecordingOptions {
+            dir,
+            zstd: self.record_zstd,
+        })
+    }
+
+    pub fn replay(&self) -> Option<ReplayOptions> {
+        if self.replay.is_empty() {
+            return None;
+        }
+        Some(ReplayOptions {
+            paths: self.replay.clone(),
+            speed: self.replay_speed,
+            from_us: self.replay_from,
+        })
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        for (i, network) in self.networks.iter().enumerate() {
+            if self.networks[..i].iter().any(|n| n.name == network.name) {
+                return Err(format!("network '{}' is given twice", network.name));
+            }
+        }
+        if !self.replay.is_empty() {
+            if self.networks.len() > 1 {
+                return Err("a replay is served as a single network".to_string());
+            }
+            if self.record_dir.is_some() {
+                return Err("a replay cannot be recorded".to_string());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_networks() {
+        let config = Config::try_parse_from([
+            "chester",
+            "--network",
+            "testnet,local=ws://127.0.0.1:9000/v4/ws",
+        ])
+        .expect("should be valid");
+        assert_eq!(
+            config.networks,
+            vec![
+                Network {
+                    name: "testnet".into(),
+                    ws_url: TESTNET_INDEXER_WS_HOST.into()
+                },
+                Network {
+                    name: "local".into(),
+                    ws_url: "ws://127.0.0.1:9000/v4/ws".into()
+                },
+            ]
+        );
+        assert!(config.validate().is_ok());
+        assert_eq!(
+            config.networks[0].rest_url(),
+            "https://dydx-testnet.imperator.co/v4"
+        );
+        assert_eq!(config.networks[1].rest_url(), "http://127.0.0.1:9000/v4");
+
+        assert!(Network::from_str("devnet").is_err());
+        assert!(Network::from_str("local=http://127.0.0.1").is_err());
+        assert!(Network::from_str("a/b=ws://127.0.0.1").is_err());
+    }
+
+    #[test]
+    fn test_reject_duplicate_networks() {
+        let config = Config::try_parse_from(["chester", "--network", "mainnet,mainnet"])
+            .expect("should parse");
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_parse_crossed_book_policy() {
+        let config = Config::try_parse_from(["chester"]).expect("should parse");
+        assert_eq!(config.crossed_book_policy, CrossedBookPolicy::Flag);
+        let config = Config::try_parse_from(["chester", "--crossed-book-policy", "pass-through"])
+            .expect("should parse");
+        assert_eq!(config.crossed_book_policy, CrossedBookPolicy::PassThrough);
+        assert!(Config::try_parse_from(["chester", "--crossed-book-policy", "ignore"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_recording() {
+        let config = Config::try_parse_from(["chester"]).expect("should parse");
+        assert_eq!(config.recording(), None);
+        let config = Config::try_parse_from(["chester", "--record-dir", "/data", "--record-zstd"])
+            .expect("should parse");
+        assert_eq!(
+            config.recording(),
+            Some(RecordingOptions {
+                dir: "/data".into(),
+                zstd: true,
+            })
+        );
+        assert!(Config::try_parse_from(["chester", "--record-zstd"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_replay() {
+        let config = Config::try_parse_from(["chester"]).expect("should parse");
+        assert_eq!(config.replay(), None);
+        let config = Config::try_parse_from([
+            "chester",
+            "--replay",
+            "/data/a.ndjson,/data/b.ndjson.zst",
+            "--replay-speed",
+            "max",
+            "--replay-from",
+            "2024-05-01T13:30:00Z",
+        ])
+        .expect("should parse");
+        assert!(config.validate().is_ok());
+        assert_eq!(
+            config.replay(),
+            Some(ReplayOptions {
+                paths: vec!["/data/a.ndjson".into(), "/data/b.ndjson.zst".into()],
+                speed: ReplaySpeed::Max,
+                from_us: Some(1_714_570_200_000_000),
+            })
+        );
+        assert!(Config::try_parse_from(["chester", "--replay-speed", "2"]).is_err());
+        let config = Config::try_parse_from([
+            "chester",
+            "--replay",
+            "/data",
+            "--network",
+            "mainnet,testnet",
+        ])
+        .expect("should parse");
+        assert!(config.validate().is_err());
+    }
+}
@@ -1,10 +1,20 @@
-use std::collections::BTreeMap;
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, OnceLock},
+    time::{Instant, SystemTime},
+};
 
 use rust_decimal::Decimal;
 use serde::ser::SerializeStruct;
 use serde::Serialize;
 
-use crate::upstream_types::Market;
+use crate::{
+    candles::CandlesUpdate,
+    market_state::MarketStatesUpdate,
+    subaccounts::SubaccountUpdate,
+    trades::TradesUpdate,
+    upstream_types::{Block, Market, Topic},
+};
 
 #[derive(Debug)]
 pub struct Offer {
@@ -12,12 +22,42 @@ pub struct Offer {
     pub size: Decimal,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct OrderBookState {
     epoch: usize,
     pub market: Market,
     pub asks: BTreeMap<Decimal, Decimal>,
     pub bids: BTreeMap<Decimal, Decimal>,
+    /// The epoch every level was last touched at, to tell stale levels apart.
+    ask_epochs: BTreeMap<Decimal, usize>,
+    bid_epochs: BTreeMap<Decimal, usize>,
+    /// Set when the book is knowingly served crossed or locked.
+    pub crossed: Option<Crossing>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CrossingKind {
+    /// The best bid is above the best ask.
+    Crossed,
+    /// The best bid equals the best ask.
+    Locked,
+}
+
+impl CrossingKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CrossingKind::Crossed => "crossed",
+            CrossingKind::Locked => "locked",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Crossing {
+    pub kind: CrossingKind,
+    pub best_bid: Decimal,
+    pub best_ask: Decimal,
 }
 
 impl Serialize for OrderBookState {
@@ -40,6 +80,9 @@ impl Serialize for OrderBookState {
             .map(|(price, size)| (*price, *size))
             .collect();
         out.serialize_field("bids", &bids)?;
+        if let Some(crossed) = &self.crossed {
+            out.serialize_field("crossed", crossed)?;
+        }
         out.end()
     }
 }
@@ -51,30 +94,137 @@ impl OrderBookState {
         epoch: usize,
         market: Market,
     ) -> Self {
-        let map_asks = asks
+        let map_asks: BTreeMap<Decimal, Decimal> = asks
             .into_iter()
             .filter(|offer| offer.size != Decimal::ZERO)
             .map(|o| (o.price, o.size))
             .collect();
-        let map_bids = bids
+        let map_bids: BTreeMap<Decimal, Decimal> = bids
             .into_iter()
             .filter(|offer| offer.size != Decimal::ZERO)
             .map(|o| (o.price, o.size))
             .collect();
         Self {
+            ask_epochs: map_asks.keys().map(|price| (*price, epoch)).collect(),
+            bid_epochs: map_bids.keys().map(|price| (*price, epoch)).collect(),
             asks: map_asks,
             bids: map_bids,
             epoch,
             market,
+            crossed: None,
         }
     }
 
+    pub fn best_ask(&self) -> Option<(Decimal, Decimal)> {
+        self.asks.iter().next().map(|(price, size)| (*price, *size))
+    }
+
+    pub fn best_bid(&self) -> Option<(Decimal, Decimal)> {
+        self.bids
+            .iter()
+            .next_back()
+            .map(|(price, size)| (*price, *size))
+    }
+
+    /// Halfway between the best bid and ask, if both sides have orders.
+    pub fn mid(&self) -> Option<Decimal> {
+        let (bid, _) = self.best_bid()?;
+        let (ask, _) = self.best_ask()?;
+        Some(((bid + ask) / Decimal::TWO).normalize())
+    }
+
+    pub fn spread(&self) -> Option<Decimal> {
+        let (bid, _) = self.best_bid()?;
+        let (ask, _) = self.best_ask()?;
+        Some(ask - bid)
+    }
+
+    /// The top of the book, see `Ticker`.
+    pub fn ticker(&self) -> Ticker {
+        let best_bid = self.best_bid();
+        let best_ask = self.best_ask();
+        Ticker {
+            market: self.market.clone(),
+            bid: best_bid.map(|(price, _)| price),
+            bid_size: best_bid.map(|(_, size)| size),
+            ask: best_ask.map(|(price, _)| price),
+            ask_size: best_ask.map(|(_, size)| size),
+            mid: self.mid(),
+            spread: self.spread(),
+        }
+    }
+
+    /// Whether the best bid has reached the best ask, which a healthy book
+    /// never shows.
+    pub fn crossing(&self) -> Option<Crossing> {
+        let (best_bid, _) = self.best_bid()?;
+        let (best_ask, _) = self.best_ask()?;
+        let kind = match best_bid.cmp(&best_ask) {
+            std::cmp::Ordering::Less => return None,
+            std::cmp::Ordering::Equal => CrossingKind::Locked,
+            std::cmp::Ordering::Greater => CrossingKind::Crossed,
+        };
+        Some(Crossing {
+            kind,
+            best_bid,
+            best_ask,
+        })
+    }
+
+    /// Uncrosses the book by dropping best levels of whichever side was
+    /// updated less recently, as that one must have missed a removal.
+    ///
+    /// Stops early if both best levels come from the same message, there is
+    /// no telling which one is stale then. Returns the removed levels.
+    pub fn trim_crossed(&mut self) -> BookChanges {
+        let mut removed = BookChanges::default();
+        while let Some(crossing) = self.crossing() {
+            let bid_epoch = self.bid_epochs.get(&crossing.best_bid).copied();
+            let ask_epoch = self.ask_epochs.get(&crossing.best_ask).copied();
+            match bid_epoch.cmp(&ask_epoch) {
+                std::cmp::Ordering::Less => {
+                    let _ = self.bids.remove(&crossing.best_bid);
+                    let _ = self.bid_epochs.remove(&crossing.best_bid);
+                    let _ = removed.bids.insert(crossing.best_bid, Decimal::ZERO);
+                }
+                std::cmp::Ordering::Greater => {
+                    let _ = self.asks.remove(&crossing.best_ask);
+                    let _ = self.ask_epochs.remove(&crossing.best_ask);
+                    let _ = removed.asks.insert(crossing.best_ask, Decimal::ZERO);
+                }
+                std::cmp::Ordering::Equal => break,
+            }
+        }
+        removed
+    }
+
+    /// A reduced copy of the book, see `BookView`.
+    pub fn view(&self, view: &BookView) -> OrderBookState {
+        let asks = view.reduce(self.asks.iter(), |bucket| bucket.ceil());
+        let bids = view.reduce(self.bids.iter().rev(), |bucket| bucket.floor());
+        OrderBookState {
+            epoch: self.epoch,
+            market: self.market.clone(),
+            asks,
+            bids,
+            ask_epochs: BTreeMap::new(),
+            bid_epochs: BTreeMap::new(),
+            crossed: self.crossed,
+        }
+    }
+
+    /// The upstream `message_id` of the last message folded into this book.
+    pub fn epoch(&self) -> usize {
+        self.epoch
+    }
+
+    /// Applies the offers, returning the price levels that have changed.
     pub fn update_with(
         &mut self,
         asks: Vec<Offer>,
         bids: Vec<Offer>,
         epoch: usize,
-    ) -> anyhow::Result<()> {
+    ) -> anyhow::Result<BookChanges> {
         if epoch <= self.epoch {
             anyhow::bail!(
                 "Known epoch ({}) is more advanced than the given ({})",
@@ -85,21 +235,563 @@ impl OrderBookState {
             self.epoch = epoch;
         }
 
+        let mut changes = BookChanges::default();
         for o in asks.into_iter() {
             if o.size == Decimal::ZERO {
                 let _ = self.asks.remove(&o.price);
+                let _ = self.ask_epochs.remove(&o.price);
             } else {
                 let _ = self.asks.insert(o.price, o.size);
+                let _ = self.ask_epochs.insert(o.price, epoch);
             }
+            let _ = changes.asks.insert(o.price, o.size);
         }
 
         for o in bids.into_iter() {
             if o.size == Decimal::ZERO {
                 let _ = self.bids.remove(&o.price);
+                let _ = self.bid_epochs.remove(&o.price);
             } else {
                 let _ = self.bids.insert(o.price, o.size);
+                let _ = self.bid_epochs.insert(o.price, epoch);
             }
+            let _ = changes.bids.insert(o.price, o.size);
         }
-        Ok(())
+        Ok(changes)
+    }
+}
+
+/// Best bid and ask of a book, sent as `{"type":"ticker",...}`. Sides
+/// without orders are `null`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename = "ticker")]
+pub struct Ticker {
+    pub market: Market,
+    pub bid: Option<Decimal>,
+    pub bid_size: Option<Decimal>,
+    pub ask: Option<Decimal>,
+    pub ask_size: Option<Decimal>,
+    pub mid: Option<Decimal>,
+    pub spread: Option<Decimal>,
+}
+
+/// What part of a book a subscriber wants to see.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct BookView {
+    /// Only this many levels per side, counted after grouping.
+    pub depth: Option<usize>,
+    /// Buckets levels to multiples of this price step, summing their sizes.
+    /// Asks are rounded up and bids down, so that a bucket never looks better
+    /// than the levels in it.
+    pub group: Option<Decimal>,
+}
+
+impl BookView {
+    pub fn is_whole_book(&self) -> bool {
+        self.depth.is_none() && self.group.is_none()
+    }
+
+    /// Reduces one side of a book, given from the best level outwards.
+    fn reduce<'a>(
+        &self,
+        levels: impl Iterator<Item = (&'a Decimal, &'a Decimal)>,
+        round: impl Fn(Decimal) -> Decimal,
+    ) -> BTreeMap<Decimal, Decimal> {
+        let depth = self.depth.unwrap_or(usize::MAX);
+        let mut reduced = BTreeMap::new();
+        for (price, size) in levels {
+            let price = match self.group {
+                // a group too fine to count the buckets up to the price in,
+                // which only the tick size of the market would rule out,
+                // leaves the level as it is
+                Some(group) => price
+                    .checked_div(group)
+                    .and_then(|buckets| round(buckets).checked_mul(group))
+                    .unwrap_or(*price),
+                None => *price,
+            };
+            let price = price.normalize();
+            if !reduced.contains_key(&price) && reduced.len() == depth {
+                break;
+            }
+            *reduced.entry(price).or_insert(Decimal::ZERO) += size;
+        }
+        reduced
+    }
+}
+
+/// Price levels that changed in one update, a size of zero removes the level.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BookChanges {
+    pub asks: BTreeMap<Decimal, Decimal>,
+    pub bids: BTreeMap<Decimal, Decimal>,
+}
+
+impl BookChanges {
+    /// Adds the later `changes` on top of these.
+    pub fn extend(&mut self, changes: BookChanges) {
+        self.asks.extend(changes.asks);
+        self.bids.extend(changes.bids);
+    }
+
+    /// What changed from the `old` book to the `new` one.
+    pub fn between(old: &OrderBookState, new: &OrderBookState) -> Self {
+        fn side(
+            old: &BTreeMap<Decimal, Decimal>,
+            new: &BTreeMap<Decimal, Decimal>,
+        ) -> BTreeMap<Decimal, Decimal> {
+            let removed = old
+                .keys()
+                .filter(|price| !new.contains_key(price))
+                .map(|price| (*price, Decimal::ZERO));
+            let changed = new
+                .iter()
+                .filter(|(price, size)| old.get(price) != Some(size))
+                .map(|(price, size)| (*price, *size));
+            removed.chain(changed).collect()
+        }
+        Self {
+            asks: side(&old.asks, &new.asks),
+            bids: side(&old.bids, &new.bids),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum UpdateKind {
+    /// The book was (re)built from scratch.
+    Snapshot,
+    /// The book was updated in place, `prev_seq` is the `seq` of the update
+    /// right before this one for the same market.
+    Delta {
+        prev_seq: usize,
+        changes: BookChanges,
+    },
+}
+
+/// One market's book after an upstream message, shared by every client
+/// following the market. Serializations are cached as they are the same for
+/// everyone.
+#[derive(Debug)]
+pub struct BookUpdate {
+    /// The upstream `message_id` this update was derived from.
+    pub seq: usize,
+    pub kind: UpdateKind,
+    pub book: OrderBookState,
+    /// When the upstream message got folded, to measure delivery latency.
+    pub received_at: Instant,
+    /// The same moment by the wall clock, for clients.
+    pub updated_at: SystemTime,
+    /// The upstream connection `seq` belongs to, ids restart with every connection.
+    pub connection_id: Arc<str>,
+    /// The latest block known when the update got folded, telling a stalled
+    /// chain from a stalled indexer.
+    pub block: Option<Block>,
+    full_json: OnceLock<String>,
+    snapshot_json: OnceLock<String>,
+    delta_json: OnceLock<String>,
+}
+
+/// What happens to a market, as broadcast to every client following it.
+#[derive(Debug, Clone)]
+pub enum MarketEvent {
+    Book(Arc<BookUpdate>),
+    Trades(Arc<TradesUpdate>),
+    Candles(Arc<CandlesUpdate>),
+    Markets(Arc<MarketStatesUpdate>),
+    Subaccount(Arc<SubaccountUpdate>),
+    /// The data was found out of sync with the indexer and is being refetched,
+    /// nothing is sent until the next snapshot. Without a market for the
+    /// markets channel, which is about all of them.
+    Resyncing {
+        market: Option<Market>,
+        reason: Arc<str>,
+    },
+    /// The indexer stopped serving the market, e.g. because it got delisted.
+    /// Nothing follows for it.
+    Failed {
+        market: Option<Market>,
+        reason: Arc<str>,
+    },
+}
+
+impl MarketEvent {
+    /// The upstream topic of market data, `None` for statuses.
+    pub fn topic(&self) -> Option<Topic> {
+        match self {
+            MarketEvent::Book(update) => Some(Topic::Orderbook(update.book.market.clone())),
+            MarketEvent::Trades(update) => Some(Topic::Trades(update.market.clone())),
+            MarketEvent::Candles(update) => Some(Topic::Candles(update.id.clone())),
+            MarketEvent::Markets(_) => Some(Topic::Markets),
+            MarketEvent::Subaccount(update) => Some(Topic::Subaccount(update.id.clone())),
+            MarketEvent::Resyncing { .. } | MarketEvent::Failed { .. } => None,
+        }
+    }
+
+    /// The event as a `status` message, `None` for market data.
+    pub fn status_json(&self) -> Option<String> {
+        let status = match self {
+            MarketEvent::Book(_)
+            | MarketEvent::Trades(_)
+            | MarketEvent::Candles(_)
+            | MarketEvent::Markets(_)
+            | MarketEvent::Subaccount(_) => return None,
+            MarketEvent::Resyncing { market, reason } => StatusMessage {
+                market: market.as_ref(),
+                status: "resyncing",
+                reason,
+            },
+            MarketEvent::Failed { market, reason } => StatusMessage {
+                market: market.as_ref(),
+                status: "failed",
+                reason,
+            },
+        };
+        Some(serde_json::to_string(&status).expect("statuses should serialize"))
+    }
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename = "status")]
+struct StatusMessage<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    market: Option<&'a Market>,
+    status: &'a str,
+    reason: &'a str,
+}
+
+/// A book in the plain format, with the block it was seen at.
+#[derive(Serialize)]
+struct FullMessage<'a> {
+    #[serde(flatten)]
+    book: &'a OrderBookState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    block: Option<&'a Block>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum SequencedMessage<'a> {
+    Snapshot {
+        market: &'a Market,
+        seq: usize,
+        asks: Vec<(Decimal, Decimal)>,
+        bids: Vec<(Decimal, Decimal)>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        crossed: Option<Crossing>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        block: Option<&'a Block>,
+    },
+    Delta {
+        market: &'a Market,
+        seq: usize,
+        prev_seq: usize,
+        asks: Vec<(Decimal, Decimal)>,
+        bids: Vec<(Decimal, Decimal)>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        crossed: Option<Crossing>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        block: Option<&'a Block>,
+    },
+}
+
+fn levels<'a>(side: impl Iterator<Item = (&'a Decimal, &'a Decimal)>) -> Vec<(Decimal, Decimal)> {
+    side.map(|(price, size)| (*price, *size)).collect()
+}
+
+impl BookUpdate {
+    pub fn snapshot(book: OrderBookState) -> Self {
+        Self::new(book, UpdateKind::Snapshot)
+    }
+
+    pub fn delta(book: OrderBookState, prev_seq: usize, changes: BookChanges) -> Self {
+        Self::new(book, UpdateKind::Delta { prev_seq, changes })
+    }
+
+    fn new(book: OrderBookState, kind: UpdateKind) -> Self {
+        Self {
+            seq: book.epoch(),
+            kind,
+            book,
+            received_at: Instant::now(),
+            updated_at: SystemTime::now(),
+            connection_id: "".into(),
+            block: None,
+            full_json: OnceLock::new(),
+            snapshot_json: OnceLock::new(),
+            delta_json: OnceLock::new(),
+        }
+    }
+
+    pub fn on_connection(mut self, connection_id: Arc<str>) -> Self {
+        self.connection_id = connection_id;
+        self
+    }
+
+    pub fn at_block(mut self, block: Option<Block>) -> Self {
+        self.block = block;
+        self
+    }
+
+    /// The whole book in the plain format, without sequencing.
+    pub fn full_json(&self) -> &str {
+        self.full_json.get_or_init(|| self.plain_json(&self.book))
+    }
+
+    /// A `view` of the book in the plain format, not cached as views differ
+    /// between clients.
+    pub fn view_json(&self, view: &BookView) -> String {
+        self.plain_json(&self.book.view(view))
+    }
+
+    fn plain_json(&self, book: &OrderBookState) -> String {
+        let message = FullMessage {
+            book,
+            block: self.block.as_ref(),
+        };
+        serde_json::to_string(&message).expect("books should serialize")
+    }
+
+    /// The whole book as a `snapshot` message, to start or restart a delta stream.
+    pub fn snapshot_json(&self) -> &str {
+        self.snapshot_json.get_or_init(|| {
+            let message = SequencedMessage::Snapshot {
+                market: &self.book.market,
+                seq: self.seq,
+                asks: levels(self.book.asks.iter()),
+                bids: levels(self.book.bids.iter().rev()),
+                crossed: self.book.crossed,
+                block: self.block.as_ref(),
+            };
+            serde_json::to_string(&message).expect("books should serialize")
+        })
+    }
+
+    /// Only the changed levels as a `delta` message, `None` for snapshots.
+    pub fn delta_json(&self) -> Option<&str> {
+        let UpdateKind::Delta { prev_seq, changes } = &self.kind else {
+            return None;
+        };
+        let json = self.delta_json.get_or_init(|| {
+            let message = SequencedMessage::Delta {
+                market: &self.book.market,
+                seq: self.seq,
+                prev_seq: *prev_seq,
+                asks: levels(changes.asks.iter()),
+                bids: levels(changes.bids.iter().rev()),
+                crossed: self.book.crossed,
+                block: self.block.as_ref(),
+            };
+            serde_json::to_string(&message).expect("books should serialize")
+        });
+        Some(json)
+    }
+
+    /// Whether this update directly follows the one with `seq`.
+    pub fn follows(&self, seq: usize) -> bool {
+        matches!(self.kind, UpdateKind::Delta { prev_seq, .. } if prev_seq == seq)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    fn offer(price: &str, size: &str) -> Offer {
+        Offer {
+            price: Decimal::from_str(price).unwrap(),
+            size: Decimal::from_str(size).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_delta_carries_only_changed_levels() {
+        let mut book = OrderBookState::construct_from(
+            vec![offer("101", "1"), offer("102", "2")],
+            vec![offer("99", "1"), offer("98", "2")],
+            3,
+            Market::from_str("ETH-USD").unwrap(),
+        );
+        let snapshot = BookUpdate::snapshot(book.clone());
+        assert_eq!(
+            snapshot.snapshot_json(),
+            r#"{"type":"snapshot","market":"ETH-USD","seq":3,"asks":[["101","1"],["102","2"]],"bids":[["99","1"],["98","2"]]}"#
+        );
+        assert_eq!(snapshot.delta_json(), None);
+
+        let changes = book
+            .update_with(vec![offer("101", "0")], vec![offer("99.5", "4")], 9)
+            .unwrap();
+        let delta = BookUpdate::delta(book, 3, changes);
+        assert!(delta.follows(3));
+        assert!(!delta.follows(4));
+        assert_eq!(
+            delta.delta_json(),
+            Some(
+                r#"{"type":"delta","market":"ETH-USD","seq":9,"prev_seq":3,"asks":[["101","0"]],"bids":[["99.5","4"]]}"#
+            )
+        );
+        assert_eq!(
+            delta.full_json(),
+            r#"{"market":"ETH-USD","asks":[["102","2"]],"bids":[["99.5","4"],["99","1"],["98","2"]]}"#
+        );
+    }
+
+    #[test]
+    fn test_updates_carry_their_block() {
+        let book = OrderBookState::construct_from(
+            vec![offer("101", "1")],
+            vec![offer("99", "1")],
+            3,
+            Market::from_str("ETH-USD").unwrap(),
+        );
+        let update = BookUpdate::snapshot(book).at_block(Some(Block {
+            height: "1000".to_string(),
+            time: "2024-05-01T13:30:00.000Z".to_string(),
+        }));
+        let block = r#""block":{"height":"1000","time":"2024-05-01T13:30:00.000Z"}"#;
+        assert_eq!(
+            update.full_json(),
+            format!(
+                r#"{{"market":"ETH-USD","asks":[["101","1"]],"bids":[["99","1"]],{}}}"#,
+                block
+            )
+        );
+        assert!(update
+            .snapshot_json()
+            .ends_with(&format!(r#""bids":[["99","1"]],{}}}"#, block)));
+    }
+
+    #[test]
+    fn test_changes_between_books_add_up() {
+        let old = OrderBookState::construct_from(
+            vec![offer("101", "1"), offer("102", "2")],
+            vec![offer("99", "1")],
+            3,
+            Market::from_str("ETH-USD").unwrap(),
+        );
+        let mut new = old.clone();
+        let mut changes = new
+            .update_with(vec![offer("101", "0")], vec![offer("98", "4")], 4)
+            .unwrap();
+        changes.extend(
+            new.update_with(vec![offer("102", "3")], vec![offer("98", "0")], 5)
+                .unwrap(),
+        );
+        // the level added and removed again in between is absent from the old book too
+        let _ = changes.bids.remove(&Decimal::from(98));
+        assert_eq!(BookChanges::between(&old, &new), changes);
+        assert_eq!(BookChanges::between(&new, &new), BookChanges::default());
+    }
+
+    #[test]
+    fn test_ticker_is_the_top_of_the_book() {
+        let book = OrderBookState::construct_from(
+            vec![offer("101", "1"), offer("102", "2")],
+            vec![offer("99", "3")],
+            1,
+            Market::from_str("ETH-USD").unwrap(),
+        );
+        assert_eq!(
+            serde_json::to_string(&book.ticker()).unwrap(),
+            r#"{"type":"ticker","market":"ETH-USD","bid":"99","bid_size":"3","ask":"101","ask_size":"1","mid":"100","spread":"2"}"#
+        );
+
+        let one_sided = OrderBookState::construct_from(
+            vec![offer("101", "1")],
+            vec![],
+            1,
+            Market::from_str("ETH-USD").unwrap(),
+        );
+        let ticker = one_sided.ticker();
+        assert_eq!(ticker.bid, None);
+        assert_eq!(ticker.mid, None);
+        assert_eq!(ticker.ask, Some(Decimal::from(101)));
+    }
+
+    #[test]
+    fn test_view_groups_and_limits_depth() {
+        let book = OrderBookState::construct_from(
+            vec![
+                offer("100.1", "1"),
+                offer("100.4", "2"),
+                offer("100.6", "3"),
+                offer("101.2", "4"),
+            ],
+            vec![offer("99.9", "1"), offer("99.6", "2"), offer("99.4", "3")],
+            1,
+            Market::from_str("ETH-USD").unwrap(),
+        );
+        let view = BookView {
+            depth: Some(2),
+            group: Some(Decimal::from_str("0.5").unwrap()),
+        };
+        assert_eq!(
+            serde_json::to_string(&book.view(&view)).unwrap(),
+            r#"{"market":"ETH-USD","asks":[["100.5","3"],["101","3"]],"bids":[["99.5","3"],["99","3"]]}"#
+        );
+
+        let view = BookView {
+            depth: Some(1),
+            group: None,
+        };
+        assert_eq!(
+            serde_json::to_string(&book.view(&view)).unwrap(),
+            r#"{"market":"ETH-USD","asks":[["100.1","1"]],"bids":[["99.9","1"]]}"#
+        );
+        assert!(BookView::default().is_whole_book());
+
+        // finer than a decimal can count up to the prices in
+        let view = BookView {
+            depth: Some(1),
+            group: Some(Decimal::from_str("0.0000000000000000000000000001").unwrap()),
+        };
+        assert_eq!(
+            serde_json::to_string(&book.view(&view)).unwrap(),
+            r#"{"market":"ETH-USD","asks":[["100.1","1"]],"bids":[["99.9","1"]]}"#
+        );
+    }
+
+    #[test]
+    fn test_trim_crossed_drops_the_stale_side() {
+        let mut book = OrderBookState::construct_from(
+            vec![offer("101", "1"), offer("102", "1")],
+            vec![offer("100", "1"), offer("99", "1")],
+            1,
+            Market::from_str("ETH-USD").unwrap(),
+        );
+        assert_eq!(book.crossing(), None);
+
+        // a fresh bid crosses the asks left over from the snapshot
+        let _ = book
+            .update_with(vec![], vec![offer("101.5", "2")], 2)
+            .unwrap();
+        assert_eq!(
+            book.crossing(),
+            Some(Crossing {
+                kind: CrossingKind::Crossed,
+                best_bid: Decimal::from_str("101.5").unwrap(),
+                best_ask: Decimal::from_str("101").unwrap(),
+            })
+        );
+        let removed = book.trim_crossed();
+        assert_eq!(book.crossing(), None);
+        assert_eq!(book.best_ask(), Some((Decimal::from(102), Decimal::ONE)));
+        assert_eq!(
+            removed.asks.into_iter().collect::<Vec<_>>(),
+            vec![(Decimal::from(101), Decimal::ZERO)]
+        );
+
+        // both sides from the same message cannot be told apart
+        let mut book = OrderBookState::construct_from(
+            vec![offer("100", "1")],
+            vec![offer("100", "1")],
+            1,
+            Market::from_str("ETH-USD").unwrap(),
+        );
+        assert_eq!(book.crossing().map(|c| c.kind), Some(CrossingKind::Locked));
+        assert_eq!(book.trim_crossed(), BookChanges::default());
     }
 }
@@ -0,0 +1,1447 @@
+use std::{collections::BTreeMap, str::FromStr, sync::Arc, time::Duration};
+
+use axum::extract::ws::{close_code, CloseFrame, Message, WebSocket};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::{
+    sync::{mpsc, Notify},
+    task::JoinHandle,
+    time::Instant,
+};
+
+use crate::{
+    analytics::StatsParams,
+    candles::CandlesUpdate,
+    core_types::{BookChanges, BookUpdate, BookView, MarketEvent, OrderBookState},
+    manager::{MarketSubscription, UpstreamManager},
+    market_state::MarketStatesUpdate,
+    metrics::METRICS,
+    subaccounts::SubaccountUpdate,
+    trades::TradesUpdate,
+    upstream_types::{CandleResolution, CandlesId, Market, SubaccountId, Topic},
+    AppState,
+};
+
+pub const OUTBOUND_BUFFER: usize = 64;
+
+/// How long a message may wait in a client's queue, a client further behind
+/// than that is disconnected.
+pub const MAX_CLIENT_LAG: Duration = Duration::from_secs(30);
+
+/// The widest `depth_bps` of the stats channel, all the way from the mid to zero.
+const MAX_DEPTH_BPS: Decimal = Decimal::from_parts(10_000, 0, 0, false, 0);
+
+/// Whether a client only getting a message `queued_at` now has fallen too far
+/// behind. How old the book behind it is does not matter, a quiet market's
+/// latest book can be any age.
+pub fn too_far_behind(queued_at: Instant) -> bool {
+    queued_at.elapsed() >= MAX_CLIENT_LAG
+}
+
+/// What a client can subscribe to for a market.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Channel {
+    #[default]
+    Orderbook,
+    /// Only the best bid and ask, sent when they change.
+    Ticker,
+    /// Analytics of the book, sent when they change.
+    Stats,
+    /// The recent trades first, then the new ones as they happen.
+    Trades,
+    /// The recent candles of a `resolution` first, then the ones that change.
+    Candles,
+    /// Oracle price, funding and open interest of every market at once, so
+    /// subscribed to without markets.
+    Markets,
+    /// The open orders and positions of a `subaccount`, and its fills and
+    /// transfers. Subscribed to without markets.
+    Subaccount,
+    /// The book with the resting orders of a `subaccount` laid over it.
+    Ladder,
+}
+
+impl Channel {
+    /// The upstream topic the channel of the market is made from, candles
+    /// being the only ones with a resolution.
+    pub fn topic(
+        self,
+        market: Market,
+        resolution: Option<CandleResolution>,
+    ) -> Result<Topic, String> {
+        match (self, resolution) {
+            (Channel::Candles, Some(resolution)) => {
+                Ok(Topic::Candles(CandlesId { market, resolution }))
+            }
+            (Channel::Candles, None) => Err("candles need a resolution".to_string()),
+            (_, Some(_)) => Err("resolution only applies to the candles channel".to_string()),
+            (Channel::Orderbook | Channel::Ticker | Channel::Stats | Channel::Ladder, None) => {
+                Ok(Topic::Orderbook(market))
+            }
+            (Channel::Trades, None) => Ok(Topic::Trades(market)),
+            (Channel::Markets, None) => Err("the markets channel takes no markets".to_string()),
+            (Channel::Subaccount, None) => {
+                Err("the subaccount channel takes no markets".to_string())
+            }
+        }
+    }
+}
+
+/// A client has at most one subscription per channel and topic, e.g. one
+/// per resolution of the candles of a market. Ladders also have one per
+/// subaccount laid over the book.
+pub type SubscriptionKey = (Channel, Topic, Option<SubaccountId>);
+
+fn subscription_keys(
+    channel: Channel,
+    markets: &[Market],
+    resolution: Option<CandleResolution>,
+    subaccount: Option<&SubaccountId>,
+) -> Result<Vec<SubscriptionKey>, String> {
+    let topic = match (channel, subaccount) {
+        (Channel::Markets, _) => Some(Topic::Markets),
+        (Channel::Subaccount, Some(id)) => Some(Topic::Subaccount(id.clone())),
+        (Channel::Subaccount, None) => {
+            return Err("the subaccount channel needs a subaccount".to_string())
+        }
+        (Channel::Ladder, None) => return Err("ladders need a subaccount".to_string()),
+        _ => None,
+    };
+    if let Some(topic) = topic.filter(|_| markets.is_empty()) {
+        if resolution.is_some() {
+            return Err("resolution only applies to the candles channel".to_string());
+        }
+        return Ok(vec![(channel, topic, None)]);
+    }
+    let ladder = match channel {
+        Channel::Ladder => subaccount.cloned(),
+        _ => None,
+    };
+    markets
+        .iter()
+        .map(|market| {
+            let topic = channel.topic(market.clone(), resolution)?;
+            Ok((channel, topic, ladder.clone()))
+        })
+        .collect()
+}
+
+/// Takes a single market as well as a list of them.
+fn one_or_many<'de, D>(deserializer: D) -> Result<Vec<Market>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(Market),
+        Many(Vec<Market>),
+    }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(market) => vec![market],
+        OneOrMany::Many(markets) => markets,
+    })
+}
+
+/// Control messages a client can send over its websocket, e.g.
+/// `{"op":"subscribe","id":1,"markets":["ETH-USD"]}`. The channel defaults
+/// to the orderbook, a single `market` can be given instead of `markets`,
+/// the `markets` channel takes none.
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum ClientRequest {
+    Subscribe {
+        #[serde(default)]
+        channel: Channel,
+        #[serde(default, alias = "market", deserialize_with = "one_or_many")]
+        markets: Vec<Market>,
+        /// Of the candles channel.
+        resolution: Option<CandleResolution>,
+        /// Of the subaccount and ladder channels, `ADDRESS/NUMBER`.
+        subaccount: Option<SubaccountId>,
+        #[serde(default)]
+        mode: BookMode,
+        depth: Option<usize>,
+        /// A decimal string like `"0.5"`, just like the prices sent out.
+        group: Option<Decimal>,
+        /// Messages per second at most.
+        max_rate: Option<f64>,
+        depth_bps: Option<Decimal>,
+        fill_size: Option<Decimal>,
+    },
+    Unsubscribe {
+        #[serde(default)]
+        channel: Channel,
+        #[serde(default, alias = "market", deserialize_with = "one_or_many")]
+        markets: Vec<Market>,
+        resolution: Option<CandleResolution>,
+        subaccount: Option<SubaccountId>,
+    },
+    /// Asks for a fresh snapshot, after the client has detected a gap.
+    Resync {
+        #[serde(default)]
+        channel: Channel,
+        #[serde(alias = "market", deserialize_with = "one_or_many")]
+        markets: Vec<Market>,
+    },
+    List,
+    Ping,
+}
+
+/// How book updates of a subscription are sent.
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum BookMode {
+    /// The whole book on every update.
+    #[default]
+    Full,
+    /// A `snapshot` first, then `delta`s of the changed levels only. Every
+    /// delta carries its `seq` and the `prev_seq` it applies on top of, a
+    /// mismatch means updates were missed and a `resync` is needed. Books
+    /// skipped for a slow client or a `max_rate` are covered by the delta
+    /// that follows them.
+    Delta,
+}
+
+/// How one market subscription wants its books.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SubscriptionOptions {
+    pub mode: BookMode,
+    pub view: BookView,
+    pub max_rate: Option<f64>,
+    /// Of the stats channel, defaulting to `StatsParams::default()`.
+    pub depth_bps: Option<Decimal>,
+    pub fill_size: Option<Decimal>,
+    /// Of the candles channel, which has no default.
+    pub resolution: Option<CandleResolution>,
+    /// Whose orders a ladder shows, or which subaccount to follow.
+    pub subaccount: Option<SubaccountId>,
+}
+
+impl SubscriptionOptions {
+    pub fn stats_params(&self) -> StatsParams {
+        let defaults = StatsParams::default();
+        StatsParams {
+            depth_bps: self.depth_bps.unwrap_or(defaults.depth_bps),
+            fill_size: self.fill_size.or(defaults.fill_size),
+        }
+    }
+}
+
+/// Checks that the markets can be subscribed to with the given options,
+/// returning the subscriptions to make or the reason for the client otherwise.
+pub fn validate_subscription(
+    state: &AppState,
+    channel: Channel,
+    markets: &[Market],
+    options: &SubscriptionOptions,
+) -> Result<Vec<SubscriptionKey>, String> {
+    let view = &options.view;
+    match channel {
+        Channel::Subaccount | Channel::Ladder if !state.subaccounts => {
+            return Err("Subaccounts are not served here".to_string())
+        }
+        Channel::Ladder if options.subaccount.is_none() => {
+            return Err("ladders need a subaccount".to_string())
+        }
+        Channel::Orderbook
+        | Channel::Ticker
+        | Channel::Stats
+        | Channel::Trades
+        | Channel::Candles
+        | Channel::Markets
+            if options.subaccount.is_some() =>
+        {
+            return Err("subaccount only applies to the subaccount and ladder channels".to_string())
+        }
+        Channel::Ticker
+        | Channel::Stats
+        | Channel::Trades
+        | Channel::Candles
+        | Channel::Markets
+        | Channel::Subaccount
+        | Channel::Ladder
+            if options.mode != BookMode::Full || !view.is_whole_book() =>
+        {
+            return Err("mode, depth and group only apply to the orderbook channel".to_string())
+        }
+        Channel::Orderbook
+        | Channel::Ticker
+        | Channel::Trades
+        | Channel::Candles
+        | Channel::Markets
+        | Channel::Subaccount
+        | Channel::Ladder
+            if options.depth_bps.is_some() || options.fill_size.is_some() =>
+        {
+            return Err("depth_bps and fill_size only apply to the stats channel".to_string())
+        }
+        _ => {}
+    }
+    if options
+        .depth_bps
+        .is_some_and(|bps| bps <= Decimal::ZERO || bps > MAX_DEPTH_BPS)
+    {
+        return Err(format!(
+            "depth_bps must be positive and at most {}",
+            MAX_DEPTH_BPS
+        ));
+    }
+    if options.fill_size.is_some_and(|size| size <= Decimal::ZERO) {
+        return Err("fill_size must be positive".to_string());
+    }
+    if options
+        .max_rate
+        .is_some_and(|rate| !(rate.is_finite() && rate > 0.0))
+    {
+        return Err("max_rate must be a positive number".to_string());
+    }
+    if options.mode == BookMode::Delta && !view.is_whole_book() {
+        return Err("depth and group are only supported in full mode".to_string());
+    }
+    if view.depth == Some(0) {
+        return Err("depth must be at least 1".to_string());
+    }
+    if view.group.is_some_and(|group| group <= Decimal::ZERO) {
+        return Err("group must be positive".to_string());
+    }
+    let keys = subscription_keys(
+        channel,
+        markets,
+        options.resolution,
+        options.subaccount.as_ref(),
+    )?;
+    for market in markets {
+        let info = state.registry.check(market).map_err(|e| e.to_string())?;
+        if let (Some(group), Some(tick_size)) = (view.group, info.tick_size) {
+            if !(group % tick_size).is_zero() {
+                return Err(format!(
+                    "group {} is not a multiple of the tick size {} of {}",
+                    group, tick_size, market
+                ));
+            }
+        }
+    }
+    Ok(keys)
+}
+
+/// Replies to `ClientRequest`s, echoing the `id` of the request they answer.
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerReply {
+    Subscribed {
+        id: Option<Value>,
+        channel: Channel,
+        markets: Vec<Market>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        resolution: Option<CandleResolution>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        subaccount: Option<SubaccountId>,
+    },
+    Unsubscribed {
+        id: Option<Value>,
+        channel: Channel,
+        markets: Vec<Market>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        resolution: Option<CandleResolution>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        subaccount: Option<SubaccountId>,
+    },
+    Resyncing {
+        id: Option<Value>,
+        channel: Channel,
+        markets: Vec<Market>,
+    },
+    /// The subscribed markets by channel, candles as `MARKET/RESOLUTION`,
+    /// subaccounts as `ADDRESS/NUMBER` and none for the markets channel.
+    Markets {
+        id: Option<Value>,
+        markets: BTreeMap<Channel, Vec<String>>,
+    },
+    Pong {
+        id: Option<Value>,
+    },
+    Error {
+        id: Option<Value>,
+        message: String,
+    },
+}
+
+/// Splits a raw control message into its request id and the request itself,
+/// so that even malformed requests can be answered with their id.
+fn parse_request(text: &str) -> (Option<Value>, Result<ClientRequest, String>) {
+    let value: Value = match serde_json::from_str(text) {
+        Ok(value) => value,
+        Err(e) => return (None, Err(format!("Invalid JSON: {}", e))),
+    };
+    let id = value.get("id").cloned();
+    let request = ClientRequest::deserialize(value).map_err(|e| format!("Invalid request: {}", e));
+    (id, request)
+}
+
+/// Where a client left off, as the id of the last event it got, e.g.
+/// `ETH-USD:9a75aff4-923a-4f43-9197-81eefceaacd1:1042`.
+///
+/// Message ids are counted across every market of the upstream connection,
+/// so they only tell how far the client got in the market of that event.
+/// The other markets of the stream start over with their latest book.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResumePoint {
+    pub market: Market,
+    pub connection_id: String,
+    pub message_id: usize,
+}
+
+impl ResumePoint {
+    pub fn event_id(update: &BookUpdate) -> String {
+        format!(
+            "{}:{}:{}",
+            update.book.market, update.connection_id, update.seq
+        )
+    }
+
+    /// Whether the client has already seen the update, or a later one of its market.
+    pub fn covers(&self, update: &BookUpdate) -> bool {
+        update.book.market == self.market
+            && *update.connection_id == self.connection_id
+            && update.seq <= self.message_id
+    }
+}
+
+impl FromStr for ResumePoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid event id '{}'", s);
+        let (market, rest) = s.split_once(':').ok_or_else(invalid)?;
+        let (connection_id, message_id) = rest.rsplit_once(':').ok_or_else(invalid)?;
+        Ok(Self {
+            market: Market::from_str(market).map_err(|_| invalid())?,
+            connection_id: connection_id.to_string(),
+            message_id: message_id.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+pub struct SubscriptionTask {
+    task: JoinHandle<()>,
+    resync: Arc<Notify>,
+}
+
+/// What the forwarding task of `market` has for the client.
+pub enum Outbound {
+    Message {
+        key: SubscriptionKey,
+        message: String,
+        /// The book behind the message, statuses have none.
+        update: Option<Arc<BookUpdate>>,
+        /// When the message was put in the client's queue.
+        queued_at: Instant,
+    },
+    /// The indexer dropped the market, so should the client.
+    Failed { key: SubscriptionKey },
+    /// The updates of the market stopped for good.
+    Ended { key: SubscriptionKey },
+}
+
+/// Forwarding tasks of the markets a client is subscribed to, aborted on drop.
+#[derive(Default)]
+pub struct Subscriptions(pub BTreeMap<SubscriptionKey, SubscriptionTask>);
+
+impl Subscriptions {
+    /// Starts forwarding the channel of the market to `outbound`, unless it already is.
+    pub fn add(
+        &mut self,
+        manager: &UpstreamManager,
+        key: SubscriptionKey,
+        options: SubscriptionOptions,
+        outbound: mpsc::Sender<Outbound>,
+        resume: Option<ResumePoint>,
+    ) {
+        if self.0.contains_key(&key) {
+            return;
+        }
+        let subscription = manager.subscribe(&key.1);
+        let resync = Arc::new(Notify::new());
+        let task = match key.0 {
+            Channel::Orderbook => tokio::spawn(forward(
+                subscription,
+                options,
+                resync.clone(),
+                outbound,
+                resume,
+            )),
+            Channel::Ticker => tokio::spawn(forward_derived(
+                subscription,
+                None,
+                key.clone(),
+                |book: &OrderBookState, _| book.ticker(),
+                options.max_rate,
+                outbound,
+            )),
+            Channel::Stats => {
+                let params = options.stats_params();
+                // for the oracle price
+                let markets = manager.subscribe(&Topic::Markets);
+                tokio::spawn(forward_derived(
+                    subscription,
+                    Some(markets),
+                    key.clone(),
+                    move |book: &OrderBookState, markets: Option<&MarketEvent>| {
+                        let oracle_price = match markets {
+                            Some(MarketEvent::Markets(markets)) => {
+                                markets.oracle_price(&book.market)
+                            }
+                            _ => None,
+                        };
+                        book.stats(&params, oracle_price)
+                    },
+                    options.max_rate,
+                    outbound,
+                ))
+            }
+            Channel::Ladder => {
+                let id = key
+                    .2
+                    .clone()
+                    .expect("ladder keys are made with a subaccount");
+                let orders = manager.subscribe(&Topic::Subaccount(id.clone()));
+                tokio::spawn(forward_derived(
+                    subscription,
+                    Some(orders),
+                    key.clone(),
+                    move |book: &OrderBookState, orders: Option<&MarketEvent>| {
+                        let subaccount = match orders {
+                            Some(MarketEvent::Subaccount(subaccount)) => Some(&**subaccount),
+                            _ => None,
+                        };
+                        book.ladder(&id, subaccount)
+                    },
+                    options.max_rate,
+                    outbound,
+                ))
+            }
+            Channel::Trades => tokio::spawn(forward_recent(
+                subscription,
+                key.0,
+                |event: &MarketEvent| match event {
+                    MarketEvent::Trades(update) => Some(update.clone()),
+                    _ => None,
+                },
+                TradesUpdate::json_since,
+                options.max_rate,
+                outbound,
+            )),
+            Channel::Candles => tokio::spawn(forward_recent(
+                subscription,
+                key.0,
+                |event: &MarketEvent| match event {
+                    MarketEvent::Candles(update) => Some(update.clone()),
+                    _ => None,
+                },
+                CandlesUpdate::json_since,
+                options.max_rate,
+                outbound,
+            )),
+            Channel::Markets => tokio::spawn(forward_recent(
+                subscription,
+                key.0,
+                |event: &MarketEvent| match event {
+                    MarketEvent::Markets(update) => Some(update.clone()),
+                    _ => None,
+                },
+                MarketStatesUpdate::json_since,
+                options.max_rate,
+                outbound,
+            )),
+            Channel::Subaccount => tokio::spawn(forward_recent(
+                subscription,
+                key.0,
+                |event: &MarketEvent| match event {
+                    MarketEvent::Subaccount(update) => Some(update.clone()),
+                    _ => None,
+                },
+                SubaccountUpdate::json_since,
+                options.max_rate,
+                outbound,
+            )),
+        };
+        let _ = self.0.insert(key, SubscriptionTask { task, resync });
+    }
+}
+
+/// Passes a status on, ending the subscription if the market has failed.
+/// Returns false once there is nothing left to forward.
+async fn forward_status(
+    outbound: &mpsc::Sender<Outbound>,
+    key: &SubscriptionKey,
+    event: &MarketEvent,
+) -> bool {
+    let status = Outbound::Message {
+        key: key.clone(),
+        message: event.status_json().expect("only books are not statuses"),
+        update: None,
+        queued_at: Instant::now(),
+    };
+    if outbound.send(status).await.is_err() {
+        return false;
+    }
+    if let MarketEvent::Failed { .. } = event {
+        let _ = outbound.send(Outbound::Failed { key: key.clone() }).await;
+        return false;
+    }
+    true
+}
+
+impl Drop for Subscriptions {
+    fn drop(&mut self) {
+        for subscription in self.0.values() {
+            subscription.task.abort();
+        }
+    }
+}
+
+/// Waits until `at`, then for room in the client's queue.
+async fn reserve_after(
+    outbound: &mpsc::Sender<Outbound>,
+    at: Instant,
+) -> Result<mpsc::Permit<'_, Outbound>, mpsc::error::SendError<()>> {
+    tokio::time::sleep_until(at).await;
+    outbound.reserve().await
+}
+
+/// Renders the updates of one market for one client, until the client goes away.
+///
+/// Books wait for room in the client's queue, a newer one replacing the one
+/// waiting. In delta mode the books skipped that way are bridged with a delta
+/// from the last book the client got. A client resuming from `resume` is not
+/// sent the book it already has, in delta mode it gets the deltas that follow
+/// right away.
+async fn forward(
+    mut subscription: MarketSubscription,
+    options: SubscriptionOptions,
+    resync: Arc<Notify>,
+    outbound: mpsc::Sender<Outbound>,
+    mut resume: Option<ResumePoint>,
+) {
+    let key = (Channel::Orderbook, subscription.topic().clone(), None);
+    let min_interval = options
+        .max_rate
+        .map(|rate| Duration::from_secs_f64(1.0 / rate));
+    let mut next_allowed = Instant::now();
+    let mut pending: Option<Arc<BookUpdate>> = None;
+    // the book the client has, deltas apply on top of it
+    let mut sent: Option<Arc<BookUpdate>> = None;
+    let mut force_snapshot = false;
+    loop {
+        tokio::select! {
+            event = subscription.recv() => match event {
+                None => {
+                    let _ = outbound.send(Outbound::Ended { key }).await;
+                    return;
+                }
+                Some(MarketEvent::Book(update)) => {
+                    // only the first book can be one the client already has
+                    match resume.take() {
+                        Some(resume) if resume.covers(&update) => sent = Some(update),
+                        _ => pending = Some(update),
+                    }
+                }
+                Some(
+                    MarketEvent::Trades(_)
+                    | MarketEvent::Candles(_)
+                    | MarketEvent::Markets(_)
+                    | MarketEvent::Subaccount(_),
+                ) => {}
+                Some(event) => {
+                    // clients start over with a snapshot after a status
+                    pending = None;
+                    sent = None;
+                    if !forward_status(&outbound, &key, &event).await {
+                        return;
+                    }
+                }
+            },
+            _ = resync.notified() => {
+                // right away if nothing is queued, otherwise with the next update
+                force_snapshot = true;
+                if pending.is_none() {
+                    pending = subscription.latest_if_caught_up();
+                }
+            }
+            permit = reserve_after(&outbound, next_allowed), if pending.is_some() => {
+                let Ok(permit) = permit else {
+                    return;
+                };
+                let update = pending.take().expect("only reserved for a pending book");
+                let last = sent.as_deref().filter(|_| !force_snapshot);
+                permit.send(Outbound::Message {
+                    key: key.clone(),
+                    message: render_book(&options, &update, last),
+                    update: Some(update.clone()),
+                    queued_at: Instant::now(),
+                });
+                force_snapshot = false;
+                sent = Some(update);
+                if let Some(min_interval) = min_interval {
+                    next_allowed = Instant::now() + min_interval;
+                }
+            }
+        }
+    }
+}
+
+/// Renders a book for a subscription, in delta mode relative to the `last`
+/// book sent, if there is one to build on.
+fn render_book(
+    options: &SubscriptionOptions,
+    update: &BookUpdate,
+    last: Option<&BookUpdate>,
+) -> String {
+    match options.mode {
+        BookMode::Full if options.view.is_whole_book() => update.full_json().to_string(),
+        BookMode::Full => update.view_json(&options.view),
+        BookMode::Delta => match last {
+            None => update.snapshot_json().to_string(),
+            Some(last) => match update.delta_json() {
+                Some(delta) if update.follows(last.seq) => delta.to_string(),
+                _ => {
+                    let changes = BookChanges::between(&last.book, &update.book);
+                    BookUpdate::delta(update.book.clone(), last.seq, changes)
+                        .at_block(update.block.clone())
+                        .delta_json()
+                        .expect("deltas have a delta")
+                        .to_string()
+                }
+            },
+        },
+    }
+}
+
+/// The next event of a subscription that may not be there, which never comes.
+async fn recv_if(subscription: &mut Option<MarketSubscription>) -> Option<MarketEvent> {
+    match subscription {
+        Some(subscription) => subscription.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Sends what `derive` makes of the book of one market whenever that changes,
+/// like its ticker. A `context` subscription, like the markets for the
+/// oracle price, has its latest data passed along and is derived anew with
+/// when it changes. With a `max_rate`, changes coming in quicker are held
+/// back and only the latest of them is sent once allowed.
+async fn forward_derived<T, F>(
+    mut subscription: MarketSubscription,
+    mut context: Option<MarketSubscription>,
+    key: SubscriptionKey,
+    derive: F,
+    max_rate: Option<f64>,
+    outbound: mpsc::Sender<Outbound>,
+) where
+    T: Serialize + PartialEq,
+    F: Fn(&OrderBookState, Option<&MarketEvent>) -> T,
+{
+    let min_interval = max_rate.map(|rate| Duration::from_secs_f64(1.0 / rate));
+    let mut next_allowed = Instant::now();
+    let mut sent: Option<T> = None;
+    // a change of the context alone has no book behind it to measure the lag by
+    let mut pending: Option<(T, Option<Arc<BookUpdate>>)> = None;
+    let mut book: Option<Arc<BookUpdate>> = None;
+    let mut latest: Option<MarketEvent> = None;
+    loop {
+        tokio::select! {
+            event = subscription.recv() => match event {
+                None => {
+                    let _ = outbound.send(Outbound::Ended { key }).await;
+                    return;
+                }
+                Some(
+                    MarketEvent::Trades(_)
+                    | MarketEvent::Candles(_)
+                    | MarketEvent::Markets(_)
+                    | MarketEvent::Subaccount(_),
+                ) => {}
+                Some(event @ MarketEvent::Resyncing { .. }) => {
+                    // nothing is derived from the stale book, and the first
+                    // one after the resync is sent even if it looks the same
+                    pending = None;
+                    sent = None;
+                    book = None;
+                    if !forward_status(&outbound, &key, &event).await {
+                        return;
+                    }
+                }
+                Some(event @ MarketEvent::Failed { .. }) => {
+                    let _ = forward_status(&outbound, &key, &event).await;
+                    return;
+                }
+                Some(MarketEvent::Book(update)) => {
+                    let derived = derive(&update.book, latest.as_ref());
+                    pending = (sent.as_ref() != Some(&derived)).then_some((derived, Some(update.clone())));
+                    book = Some(update);
+                }
+            },
+            event = recv_if(&mut context) => match event {
+                // the book goes on without it
+                None | Some(MarketEvent::Failed { .. }) => context = None,
+                Some(MarketEvent::Resyncing { .. }) => {}
+                Some(event) => {
+                    latest = Some(event);
+                    if let Some(book) = &book {
+                        let derived = derive(&book.book, latest.as_ref());
+                        let update = pending.take().and_then(|(_, update)| update);
+                        pending = (sent.as_ref() != Some(&derived)).then_some((derived, update));
+                    }
+                }
+            },
+            permit = reserve_after(&outbound, next_allowed), if pending.is_some() => {
+                let Ok(permit) = permit else {
+                    return;
+                };
+                let (derived, update) = pending.take().expect("only reserved for a pending message");
+                permit.send(Outbound::Message {
+                    key: key.clone(),
+                    message: serde_json::to_string(&derived).expect("derived messages should serialize"),
+                    update,
+                    queued_at: Instant::now(),
+                });
+                sent = Some(derived);
+                if let Some(min_interval) = min_interval {
+                    next_allowed = Instant::now() + min_interval;
+                }
+            }
+        }
+    }
+}
+
+/// Sends the recent trades or candles of one market, `pick`ed from its
+/// events, and then whatever `render` finds changed since the last message.
+/// Changes coming in quicker than `max_rate` are sent together.
+async fn forward_recent<U, P, R>(
+    mut subscription: MarketSubscription,
+    channel: Channel,
+    pick: P,
+    render: R,
+    max_rate: Option<f64>,
+    outbound: mpsc::Sender<Outbound>,
+) where
+    P: Fn(&MarketEvent) -> Option<Arc<U>>,
+    R: Fn(&U, Option<&U>) -> Option<String>,
+{
+    let key = (channel, subscription.topic().clone(), None);
+    let min_interval = max_rate.map(|rate| Duration::from_secs_f64(1.0 / rate));
+    let mut next_allowed = Instant::now();
+    let mut pending: Option<Arc<U>> = None;
+    // what the client has, only changes are sent
+    let mut sent: Option<Arc<U>> = None;
+    loop {
+        tokio::select! {
+            event = subscription.recv() => match event {
+                None => {
+                    let _ = outbound.send(Outbound::Ended { key }).await;
+                    return;
+                }
+                Some(event @ MarketEvent::Failed { .. }) => {
+                    let _ = forward_status(&outbound, &key, &event).await;
+                    return;
+                }
+                // what was sent stays true, a resync only refetches the recent ones
+                Some(event) => {
+                    if let Some(update) = pick(&event) {
+                        pending = Some(update);
+                    }
+                }
+            },
+            permit = reserve_after(&outbound, next_allowed), if pending.is_some() => {
+                let Ok(permit) = permit else {
+                    return;
+                };
+                let update = pending.take().expect("only reserved for a pending update");
+                let Some(message) = render(&update, sent.as_deref()) else {
+                    continue;
+                };
+                permit.send(Outbound::Message {
+                    key: key.clone(),
+                    message,
+                    update: None,
+                    queued_at: Instant::now(),
+                });
+                sent = Some(update);
+                if let Some(min_interval) = min_interval {
+                    next_allowed = Instant::now() + min_interval;
+                }
+            }
+        }
+    }
+}
+
+struct ClientSession {
+    state: AppState,
+    subscriptions: Subscriptions,
+    outbound: mpsc::Sender<Outbound>,
+}
+
+impl ClientSession {
+    fn subscribe(&mut self, key: SubscriptionKey, options: &SubscriptionOptions) {
+        self.subscriptions.add(
+            &self.state.manager,
+            key,
+            options.clone(),
+            self.outbound.clone(),
+            None,
+        );
+    }
+
+    fn unsubscribe(&mut self, key: &SubscriptionKey) {
+        if let Some(subscription) = self.subscriptions.0.remove(key) {
+            subscription.task.abort();
+        }
+    }
+
+    /// The keys of the subscriptions, if the client has all of them.
+    fn check_subscribed(
+        &self,
+        channel: Channel,
+        markets: &[Market],
+        resolution: Option<CandleResolution>,
+        subaccount: Option<&SubaccountId>,
+    ) -> Result<Vec<SubscriptionKey>, String> {
+        let keys = subscription_keys(channel, markets, resolution, subaccount)?;
+        match keys
+            .iter()
+            .find(|key| !self.subscriptions.0.contains_key(key))
+        {
+            Some((_, topic, _)) => match topic.id() {
+                Some(id) => Err(format!("Not subscribed to market: {}", id)),
+                None => Err(format!("Not subscribed to the {}", topic)),
+            },
+            None => Ok(keys),
+        }
+    }
+
+    /// Fails if the client already has one of the subscriptions, whose
+    /// options would otherwise be silently kept.
+    fn check_not_subscribed(&self, keys: &[SubscriptionKey]) -> Result<(), String> {
+        match keys
+            .iter()
+            .find(|key| self.subscriptions.0.contains_key(key))
+        {
+            Some((_, topic, _)) => match topic.id() {
+                Some(id) => Err(format!(
+                    "Already subscribed to market: {}, unsubscribe first",
+                    id
+                )),
+                None => Err(format!(
+                    "Already subscribed to the {}, unsubscribe first",
+                    topic
+                )),
+            },
+            None => Ok(()),
+        }
+    }
+
+    fn handle_request(&mut self, id: Option<Value>, request: ClientRequest) -> ServerReply {
+        match request {
+            ClientRequest::Subscribe {
+                channel,
+                markets,
+                resolution,
+                subaccount,
+                mode,
+                depth,
+                group,
+                max_rate,
+                depth_bps,
+                fill_size,
+            } => {
+                let options = SubscriptionOptions {
+                    mode,
+                    view: BookView { depth, group },
+                    max_rate,
+                    depth_bps,
+                    fill_size,
+                    resolution,
+                    subaccount,
+                };
+                // all or nothing, so that the client never has to guess what went through
+                let keys = match validate_subscription(&self.state, channel, &markets, &options)
+                    .and_then(|keys| self.check_not_subscribed(&keys).map(|()| keys))
+                {
+                    Ok(keys) => keys,
+                    Err(message) => return ServerReply::Error { id, message },
+                };
+                for key in keys {
+                    self.subscribe(key, &options);
+                }
+                ServerReply::Subscribed {
+                    id,
+                    channel,
+                    markets,
+                    resolution,
+                    subaccount: options.subaccount,
+                }
+            }
+            ClientRequest::Unsubscribe {
+                channel,
+                markets,
+                resolution,
+                subaccount,
+            } => {
+                let keys =
+                    match self.check_subscribed(channel, &markets, resolution, subaccount.as_ref())
+                    {
+                        Ok(keys) => keys,
+                        Err(message) => return ServerReply::Error { id, message },
+                    };
+                for key in keys.iter() {
+                    self.unsubscribe(key);
+                }
+                ServerReply::Unsubscribed {
+                    id,
+                    channel,
+                    markets,
+                    resolution,
+                    subaccount,
+                }
+            }
+            ClientRequest::Resync { channel, markets } => {
+                if channel != Channel::Orderbook {
+                    return ServerReply::Error {
+                        id,
+                        message: "Only orderbooks can be resynced".to_string(),
+                    };
+                }
+                let keys = match self.check_subscribed(channel, &markets, None, None) {
+                    Ok(keys) => keys,
+                    Err(message) => return ServerReply::Error { id, message },
+                };
+                for key in keys.iter() {
+                    self.subscriptions.0[key].resync.notify_one();
+                }
+                ServerReply::Resyncing {
+                    id,
+                    channel,
+                    markets,
+                }
+            }
+            ClientRequest::List => {
+                let mut markets: BTreeMap<Channel, Vec<String>> = BTreeMap::new();
+                for (channel, topic, _) in self.subscriptions.0.keys() {
+                    markets.entry(*channel).or_default().extend(topic.id());
+                }
+                ServerReply::Markets { id, markets }
+            }
+            ClientRequest::Ping => ServerReply::Pong { id },
+        }
+    }
+
+    async fn run(&mut self, mut socket: WebSocket, mut outbound: mpsc::Receiver<Outbound>) {
+        let network = self.state.manager.network().to_string();
+        loop {
+            let (message, received_at) = tokio::select! {
+                incoming = socket.recv() => match incoming {
+                    // the close handshake is completed by axum itself
+                    None | Some(Ok(Message::Close(_))) => {
+                        eprintln!("User disconnected");
+                        return;
+                    }
+                    Some(Err(e)) => {
+                        eprintln!("User connection failed: {}", e);
+                        return;
+                    }
+                    Some(Ok(Message::Binary(_))) => {
+                        close(socket, close_code::UNSUPPORTED, "Only text messages are supported").await;
+                        return;
+                    }
+                    Some(Ok(Message::Text(text))) => {
+                        let reply = match parse_request(&text) {
+                            (id, Ok(request)) => self.handle_request(id, request),
+                            (id, Err(message)) => ServerReply::Error { id, message },
+                        };
+                        (serde_json::to_string(&reply).expect("replies should serialize"), None)
+                    }
+                    // pings are answered by axum itself
+                    Some(Ok(Message::Ping(_) | Message::Pong(_))) => continue,
+                },
+                Some(outbound) = outbound.recv() => match outbound {
+                    // books still in flight after an unsubscribe are dropped
+                    Outbound::Message { key, .. }
+                    | Outbound::Failed { key }
+                    | Outbound::Ended { key }
+                        if !self.subscriptions.0.contains_key(&key) => continue,
+                    Outbound::Failed { key } => {
+                        // the status saying why has already been sent
+                        self.unsubscribe(&key);
+                        continue;
+                    }
+                    Outbound::Message { queued_at, .. } if too_far_behind(queued_at) => {
+                        METRICS.slow_client_disconnects.with_label_values(&[&network]).inc();
+                        close(socket, close_code::POLICY, "Too far behind").await;
+                        return;
+                    }
+                    Outbound::Message { message, update, .. } => {
+                        (message, update.map(|update| update.received_at))
+                    }
+                    Outbound::Ended { key: (_, topic, _) } => {
+                        let reason = match topic.market() {
+                            Some(market) => format!("Updates of {} are no longer available", market),
+                            None => format!("Updates of the {} are no longer available", topic),
+                        };
+                        close(socket, close_code::ERROR, &reason).await;
+                        return;
+                    }
+                }
+            };
+            let bytes = message.len();
+            match tokio::time::timeout(MAX_CLIENT_LAG, socket.send(Message::Text(message))).await {
+                Ok(Ok(())) => {}
+                Ok(Err(_)) => {
+                    eprintln!("User disconnected");
+                    return;
+                }
+                Err(_) => {
+                    eprintln!("Disconnecting user: Not reading");
+                    METRICS
+                        .slow_client_disconnects
+                        .with_label_values(&[&network])
+                        .inc();
+                    return;
+                }
+            }
+            METRICS
+                .bytes_sent
+                .with_label_values(&[&network])
+                .inc_by(bytes as u64);
+            if let Some(received_at) = received_at {
+                METRICS
+                    .update_latency
+                    .with_label_values(&[&network])
+                    .observe(received_at.elapsed().as_secs_f64());
+            }
+        }
+    }
+}
+
+/// Sends a close frame, the client is expected to hang up in turn.
+async fn close(mut socket: WebSocket, code: u16, reason: &str) {
+    eprintln!("Disconnecting user: {}", reason);
+    let frame = CloseFrame {
+        code,
+        reason: reason.to_string().into(),
+    };
+    let _ = socket.send(Message::Close(Some(frame))).await;
+}
+
+/// Serves one client, starting with the subscriptions of the query string.
+pub async fn handle_socket(
+    socket: WebSocket,
+    state: AppState,
+    keys: Vec<SubscriptionKey>,
+    options: SubscriptionOptions,
+) {
+    let (outbound_tx, outbound_rx) = mpsc::channel(OUTBOUND_BUFFER);
+    let mut session = ClientSession {
+        state,
+        subscriptions: Subscriptions::default(),
+        outbound: outbound_tx,
+    };
+    for key in keys {
+        session.subscribe(key, &options);
+    }
+    let clients = METRICS
+        .clients
+        .with_label_values(&[session.state.manager.network()]);
+    clients.inc();
+    session.run(socket, outbound_rx).await;
+    clients.dec();
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{core_types::Offer, upstream::OrderBookStream};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_old_books_are_no_sign_of_lag() {
+        let (handle, stream, feed) = OrderBookStream::channel();
+        let manager = UpstreamManager::with_upstream("test", handle, stream);
+        let market = Market::from_str("ETH-USD").unwrap();
+        let topic = Topic::Orderbook(market.clone());
+        // a quiet market, whose latest book came long ago
+        let mut follower = manager.subscribe(&topic);
+        let book = OrderBookState::construct_from(vec![], vec![], 1, market);
+        let mut update = BookUpdate::snapshot(book);
+        update.received_at = std::time::Instant::now() - MAX_CLIENT_LAG * 2;
+        feed.tx
+            .send(Ok(MarketEvent::Book(Arc::new(update))))
+            .await
+            .unwrap();
+        assert!(follower.recv().await.is_some());
+
+        let (outbound_tx, mut outbound) = mpsc::channel(OUTBOUND_BUFFER);
+        let mut subscriptions = Subscriptions::default();
+        subscriptions.add(
+            &manager,
+            (Channel::Orderbook, topic, None),
+            SubscriptionOptions::default(),
+            outbound_tx,
+            None,
+        );
+        let Some(Outbound::Message {
+            update: Some(update),
+            queued_at,
+            ..
+        }) = outbound.recv().await
+        else {
+            panic!("expected the latest book");
+        };
+        assert!(update.received_at.elapsed() >= MAX_CLIENT_LAG);
+        assert!(!too_far_behind(queued_at));
+    }
+
+    #[test]
+    fn test_parse_requests() {
+        let (id, request) = parse_request(r#"{"op":"subscribe","id":7,"markets":["ETH-USD"]}"#);
+        assert_eq!(id, Some(Value::from(7)));
+        assert_eq!(
+            request,
+            Ok(ClientRequest::Subscribe {
+                channel: Channel::Orderbook,
+                markets: vec![Market::from_str("ETH-USD").unwrap()],
+                resolution: None,
+                subaccount: None,
+                mode: BookMode::Full,
+                depth: None,
+                group: None,
+                max_rate: None,
+                depth_bps: None,
+                fill_size: None,
+            })
+        );
+
+        let (_, request) = parse_request(
+            r#"{"op":"subscribe","channel":"stats","markets":["ETH-USD"],"depth_bps":"25","fill_size":"1.5"}"#,
+        );
+        assert!(matches!(
+            request,
+            Ok(ClientRequest::Subscribe {
+                channel: Channel::Stats,
+                depth_bps: Some(_),
+                fill_size: Some(_),
+                ..
+            })
+        ));
+
+        let (_, request) =
+            parse_request(r#"{"op":"subscribe","channel":"trades","markets":["ETH-USD"]}"#);
+        assert!(matches!(
+            request,
+            Ok(ClientRequest::Subscribe {
+                channel: Channel::Trades,
+                ..
+            })
+        ));
+
+        let (_, request) = parse_request(
+            r#"{"op":"subscribe","channel":"candles","market":"ETH-USD","resolution":"1MIN"}"#,
+        );
+        let Ok(ClientRequest::Subscribe {
+            channel: Channel::Candles,
+            markets,
+            resolution: Some(resolution),
+            ..
+        }) = request
+        else {
+            panic!("expected a candles subscription, got {:?}", request);
+        };
+        assert_eq!(
+            Channel::Candles.topic(markets[0].clone(), Some(resolution)),
+            Ok(Topic::Candles(CandlesId::from_str("ETH-USD/1MIN").unwrap()))
+        );
+        assert!(Channel::Candles.topic(markets[0].clone(), None).is_err());
+        assert!(Channel::Ticker
+            .topic(markets[0].clone(), Some(resolution))
+            .is_err());
+        let (_, request) = parse_request(
+            r#"{"op":"subscribe","channel":"candles","market":"ETH-USD","resolution":"2MINS"}"#,
+        );
+        assert!(request.is_err());
+
+        let (_, request) =
+            parse_request(r#"{"op":"subscribe","channel":"subaccount","subaccount":"dydx1abc/0"}"#);
+        let Ok(ClientRequest::Subscribe {
+            channel: Channel::Subaccount,
+            markets,
+            subaccount: Some(subaccount),
+            ..
+        }) = request
+        else {
+            panic!("expected a subaccount subscription, got {:?}", request);
+        };
+        assert_eq!(
+            subscription_keys(Channel::Subaccount, &markets, None, Some(&subaccount)),
+            Ok(vec![(
+                Channel::Subaccount,
+                Topic::Subaccount(subaccount.clone()),
+                None
+            )])
+        );
+        assert!(subscription_keys(Channel::Subaccount, &markets, None, None).is_err());
+        assert_eq!(
+            Channel::Ladder.topic(Market::from_str("ETH-USD").unwrap(), None),
+            Ok(Topic::Orderbook(Market::from_str("ETH-USD").unwrap()))
+        );
+        let eth = [Market::from_str("ETH-USD").unwrap()];
+        assert_eq!(
+            subscription_keys(Channel::Ladder, &eth, None, Some(&subaccount)),
+            Ok(vec![(
+                Channel::Ladder,
+                Topic::Orderbook(eth[0].clone()),
+                Some(subaccount.clone())
+            )])
+        );
+        assert!(subscription_keys(Channel::Ladder, &eth, None, None).is_err());
+
+        let (_, request) = parse_request(
+            r#"{"op":"subscribe","channel":"ticker","markets":["BTC-USD"],"max_rate":2}"#,
+        );
+        assert!(matches!(
+            request,
+            Ok(ClientRequest::Subscribe {
+                channel: Channel::Ticker,
+                max_rate: Some(rate),
+                ..
+            }) if rate == 2.0
+        ));
+
+        let (_, request) =
+            parse_request(r#"{"op":"subscribe","markets":["BTC-USD"],"mode":"delta"}"#);
+        assert!(matches!(
+            request,
+            Ok(ClientRequest::Subscribe {
+                mode: BookMode::Delta,
+                ..
+            })
+        ));
+
+        let (_, request) =
+            parse_request(r#"{"op":"subscribe","markets":["BTC-USD"],"depth":10,"group":"0.5"}"#);
+        assert!(matches!(
+            request,
+            Ok(ClientRequest::Subscribe {
+                depth: Some(10),
+                group: Some(_),
+                ..
+            })
+        ));
+        // decimals are strings both ways, floats would not be exact
+        let (_, request) = parse_request(r#"{"op":"subscribe","markets":["BTC-USD"],"group":0.5}"#);
+        assert!(request.is_err());
+
+        let (id, request) = parse_request(r#"{"op":"ping","id":"abc"}"#);
+        assert_eq!(id, Some(Value::from("abc")));
+        assert_eq!(request, Ok(ClientRequest::Ping));
+
+        let (id, request) = parse_request(r#"{"op":"subscribe","id":8,"markets":["eth"]}"#);
+        assert_eq!(id, Some(Value::from(8)));
+        assert!(request.is_err());
+
+        let (id, request) = parse_request("not json");
+        assert_eq!(id, None);
+        assert!(request.is_err());
+    }
+
+    #[test]
+    fn test_serialize_replies() {
+        let reply = ServerReply::Error {
+            id: Some(Value::from(3)),
+            message: "Unknown market: FOO-USD".into(),
+        };
+        assert_eq!(
+            serde_json::to_string(&reply).unwrap(),
+            r#"{"type":"error","id":3,"message":"Unknown market: FOO-USD"}"#
+        );
+        let reply = ServerReply::Markets {
+            id: None,
+            markets: [
+                (Channel::Ticker, vec!["ETH-USD".to_string()]),
+                (Channel::Candles, vec!["ETH-USD/1HOUR".to_string()]),
+            ]
+            .into(),
+        };
+        assert_eq!(
+            serde_json::to_string(&reply).unwrap(),
+            r#"{"type":"markets","id":null,"markets":{"ticker":["ETH-USD"],"candles":["ETH-USD/1HOUR"]}}"#
+        );
+        let reply = ServerReply::Pong { id: None };
+        assert_eq!(
+            serde_json::to_string(&reply).unwrap(),
+            r#"{"type":"pong","id":null}"#
+        );
+    }
+
+    #[test]
+    fn test_render_book_bridges_skipped_deltas() {
+        let offer = |price: i64, size: i64| Offer {
+            price: Decimal::from(price),
+            size: Decimal::from(size),
+        };
+        let mut book = OrderBookState::construct_from(
+            vec![offer(101, 1)],
+            vec![offer(99, 1)],
+            3,
+            Market::from_str("ETH-USD").unwrap(),
+        );
+        let first = BookUpdate::snapshot(book.clone());
+        let changes = book.update_with(vec![offer(101, 0)], vec![], 4).unwrap();
+        let skipped = BookUpdate::delta(book.clone(), 3, changes);
+        let changes = book.update_with(vec![], vec![offer(98, 2)], 5).unwrap();
+        let latest = BookUpdate::delta(book, 4, changes);
+
+        let options = SubscriptionOptions {
+            mode: BookMode::Delta,
+            ..Default::default()
+        };
+        assert_eq!(render_book(&options, &first, None), first.snapshot_json());
+        assert_eq!(
+            render_book(&options, &skipped, Some(&first)),
+            skipped.delta_json().unwrap()
+        );
+        assert_eq!(
+            render_book(&options, &latest, Some(&first)),
+            r#"{"type":"delta","market":"ETH-USD","seq":5,"prev_seq":3,"asks":[["101","0"]],"bids":[["98","2"]]}"#
+        );
+    }
+
+    #[test]
+    fn test_resume_point() {
+        let book = OrderBookState::construct_from(
+            vec![],
+            vec![],
+            12,
+            Market::from_str("ETH-USD").unwrap(),
+        );
+        let update = BookUpdate::snapshot(book).on_connection("9a75aff4".into());
+        let event_id = ResumePoint::event_id(&update);
+        assert_eq!(event_id, "ETH-USD:9a75aff4:12");
+
+        let resume = ResumePoint::from_str(&event_id).unwrap();
+        assert!(resume.covers(&update));
+        let resume = ResumePoint::from_str("ETH-USD:9a75aff4:11").unwrap();
+        assert!(!resume.covers(&update));
+        // message ids of another connection say nothing
+        let resume = ResumePoint::from_str("ETH-USD:5c1e0b7d:40").unwrap();
+        assert!(!resume.covers(&update));
+        // nor do those of another market, whose last update may come later
+        let resume = ResumePoint::from_str("BTC-USD:9a75aff4:40").unwrap();
+        assert!(!resume.covers(&update));
+
+        assert!(ResumePoint::from_str("12").is_err());
+        assert!(ResumePoint::from_str("9a75aff4:12").is_err());
+        assert!(ResumePoint::from_str("ETH-USD:9a75aff4:x").is_err());
+    }
+}
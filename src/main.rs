@@ -1,19 +1,37 @@
-// use anyhow::Context;
-// use serde::Deserialize;
-// use v4_manager::StreamOrderBook;
-
-use anyhow::Context;
-use futures_util::StreamExt;
+use clap::Parser;
+use config::{Config, CrossedBookPolicy, Network};
+use core_types::BookView;
+use downstream::{
+    handle_socket, validate_subscription, BookMode, Channel, SubscriptionKey, SubscriptionOptions,
+};
+use manager::UpstreamManager;
+use markets::MarketRegistry;
+use recorder::{Recorder, RecordingOptions};
+use replay::ReplayOptions;
+use rust_decimal::Decimal;
 use serde::Deserialize;
-use upstream::OrderBookStream;
-use upstream_types::Market;
+use upstream_types::{CandleResolution, Market, SubaccountId};
 
+mod analytics;
+mod candles;
+mod config;
 mod core_types;
+mod downstream;
+mod manager;
+mod market_state;
+mod markets;
+mod metrics;
+mod recorder;
+mod replay;
+mod rest;
+mod sse;
+mod subaccounts;
+mod trades;
 mod upstream;
 mod upstream_types;
 
 use axum::{
-    extract::ws::{Message, WebSocket, WebSocketUpgrade},
+    extract::{ws::WebSocketUpgrade, State},
     http::StatusCode,
     response::Response,
     routing::get,
@@ -23,70 +41,158 @@ use axum_extra::extract::Query;
 
 #[derive(Deserialize, Debug)]
 struct WSParams {
-    #[serde(rename = "market")]
+    /// Markets to start with, more can be subscribed to over the socket
+    #[serde(rename = "market", default)]
     markets: Vec<Market>,
+    #[serde(default)]
+    channel: Channel,
+    #[serde(default)]
+    mode: BookMode,
+    depth: Option<usize>,
+    group: Option<Decimal>,
+    max_rate: Option<f64>,
+    depth_bps: Option<Decimal>,
+    fill_size: Option<Decimal>,
+    resolution: Option<CandleResolution>,
+    subaccount: Option<SubaccountId>,
 }
 
-async fn handler(ws: WebSocketUpgrade, Query(params): Query<WSParams>) -> Response {
-    if params.markets.is_empty() {
-        return Response::builder()
-            .status(StatusCode::BAD_REQUEST)
-            .body("No markets provided".into())
-            .unwrap();
-    }
-    ws.on_upgrade(move |websocket| handle_socket(websocket, params.markets))
-    // ws.on_upgrade(nofusshandlesocket)
+/// Everything the handlers of one indexer network need.
+#[derive(Clone)]
+struct AppState {
+    manager: UpstreamManager,
+    registry: MarketRegistry,
+    /// Whether the subaccount and ladder channels are served.
+    subaccounts: bool,
 }
 
-// async fn nofusshandlesocket(mut socket: WebSocket) {
-//     socket
-//         .send(Message::Text("hi".to_string()))
-//         .await
-//         .expect("send");
-//     socket.close().await.unwrap();
-// }
+impl WSParams {
+    /// The initial subscriptions and their options, if they are fine to make.
+    fn validate(
+        &self,
+        state: &AppState,
+    ) -> Result<(Vec<SubscriptionKey>, SubscriptionOptions), String> {
+        let options = SubscriptionOptions {
+            mode: self.mode,
+            view: BookView {
+                depth: self.depth,
+                group: self.group,
+            },
+            max_rate: self.max_rate,
+            depth_bps: self.depth_bps,
+            fill_size: self.fill_size,
+            resolution: self.resolution,
+            subaccount: self.subaccount.clone(),
+        };
+        let keys = validate_subscription(state, self.channel, &self.markets, &options)?;
+        Ok((keys, options))
+    }
+}
 
-async fn handle_socket(mut socket: WebSocket, markets: Vec<Market>) {
-    let mut stream = OrderBookStream::subscribe(&markets)
-        .await
-        .context("subscribing to markets in handle_socket")
-        .unwrap();
+async fn handler(
+    ws: WebSocketUpgrade,
+    State(state): State<AppState>,
+    Query(params): Query<WSParams>,
+) -> Response {
+    let (keys, options) = match params.validate(&state) {
+        Ok(validated) => validated,
+        Err(e) => {
+            return Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(e.into())
+                .unwrap()
+        }
+    };
+    ws.on_upgrade(move |websocket| handle_socket(websocket, state, keys, options))
+}
 
-    while let Ok(orderbook_json) = stream
-        .next()
-        .await
-        .context("stream should be unending")
-        .unwrap()
-    {
-        let send_result = socket.send(Message::Text(orderbook_json)).await;
-        if send_result.is_err() {
-            eprintln!("User disconnected");
-            return;
+async fn network_router(
+    network: &Network,
+    crossed_book_policy: CrossedBookPolicy,
+    recording: Option<&RecordingOptions>,
+    replay: Option<&ReplayOptions>,
+    subaccounts: bool,
+) -> anyhow::Result<Router> {
+    let state = match replay {
+        Some(replay) => {
+            let registry = MarketRegistry::default();
+            let (upstream, stream) =
+                replay::spawn(replay, &network.name, crossed_book_policy, registry.clone())?;
+            AppState {
+                manager: UpstreamManager::with_upstream(&network.name, upstream, stream),
+                registry,
+                subaccounts,
+            }
         }
-    }
+        None => {
+            let recorder = recording.map(|recording| Recorder::start(recording, &network.name));
+            AppState {
+                manager: UpstreamManager::start(network, crossed_book_policy, recorder),
+                registry: MarketRegistry::start(&network.rest_url()).await,
+                subaccounts,
+            }
+        }
+    };
+    Ok(Router::new()
+        .route("/", get(handler))
+        .route("/sse", get(sse::handler))
+        .route("/orderbook/:market", get(rest::orderbook))
+        .route("/markets", get(rest::markets))
+        .with_state(state))
 }
 
 #[tokio::main]
 async fn main() {
-    // build our application with a single route
-    let app = Router::new().route("/", get(handler));
+    let config = Config::parse();
+    if let Err(e) = config.validate() {
+        eprintln!("Invalid configuration: {}", e);
+        std::process::exit(2);
+    }
+
+    // every network gets its own upstream, the first one is also served at the root
+    let recording = config.recording();
+    if let Some(recording) = &recording {
+        eprintln!("Recording upstream frames to {}", recording.dir.display());
+    }
+    let replay = config.replay();
+    let mut app = Router::new().route("/metrics", get(metrics::handler));
+    for (i, network) in config.networks.iter().enumerate() {
+        let router = match network_router(
+            network,
+            config.crossed_book_policy,
+            recording.as_ref(),
+            replay.as_ref(),
+            config.subaccounts,
+        )
+        .await
+        {
+            Ok(router) => router,
+            Err(e) => {
+                eprintln!("Cannot serve {}: {:#}", network.name, e);
+                std::process::exit(2);
+            }
+        };
+        if i == 0 {
+            app = app.merge(router.clone());
+        }
+        app = app.nest(&format!("/{}", network.name), router);
+    }
 
-    let port = std::env::var("PORT").unwrap_or_else(|_| String::from("80"));
-    let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", port))
+    let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", config.port))
         .await
         .unwrap();
-    eprintln!(
-        "Hit the websocket connection like ws://127.0.0.1:{}/?market=ETH-USD&market=BTC-USD",
-        port
-    );
+    for network in config.networks.iter() {
+        if replay.is_some() {
+            eprintln!(
+                "Hit the replay websocket connection like ws://127.0.0.1:{}/{}?market=ETH-USD",
+                config.port, network.name
+            );
+            continue;
+        }
+        eprintln!(
+            "Hit the {} websocket connection like ws://127.0.0.1:{}/{}?market=ETH-USD&market=BTC-USD",
+            network.ws_url, config.port, network.name
+        );
+    }
     axum::serve(listener, app).await.unwrap();
 }
-
-// #[tokio::main]
-// async fn main() -> anyhow::Result<()> {
-//     let mut stream = OrderBookStream::subscribe(&[Market::EthUsd, Market::BtcUsd]).await?;
-//     while let Ok(orderbook_json) = stream.next().await.context("unending stream")? {
-//         println!("{}", &orderbook_json);
-//     }
-//     Ok(())
-// }
@@ -0,0 +1,370 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use futures_util::StreamExt;
+use tokio::sync::broadcast;
+
+use crate::{
+    config::{CrossedBookPolicy, Network},
+    core_types::{BookUpdate, MarketEvent},
+    metrics::METRICS,
+    recorder::Recorder,
+    upstream::{OrderBookStream, UpstreamError, UpstreamHandle},
+    upstream_types::{Market, Topic},
+};
+
+const MARKET_BUFFER: usize = 16;
+
+/// Tells entries of the same topic apart, once a dropped topic is subscribed again.
+static NEXT_ENTRY_ID: AtomicUsize = AtomicUsize::new(0);
+
+struct MarketEntry {
+    id: usize,
+    subscribers: usize,
+    updates: broadcast::Sender<MarketEvent>,
+    /// The current book, recent trades or candles, `None` while not known or out of sync.
+    latest: Option<MarketEvent>,
+}
+
+type Markets = Arc<Mutex<BTreeMap<Topic, MarketEntry>>>;
+
+/// What is known of a followed market at a glance.
+pub struct MarketSummary {
+    pub market: Market,
+    pub subscribers: usize,
+    /// The current book, `None` while it is being fetched.
+    pub latest: Option<Arc<BookUpdate>>,
+}
+
+/// Process-wide owner of the single upstream connection.
+///
+/// Every topic is subscribed upstream once, on first interest, and its
+/// updates are broadcast to all the clients following it. The topic is
+/// unsubscribed again when its last client leaves.
+#[derive(Clone)]
+pub struct UpstreamManager {
+    network: Arc<str>,
+    markets: Markets,
+    upstream: UpstreamHandle,
+}
+
+impl UpstreamManager {
+    pub fn start(
+        network: &Network,
+        crossed_book_policy: CrossedBookPolicy,
+        recorder: Option<Recorder>,
+    ) -> Self {
+        let (upstream, stream) = OrderBookStream::spawn(network, crossed_book_policy, recorder);
+        Self::with_upstream(&network.name, upstream, stream)
+    }
+
+    /// Serves the books of any upstream, e.g. a replay instead of the indexer.
+    pub fn with_upstream(network: &str, upstream: UpstreamHandle, stream: OrderBookStream) -> Self {
+        let markets = Markets::default();
+        let network: Arc<str> = network.into();
+        tokio::spawn(dispatch(stream, markets.clone(), network.clone()));
+        Self {
+            network,
+            markets,
+            upstream,
+        }
+    }
+
+    /// The name of the network the upstream belongs to.
+    pub fn network(&self) -> &str {
+        &self.network
+    }
+
+    /// Every market whose book someone follows, in ticker order.
+    pub fn summaries(&self) -> Vec<MarketSummary> {
+        let markets = self.markets.lock().unwrap();
+        markets
+            .iter()
+            .filter_map(|(topic, entry)| match topic {
+                Topic::Orderbook(market) => Some(MarketSummary {
+                    market: market.clone(),
+                    subscribers: entry.subscribers,
+                    latest: entry.latest.as_ref().and_then(book_of),
+                }),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn subscribe(&self, topic: &Topic) -> MarketSubscription {
+        let mut markets = self.markets.lock().unwrap();
+        let entry = markets.entry(topic.clone()).or_insert_with(|| {
+            self.upstream.subscribe(topic.clone());
+            MarketEntry {
+                id: NEXT_ENTRY_ID.fetch_add(1, Ordering::Relaxed),
+                subscribers: 0,
+                updates: broadcast::channel(MARKET_BUFFER).0,
+                latest: None,
+            }
+        });
+        entry.subscribers += 1;
+        METRICS
+            .subscribers
+            .with_label_values(&subscriber_labels(&self.network, topic))
+            .set(entry.subscribers as i64);
+        MarketSubscription {
+            topic: topic.clone(),
+            entry_id: entry.id,
+            manager: self.clone(),
+            updates: entry.updates.subscribe(),
+            latest: entry.latest.clone(),
+        }
+    }
+
+    fn release(&self, topic: &Topic, entry_id: usize) {
+        let mut markets = self.markets.lock().unwrap();
+        let Some(entry) = markets.get_mut(topic) else {
+            return;
+        };
+        if entry.id != entry_id {
+            // the topic was dropped by the indexer since, nothing left to release
+            return;
+        }
+        entry.subscribers -= 1;
+        if entry.subscribers == 0 {
+            let _ = markets.remove(topic);
+            self.upstream.unsubscribe(topic.clone());
+            forget_series(&self.network, topic);
+        } else {
+            METRICS
+                .subscribers
+                .with_label_values(&subscriber_labels(&self.network, topic))
+                .set(entry.subscribers as i64);
+        }
+    }
+}
+
+fn subscriber_labels<'a>(network: &'a str, topic: &'a Topic) -> [&'a str; 3] {
+    [
+        network,
+        topic.market().map_or("", Market::as_str),
+        topic.channel().as_str(),
+    ]
+}
+
+fn book_of(event: &MarketEvent) -> Option<Arc<BookUpdate>> {
+    match event {
+        MarketEvent::Book(update) => Some(update.clone()),
+        _ => None,
+    }
+}
+
+async fn dispatch(mut stream: OrderBookStream, markets: Markets, network: Arc<str>) {
+    while let Some(event) = stream.next().await {
+        let event = match event {
+            Ok(event) => event,
+            Err(UpstreamError::Rejected { topic, reason }) => {
+                drop_market(&markets, &network, topic, reason.into());
+                continue;
+            }
+            Err(e) => {
+                mark_resyncing(&markets, &e);
+                continue;
+            }
+        };
+        let mut markets = markets.lock().unwrap();
+        let Some(topic) = event.topic() else {
+            continue;
+        };
+        if let Some(entry) = markets.get_mut(&topic) {
+            entry.latest = match &event {
+                MarketEvent::Book(update) => {
+                    let market = update.book.market.as_str();
+                    for (side, levels) in [("asks", &update.book.asks), ("bids", &update.book.bids)]
+                    {
+                        METRICS
+                            .book_depth
+                            .with_label_values(&[&network, market, side])
+                            .set(levels.len() as i64);
+                    }
+                    Some(event.clone())
+                }
+                MarketEvent::Trades(_)
+                | MarketEvent::Candles(_)
+                | MarketEvent::Markets(_)
+                | MarketEvent::Subaccount(_) => Some(event.clone()),
+                MarketEvent::Resyncing { .. } | MarketEvent::Failed { .. } => None,
+            };
+            let _ = entry.updates.send(event);
+        }
+    }
+    // closes every subscription, so that clients do not wait for nothing
+    markets.lock().unwrap().clear();
+}
+
+/// Forgets the series of a topic nobody follows anymore.
+fn forget_series(network: &str, topic: &Topic) {
+    let _ = METRICS
+        .subscribers
+        .remove_label_values(&subscriber_labels(network, topic));
+    if let Topic::Orderbook(market) = topic {
+        for side in ["asks", "bids"] {
+            let _ = METRICS
+                .book_depth
+                .remove_label_values(&[network, market.as_str(), side]);
+        }
+    }
+}
+
+/// Ends every subscription of a topic the indexer no longer serves. The
+/// next client to ask for it starts over with a fresh upstream subscription.
+fn drop_market(markets: &Markets, network: &str, topic: Topic, reason: Arc<str>) {
+    let mut markets = markets.lock().unwrap();
+    let Some(entry) = markets.remove(&topic) else {
+        return;
+    };
+    forget_series(network, &topic);
+    let _ = entry.updates.send(MarketEvent::Failed {
+        market: topic.market().cloned(),
+        reason,
+    });
+}
+
+/// Tells the clients of the topics affected by `error` that they are being refetched.
+fn mark_resyncing(markets: &Markets, error: &UpstreamError) {
+    let reason: Arc<str> = error.to_string().into();
+    let mut markets = markets.lock().unwrap();
+    for (topic, entry) in markets.iter_mut() {
+        let affected = match error.topic() {
+            Some(desynced) => desynced == topic,
+            // connection-wide errors repeat while reconnecting, only report the first
+            None => entry.latest.is_some(),
+        };
+        if affected {
+            entry.latest = None;
+            let _ = entry.updates.send(MarketEvent::Resyncing {
+                market: topic.market().cloned(),
+                reason: reason.clone(),
+            });
+        }
+    }
+}
+
+/// One client's interest in a topic, released when dropped.
+pub struct MarketSubscription {
+    topic: Topic,
+    entry_id: usize,
+    manager: UpstreamManager,
+    updates: broadcast::Receiver<MarketEvent>,
+    latest: Option<MarketEvent>,
+}
+
+impl MarketSubscription {
+    /// Waits for the next event, starting with the latest known data.
+    ///
+    /// A lagging subscriber skips the events it missed, every update carries
+    /// the whole book so the gap can be detected and bridged with a snapshot.
+    pub async fn recv(&mut self) -> Option<MarketEvent> {
+        if let Some(latest) = self.latest.take() {
+            return Some(latest);
+        }
+        loop {
+            match self.updates.recv().await {
+                Ok(event) => return Some(event),
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+
+    pub fn topic(&self) -> &Topic {
+        &self.topic
+    }
+
+    /// The most recent book of the market, but only if this subscription
+    /// has nothing else queued, so that it would be next in line anyway.
+    pub fn latest_if_caught_up(&self) -> Option<Arc<BookUpdate>> {
+        // updates are only sent with the lock held, so nothing can sneak in between
+        let markets = self.manager.markets.lock().unwrap();
+        if self.latest.is_some() || !self.updates.is_empty() {
+            return None;
+        }
+        let entry = markets.get(&self.topic)?;
+        if entry.id != self.entry_id {
+            return None;
+        }
+        entry.latest.as_ref().and_then(book_of)
+    }
+}
+
+impl Drop for MarketSubscription {
+    fn drop(&mut self) {
+        self.manager.release(&self.topic, self.entry_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use crate::core_types::OrderBookState;
+
+    use super::*;
+
+    fn entry(market: &Market, in_sync: bool) -> MarketEntry {
+        let book = OrderBookState::construct_from(vec![], vec![], 1, market.clone());
+        MarketEntry {
+            id: NEXT_ENTRY_ID.fetch_add(1, Ordering::Relaxed),
+            subscribers: 1,
+            updates: broadcast::channel(MARKET_BUFFER).0,
+            latest: in_sync.then(|| MarketEvent::Book(Arc::new(BookUpdate::snapshot(book)))),
+        }
+    }
+
+    #[test]
+    fn test_mark_resyncing() {
+        let eth = Market::from_str("ETH-USD").unwrap();
+        let btc = Market::from_str("BTC-USD").unwrap();
+        let markets = Markets::default();
+        let mut eth_updates = {
+            let mut markets = markets.lock().unwrap();
+            let _ = markets.insert(Topic::Orderbook(btc.clone()), entry(&btc, false));
+            let eth_entry = entry(&eth, true);
+            let updates = eth_entry.updates.subscribe();
+            let _ = markets.insert(Topic::Orderbook(eth.clone()), eth_entry);
+            updates
+        };
+        let eth_book = Topic::Orderbook(eth.clone());
+        let btc_book = Topic::Orderbook(btc.clone());
+        let mut btc_updates = markets.lock().unwrap()[&btc_book].updates.subscribe();
+
+        // only the book that was in sync hears about a lost connection
+        mark_resyncing(&markets, &UpstreamError::Closed("gone".into()));
+        assert!(matches!(
+            eth_updates.try_recv(),
+            Ok(MarketEvent::Resyncing { market, .. }) if market == Some(eth.clone())
+        ));
+        assert!(btc_updates.try_recv().is_err());
+        assert!(markets.lock().unwrap()[&eth_book].latest.is_none());
+
+        let desync = UpstreamError::Desync {
+            topic: btc_book,
+            reason: "gap".into(),
+        };
+        mark_resyncing(&markets, &desync);
+        assert!(btc_updates.try_recv().is_ok());
+        assert!(eth_updates.try_recv().is_err());
+
+        // a market the indexer drops is gone for good, after telling why
+        drop_market(&markets, "test", eth_book.clone(), "delisted".into());
+        assert!(matches!(
+            eth_updates.try_recv(),
+            Ok(MarketEvent::Failed { reason, .. }) if &*reason == "delisted"
+        ));
+        assert!(matches!(
+            eth_updates.try_recv(),
+            Err(broadcast::error::TryRecvError::Closed)
+        ));
+        assert!(!markets.lock().unwrap().contains_key(&eth_book));
+    }
+}
@@ -0,0 +1,220 @@
+use std::collections::BTreeMap;
+
+use anyhow::Context;
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::upstream_types::{
+    Market, MarketStatus, MarketsChannelBatchData, MarketsIncomingMessages, PerpetualMarket,
+    TradingUpdate,
+};
+
+/// What the indexer tells of a market beside its book: how it trades, its
+/// oracle price and funding.
+#[derive(Serialize, Debug, Clone, PartialEq, Default)]
+pub struct MarketState {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<MarketStatus>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub oracle_price: Option<Decimal>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_funding_rate: Option<Decimal>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub open_interest: Option<Decimal>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub volume_24h: Option<Decimal>,
+}
+
+impl MarketState {
+    fn apply(&mut self, update: TradingUpdate) {
+        let TradingUpdate {
+            status,
+            oracle_price,
+            next_funding_rate,
+            open_interest,
+            volume_24h,
+        } = update;
+        self.status = status.or(self.status);
+        self.oracle_price = oracle_price.or(self.oracle_price);
+        self.next_funding_rate = next_funding_rate.or(self.next_funding_rate);
+        self.open_interest = open_interest.or(self.open_interest);
+        self.volume_24h = volume_24h.or(self.volume_24h);
+    }
+}
+
+impl From<PerpetualMarket> for MarketState {
+    fn from(market: PerpetualMarket) -> Self {
+        Self {
+            status: Some(market.status),
+            oracle_price: market.oracle_price,
+            next_funding_rate: market.next_funding_rate,
+            open_interest: market.open_interest,
+            volume_24h: market.volume_24h,
+        }
+    }
+}
+
+/// The state of every market after an upstream message.
+#[derive(Debug)]
+pub struct MarketStatesUpdate {
+    pub markets: BTreeMap<Market, MarketState>,
+}
+
+impl MarketStatesUpdate {
+    pub fn oracle_price(&self, market: &Market) -> Option<Decimal> {
+        self.markets.get(market)?.oracle_price
+    }
+
+    /// Renders the markets whose state changed since `last` for a client,
+    /// all of them as a snapshot if there is no `last`. `None` if nothing
+    /// changed.
+    pub fn json_since(&self, last: Option<&MarketStatesUpdate>) -> Option<String> {
+        let markets: BTreeMap<&Market, &MarketState> = match last {
+            None => self.markets.iter().collect(),
+            Some(last) => self
+                .markets
+                .iter()
+                .filter(|(market, state)| last.markets.get(market) != Some(state))
+                .collect(),
+        };
+        if last.is_some() && markets.is_empty() {
+            return None;
+        }
+        let message = MarketsMessage {
+            snapshot: last.is_none(),
+            markets,
+        };
+        Some(serde_json::to_string(&message).expect("markets should serialize"))
+    }
+}
+
+/// Market states as sent to clients, `{"type":"markets",...}`. A snapshot has
+/// every market, otherwise only those that changed, whole.
+#[derive(Serialize)]
+#[serde(tag = "type", rename = "markets")]
+struct MarketsMessage<'a> {
+    snapshot: bool,
+    markets: BTreeMap<&'a Market, &'a MarketState>,
+}
+
+/// Keeps the state of every market once subscribed to them.
+#[derive(Debug, Default)]
+pub struct MarketStateFolder {
+    markets: Option<BTreeMap<Market, MarketState>>,
+}
+
+impl MarketStateFolder {
+    pub fn reset(&mut self) {
+        self.markets = None;
+    }
+
+    /// The state of the markets, if their subscription has been seen yet.
+    pub fn snapshot(&self) -> Option<MarketStatesUpdate> {
+        Some(MarketStatesUpdate {
+            markets: self.markets.clone()?,
+        })
+    }
+
+    /// Folds the message into the market states, returning them if they changed.
+    pub fn consume(
+        &mut self,
+        msg: MarketsIncomingMessages,
+    ) -> anyhow::Result<Option<MarketStatesUpdate>> {
+        match msg {
+            MarketsIncomingMessages::Subscribed(subscribed) => {
+                let markets = subscribed.contents.markets.into_iter();
+                self.markets = Some(
+                    markets
+                        .map(|(market, state)| (market, state.into()))
+                        .collect(),
+                );
+                Ok(self.snapshot())
+            }
+            MarketsIncomingMessages::ChannelData(data) => self.consume_batch(data.into()),
+            MarketsIncomingMessages::ChannelBatchData(batch) => self.consume_batch(batch),
+            MarketsIncomingMessages::Unsubscribed(_) => {
+                self.reset();
+                Ok(None)
+            }
+        }
+    }
+
+    fn consume_batch(
+        &mut self,
+        batch: MarketsChannelBatchData,
+    ) -> anyhow::Result<Option<MarketStatesUpdate>> {
+        let markets = self
+            .markets
+            .as_mut()
+            .context("The markets have not seen a snapshot yet, got an update")?;
+        let mut changed = false;
+        for contents in batch.contents {
+            // markets listed since subscribing show up here first
+            for (market, update) in contents.trading {
+                let state = markets.entry(market).or_default();
+                let before = state.clone();
+                state.apply(update);
+                changed |= *state != before;
+            }
+            for (market, update) in contents.oracle_prices {
+                let state = markets.entry(market).or_default();
+                changed |= state.oracle_price != Some(update.oracle_price);
+                state.oracle_price = Some(update.oracle_price);
+            }
+        }
+        if !changed {
+            return Ok(None);
+        }
+        Ok(self.snapshot())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use crate::upstream_types::IncomingMessage;
+
+    use super::*;
+
+    fn consume(
+        folder: &mut MarketStateFolder,
+        frame: &str,
+    ) -> anyhow::Result<Option<MarketStatesUpdate>> {
+        let Ok(IncomingMessage::Markets(message)) = IncomingMessage::from_str(frame) else {
+            panic!("expected a markets message");
+        };
+        folder.consume(message)
+    }
+
+    #[test]
+    fn test_fold_markets() {
+        let mut folder = MarketStateFolder::default();
+        let oracle = r#"{"type":"channel_data","connection_id":"c","message_id":2,"channel":"v4_markets","version":"1.0.0","contents":{"oraclePrices":{"ETH-USD":{"oraclePrice":"3051.5","effectiveAt":"2024-05-01T13:30:00.000Z","effectiveAtHeight":"1000","marketId":1}}}}"#;
+        assert!(consume(&mut folder, oracle).is_err());
+
+        let subscribed = r#"{"type":"subscribed","connection_id":"c","message_id":1,"channel":"v4_markets","contents":{"markets":{"BTC-USD":{"ticker":"BTC-USD","status":"ACTIVE","tickSize":"1","stepSize":"0.0001","oraclePrice":"60000","nextFundingRate":"0.00001","openInterest":"500","volume24H":"1000000"},"ETH-USD":{"ticker":"ETH-USD","status":"ACTIVE","tickSize":"0.1","stepSize":"0.001","oraclePrice":"3050","nextFundingRate":"0.00002","openInterest":"9000","volume24H":"2000000"}}}}"#;
+        let first = consume(&mut folder, subscribed).unwrap().unwrap();
+        let eth = Market::from_str("ETH-USD").unwrap();
+        assert_eq!(first.oracle_price(&eth), Decimal::from_str("3050").ok());
+        assert!(first
+            .json_since(None)
+            .unwrap()
+            .starts_with(r#"{"type":"markets","snapshot":true,"markets":{"BTC-USD":{"status":"ACTIVE","oracle_price":"60000","next_funding_rate":"0.00001","#));
+
+        let second = consume(&mut folder, oracle).unwrap().unwrap();
+        assert_eq!(second.oracle_price(&eth), Decimal::from_str("3051.5").ok());
+        assert_eq!(
+            second.json_since(Some(&first)).unwrap(),
+            r#"{"type":"markets","snapshot":false,"markets":{"ETH-USD":{"status":"ACTIVE","oracle_price":"3051.5","next_funding_rate":"0.00002","open_interest":"9000","volume_24h":"2000000"}}}"#
+        );
+        assert!(consume(&mut folder, oracle).unwrap().is_none());
+
+        let trading = r#"{"type":"channel_batch_data","connection_id":"c","message_id":3,"channel":"v4_markets","version":"1.0.0","contents":[{"trading":{"BTC-USD":{"nextFundingRate":"0.00003"}}},{"trading":{"BTC-USD":{"openInterest":"501"}}}]}"#;
+        let third = consume(&mut folder, trading).unwrap().unwrap();
+        let btc = &third.markets[&Market::from_str("BTC-USD").unwrap()];
+        assert_eq!(btc.next_funding_rate, Decimal::from_str("0.00003").ok());
+        assert_eq!(btc.open_interest, Decimal::from_str("501").ok());
+        assert_eq!(btc.volume_24h, Decimal::from_str("1000000").ok());
+    }
+}
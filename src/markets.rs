@@ -0,0 +1,183 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use anyhow::Context;
+
+use crate::upstream_types::{Market, MarketStatus, PerpetualMarket, PerpetualMarkets};
+
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, PartialEq)]
+pub enum MarketError {
+    NotLoaded,
+    Unknown(Market),
+    Inactive(Market, MarketStatus),
+}
+
+impl std::fmt::Display for MarketError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MarketError::NotLoaded => write!(f, "The list of markets is not loaded yet"),
+            MarketError::Unknown(market) => write!(f, "Unknown market: {}", market),
+            MarketError::Inactive(market, status) => {
+                write!(f, "Market {} is not active ({:?})", market, status)
+            }
+        }
+    }
+}
+
+impl std::error::Error for MarketError {}
+
+/// The markets listed by the indexer, refreshed in the background.
+#[derive(Clone, Default)]
+pub struct MarketRegistry {
+    markets: Arc<RwLock<BTreeMap<Market, PerpetualMarket>>>,
+}
+
+impl MarketRegistry {
+    /// Loads the markets from the indexer REST API at `rest_url`, and keeps
+    /// them up to date. Startup does not fail if the indexer is unreachable,
+    /// the registry keeps retrying and rejects every market until then.
+    pub async fn start(rest_url: &str) -> Self {
+        let registry = Self::default();
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .expect("the http client should build");
+        let url = format!("{}/perpetualMarkets", rest_url);
+        let loaded = registry.refresh(&client, &url).await;
+        tokio::spawn(registry.clone().keep_refreshed(client, url, loaded));
+        registry
+    }
+
+    async fn keep_refreshed(self, client: reqwest::Client, url: String, mut loaded: bool) {
+        loop {
+            let interval = if loaded {
+                REFRESH_INTERVAL
+            } else {
+                RETRY_INTERVAL
+            };
+            tokio::time::sleep(interval).await;
+            loaded = self.refresh(&client, &url).await || loaded;
+        }
+    }
+
+    async fn refresh(&self, client: &reqwest::Client, url: &str) -> bool {
+        match fetch_markets(client, url).await {
+            Ok(markets) => {
+                self.replace(markets);
+                true
+            }
+            Err(e) => {
+                eprintln!("Refreshing markets failed: {:#}", e);
+                false
+            }
+        }
+    }
+
+    pub fn replace(&self, markets: BTreeMap<Market, PerpetualMarket>) {
+        *self.markets.write().unwrap() = markets;
+    }
+
+    /// Adds or updates a single market.
+    pub fn insert(&self, market: PerpetualMarket) {
+        let _ = self
+            .markets
+            .write()
+            .unwrap()
+            .insert(market.ticker.clone(), market);
+    }
+
+    pub fn get(&self, market: &Market) -> Option<PerpetualMarket> {
+        self.markets.read().unwrap().get(market).cloned()
+    }
+
+    /// Looks up the market, failing unless it is listed and active.
+    pub fn check(&self, market: &Market) -> Result<PerpetualMarket, MarketError> {
+        let markets = self.markets.read().unwrap();
+        if markets.is_empty() {
+            return Err(MarketError::NotLoaded);
+        }
+        let info = markets
+            .get(market)
+            .ok_or_else(|| MarketError::Unknown(market.clone()))?;
+        if info.status != MarketStatus::Active {
+            return Err(MarketError::Inactive(market.clone(), info.status));
+        }
+        Ok(info.clone())
+    }
+}
+
+async fn fetch_markets(
+    client: &reqwest::Client,
+    url: &str,
+) -> anyhow::Result<BTreeMap<Market, PerpetualMarket>> {
+    let response: PerpetualMarkets = client
+        .get(url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .with_context(|| format!("Requesting {}", url))?
+        .json()
+        .await
+        .context("Parsing perpetual markets")?;
+    Ok(response.markets)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use rust_decimal::Decimal;
+
+    use super::*;
+
+    fn perpetual_market(ticker: &str, status: MarketStatus) -> PerpetualMarket {
+        PerpetualMarket {
+            ticker: Market::from_str(ticker).unwrap(),
+            status,
+            tick_size: Some(Decimal::ONE),
+            step_size: Some(Decimal::ONE),
+            oracle_price: None,
+            initial_margin_fraction: None,
+            maintenance_margin_fraction: None,
+            next_funding_rate: None,
+            open_interest: None,
+            volume_24h: None,
+        }
+    }
+
+    #[test]
+    fn test_check_market() {
+        let registry = MarketRegistry::default();
+        let eth = Market::from_str("ETH-USD").unwrap();
+        assert_eq!(registry.check(&eth), Err(MarketError::NotLoaded));
+
+        registry.replace(
+            [
+                perpetual_market("ETH-USD", MarketStatus::Active),
+                perpetual_market("LUNA-USD", MarketStatus::FinalSettlement),
+            ]
+            .into_iter()
+            .map(|m| (m.ticker.clone(), m))
+            .collect(),
+        );
+        assert!(registry.check(&eth).is_ok());
+
+        let luna = Market::from_str("LUNA-USD").unwrap();
+        assert_eq!(
+            registry.check(&luna),
+            Err(MarketError::Inactive(
+                luna.clone(),
+                MarketStatus::FinalSettlement
+            ))
+        );
+        let btc = Market::from_str("BTC-USD").unwrap();
+        assert_eq!(registry.check(&btc), Err(MarketError::Unknown(btc.clone())));
+    }
+}
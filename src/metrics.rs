@@ -0,0 +1,213 @@
+use std::sync::LazyLock;
+
+use axum::{http::header, response::IntoResponse};
+use prometheus::{
+    core::Collector, Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+
+/// Process-wide metrics, labelled by network where they concern an upstream.
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+/// Buckets of the upstream to client latency, from 100µs to 1s.
+const LATENCY_BUCKETS: &[f64] = &[
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0,
+];
+
+pub struct Metrics {
+    registry: Registry,
+    /// Upstream websocket connections established, by network.
+    pub upstream_connections: IntCounterVec,
+    /// Upstream sessions that ended and got retried, by network.
+    pub upstream_reconnects: IntCounterVec,
+    /// Whether the upstream of a network is currently connected.
+    pub upstream_connected: IntGaugeVec,
+    /// Messages received for a subscription, by network and market.
+    pub upstream_messages: IntCounterVec,
+    /// Upstream frames that could not be parsed, by network.
+    pub parse_failures: IntCounterVec,
+    /// Deltas rejected by the book for not advancing its epoch.
+    pub epoch_errors: IntCounterVec,
+    /// Books found crossed or locked, by market and kind.
+    pub crossed_books: IntCounterVec,
+    /// Websocket clients currently connected, by network.
+    pub clients: IntGaugeVec,
+    /// Clients currently following a market, by channel.
+    pub subscribers: IntGaugeVec,
+    /// Bytes of text sent to clients, by network.
+    pub bytes_sent: IntCounterVec,
+    /// Clients dropped for falling too far behind, by network.
+    pub slow_client_disconnects: IntCounterVec,
+    /// Upstream frames that could not be written to the recording, by network.
+    pub recording_errors: IntCounterVec,
+    /// Price levels of the current book, by market and side.
+    pub book_depth: IntGaugeVec,
+    /// Time from folding an upstream message to sending it to a client.
+    pub update_latency: HistogramVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new();
+        let metrics = Self {
+            upstream_connections: IntCounterVec::new(
+                Opts::new(
+                    "chester_upstream_connections_total",
+                    "Upstream websocket connections established",
+                ),
+                &["network"],
+            )
+            .unwrap(),
+            upstream_reconnects: IntCounterVec::new(
+                Opts::new(
+                    "chester_upstream_reconnects_total",
+                    "Upstream connection attempts that failed or ended and are retried",
+                ),
+                &["network"],
+            )
+            .unwrap(),
+            upstream_connected: IntGaugeVec::new(
+                Opts::new(
+                    "chester_upstream_connected",
+                    "Whether the upstream websocket is connected",
+                ),
+                &["network"],
+            )
+            .unwrap(),
+            upstream_messages: IntCounterVec::new(
+                Opts::new(
+                    "chester_upstream_messages_total",
+                    "Subscription messages received from upstream",
+                ),
+                &["network", "market"],
+            )
+            .unwrap(),
+            parse_failures: IntCounterVec::new(
+                Opts::new(
+                    "chester_upstream_parse_failures_total",
+                    "Upstream messages that could not be parsed",
+                ),
+                &["network"],
+            )
+            .unwrap(),
+            epoch_errors: IntCounterVec::new(
+                Opts::new(
+                    "chester_epoch_errors_total",
+                    "Deltas that did not advance the epoch of their book",
+                ),
+                &["network", "market"],
+            )
+            .unwrap(),
+            crossed_books: IntCounterVec::new(
+                Opts::new(
+                    "chester_crossed_books_total",
+                    "Times a book went crossed or locked, with the best bid at or above the best ask",
+                ),
+                &["network", "market", "kind"],
+            )
+            .unwrap(),
+            clients: IntGaugeVec::new(
+                Opts::new("chester_clients", "Connected websocket clients"),
+                &["network"],
+            )
+            .unwrap(),
+            subscribers: IntGaugeVec::new(
+                Opts::new("chester_subscribers", "Clients subscribed to a market"),
+                &["network", "market", "channel"],
+            )
+            .unwrap(),
+            bytes_sent: IntCounterVec::new(
+                Opts::new("chester_bytes_sent_total", "Bytes of messages sent to clients"),
+                &["network"],
+            )
+            .unwrap(),
+            slow_client_disconnects: IntCounterVec::new(
+                Opts::new(
+                    "chester_slow_client_disconnects_total",
+                    "Clients disconnected for falling too far behind",
+                ),
+                &["network"],
+            )
+            .unwrap(),
+            recording_errors: IntCounterVec::new(
+                Opts::new(
+                    "chester_recording_errors_total",
+                    "Failures to write upstream frames to the recording",
+                ),
+                &["network"],
+            )
+            .unwrap(),
+            book_depth: IntGaugeVec::new(
+                Opts::new("chester_book_depth", "Price levels on one side of a book"),
+                &["network", "market", "side"],
+            )
+            .unwrap(),
+            update_latency: HistogramVec::new(
+                HistogramOpts::new(
+                    "chester_update_latency_seconds",
+                    "Time from receiving a book update upstream to sending it to a client",
+                )
+                .buckets(LATENCY_BUCKETS.to_vec()),
+                &["network"],
+            )
+            .unwrap(),
+            registry,
+        };
+        let collectors: [Box<dyn Collector>; 14] = [
+            Box::new(metrics.upstream_connections.clone()),
+            Box::new(metrics.upstream_reconnects.clone()),
+            Box::new(metrics.upstream_connected.clone()),
+            Box::new(metrics.upstream_messages.clone()),
+            Box::new(metrics.parse_failures.clone()),
+            Box::new(metrics.epoch_errors.clone()),
+            Box::new(metrics.crossed_books.clone()),
+            Box::new(metrics.clients.clone()),
+            Box::new(metrics.subscribers.clone()),
+            Box::new(metrics.bytes_sent.clone()),
+            Box::new(metrics.slow_client_disconnects.clone()),
+            Box::new(metrics.recording_errors.clone()),
+            Box::new(metrics.book_depth.clone()),
+            Box::new(metrics.update_latency.clone()),
+        ];
+        for collector in collectors {
+            metrics
+                .registry
+                .register(collector)
+                .expect("every metric should register once");
+        }
+        metrics
+    }
+
+    /// Renders every metric in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .expect("metrics should encode");
+        String::from_utf8(buffer).expect("the text format is utf-8")
+    }
+}
+
+pub async fn handler() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)],
+        METRICS.render(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_metrics() {
+        METRICS
+            .subscribers
+            .with_label_values(&["test", "ETH-USD", "v4_orderbook"])
+            .set(2);
+        let rendered = METRICS.render();
+        assert!(rendered.contains(
+            r#"chester_subscribers{channel="v4_orderbook",market="ETH-USD",network="test"} 2"#
+        ));
+    }
+}
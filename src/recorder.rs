@@ -0,0 +1,295 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::mpsc,
+    time::{Duration, SystemTime},
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::metrics::METRICS;
+
+/// How often buffered frames are flushed to disk when the upstream is quiet.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// One line of a recording.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct RecordedFrame {
+    /// When the frame was read off the socket, in microseconds since the unix epoch.
+    pub received_at_us: u64,
+    /// The text of the frame exactly as the indexer sent it.
+    pub frame: String,
+}
+
+/// Where and how the raw upstream traffic gets recorded.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecordingOptions {
+    pub dir: PathBuf,
+    pub zstd: bool,
+}
+
+/// Records raw upstream frames of one network. Frames are written by a
+/// thread of their own, so that a slow disk never holds up the upstream.
+#[derive(Clone, Debug)]
+pub struct Recorder {
+    frames: mpsc::Sender<RecordedFrame>,
+}
+
+impl Recorder {
+    pub fn start(options: &RecordingOptions, network: &str) -> Self {
+        let (frames, rx) = mpsc::channel();
+        let writer = RecordingWriter::new(options, network);
+        let _ = std::thread::Builder::new()
+            .name(format!("recorder-{}", network))
+            .spawn(move || writer.run(rx))
+            .expect("the recorder thread should spawn");
+        Self { frames }
+    }
+
+    pub fn record(&self, frame: &str) {
+        let _ = self.frames.send(RecordedFrame {
+            received_at_us: unix_micros(SystemTime::now()),
+            frame: frame.to_string(),
+        });
+    }
+}
+
+fn unix_micros(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map(|since| since.as_micros() as u64)
+        .unwrap_or_default()
+}
+
+/// Writes frames to one file per network and hour, named after the first
+/// frame in it, like `mainnet-2024-05-01T13-59-59.000000.ndjson.zst`. A
+/// restarted recorder starts a file of its own rather than appending, as a
+/// killed one can leave its last zstd frame unfinished.
+struct RecordingWriter {
+    dir: PathBuf,
+    network: String,
+    zstd: bool,
+    /// The hour since the unix epoch of the open file.
+    hour: Option<u64>,
+    out: Option<Box<dyn Write + Send>>,
+}
+
+impl RecordingWriter {
+    fn new(options: &RecordingOptions, network: &str) -> Self {
+        Self {
+            dir: options.dir.clone(),
+            network: network.to_string(),
+            zstd: options.zstd,
+            hour: None,
+            out: None,
+        }
+    }
+
+    fn run(mut self, frames: mpsc::Receiver<RecordedFrame>) {
+        loop {
+            let result = match frames.recv_timeout(FLUSH_INTERVAL) {
+                Ok(frame) => self.write(&frame),
+                Err(mpsc::RecvTimeoutError::Timeout) => self.flush(),
+                Err(mpsc::RecvTimeoutError::Disconnected) => return,
+            };
+            if let Err(e) = result {
+                eprintln!("Recording {} failed: {}", self.network, e);
+                METRICS
+                    .recording_errors
+                    .with_label_values(&[&self.network])
+                    .inc();
+                // the next frame tries a fresh file
+                self.hour = None;
+                self.out = None;
+            }
+        }
+    }
+
+    fn path(&self, received_at_us: u64) -> PathBuf {
+        let start = DateTime::<Utc>::UNIX_EPOCH + Duration::from_micros(received_at_us);
+        let extension = if self.zstd { "ndjson.zst" } else { "ndjson" };
+        self.dir.join(format!(
+            "{}-{}.{}",
+            self.network,
+            start.format("%Y-%m-%dT%H-%M-%S%.6f"),
+            extension
+        ))
+    }
+
+    fn write(&mut self, frame: &RecordedFrame) -> io::Result<()> {
+        let hour = frame.received_at_us / 3_600_000_000;
+        if self.hour != Some(hour) {
+            // finishes the previous file before starting the next one
+            self.out = None;
+            self.out = Some(create(&self.path(frame.received_at_us), self.zstd)?);
+            self.hour = Some(hour);
+        }
+        let out = self.out.as_mut().expect("a file was just opened");
+        serde_json::to_writer(&mut *out, frame)?;
+        out.write_all(b"\n")
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.out.as_mut() {
+            Some(out) => out.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Starts a new recording, never touching one that is already there.
+fn create(path: &Path, zstd: bool) -> io::Result<Box<dyn Write + Send>> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let file = BufWriter::new(OpenOptions::new().write(true).create_new(true).open(path)?);
+    if zstd {
+        Ok(Box::new(zstd::Encoder::new(file, 0)?.auto_finish()))
+    } else {
+        Ok(Box::new(file))
+    }
+}
+
+/// Reads a recording back, decompressing it if its name ends in `.zst`.
+pub fn read_recording(path: &Path) -> io::Result<Box<dyn io::BufRead + Send>> {
+    let file = File::open(path)?;
+    if path.extension().is_some_and(|extension| extension == "zst") {
+        Ok(Box::new(io::BufReader::new(zstd::Decoder::new(file)?)))
+    } else {
+        Ok(Box::new(io::BufReader::new(file)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::BufRead;
+
+    use super::*;
+
+    fn temp_dir(name: &str, zstd: bool) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("chester-{}-{}-{}", name, std::process::id(), zstd));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    /// The recordings in `dir` by name, with the frames read up to the first
+    /// that can't be, like replay does.
+    fn recorded(dir: &Path) -> Vec<(String, Vec<RecordedFrame>)> {
+        let mut files: Vec<_> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        files.sort();
+        files
+            .into_iter()
+            .map(|file| {
+                let frames = read_recording(&file)
+                    .unwrap()
+                    .lines()
+                    .map_while(Result::ok)
+                    .map(|line| serde_json::from_str(&line).unwrap())
+                    .collect();
+                let name = file.file_name().unwrap().to_string_lossy().to_string();
+                (name, frames)
+            })
+            .collect()
+    }
+
+    fn frame(received_at_us: u64, frame: &str) -> RecordedFrame {
+        RecordedFrame {
+            received_at_us,
+            frame: frame.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_recordings_rotate_hourly() {
+        for zstd in [false, true] {
+            let dir = temp_dir("recorder", zstd);
+            let options = RecordingOptions {
+                dir: dir.clone(),
+                zstd,
+            };
+            let mut writer = RecordingWriter::new(&options, "mainnet");
+            // 2024-05-01 13:59:59, twice, and 14:00:00 UTC
+            writer
+                .write(&frame(1_714_571_999_000_000, r#"{"type":"connected"}"#))
+                .unwrap();
+            writer
+                .write(&frame(1_714_571_999_000_001, r#"{"type":"subscribed"}"#))
+                .unwrap();
+            writer
+                .write(&frame(1_714_572_000_000_000, r#"{"type":"channel_data"}"#))
+                .unwrap();
+            drop(writer);
+
+            let extension = if zstd { "ndjson.zst" } else { "ndjson" };
+            let recordings = recorded(&dir);
+            assert_eq!(recordings.len(), 2);
+            let (name, frames) = &recordings[0];
+            assert_eq!(
+                *name,
+                format!("mainnet-2024-05-01T13-59-59.000000.{}", extension)
+            );
+            assert_eq!(frames.len(), 2);
+            assert_eq!(frames[0].frame, r#"{"type":"connected"}"#);
+            assert_eq!(frames[1].received_at_us, 1_714_571_999_000_001);
+            let (name, frames) = &recordings[1];
+            assert_eq!(
+                *name,
+                format!("mainnet-2024-05-01T14-00-00.000000.{}", extension)
+            );
+            assert_eq!(frames.len(), 1);
+            assert_eq!(frames[0].frame, r#"{"type":"channel_data"}"#);
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_a_killed_recorder_spoils_no_recording() {
+        for zstd in [false, true] {
+            let dir = temp_dir("recorder-killed", zstd);
+            let options = RecordingOptions {
+                dir: dir.clone(),
+                zstd,
+            };
+            let mut writer = RecordingWriter::new(&options, "mainnet");
+            writer
+                .write(&frame(1_714_571_000_000_000, r#"{"type":"connected"}"#))
+                .unwrap();
+            writer.flush().unwrap();
+            writer
+                .write(&frame(1_714_571_000_500_000, r#"{"type":"subscribed"}"#))
+                .unwrap();
+            // killed before the next flush, the zstd frame is never finished
+            std::mem::forget(writer);
+
+            // restarted within the same hour
+            let mut writer = RecordingWriter::new(&options, "mainnet");
+            writer
+                .write(&frame(1_714_571_001_000_000, r#"{"type":"connected"}"#))
+                .unwrap();
+            writer
+                .write(&frame(1_714_571_001_000_001, r#"{"type":"subscribed"}"#))
+                .unwrap();
+            drop(writer);
+
+            let recordings = recorded(&dir);
+            assert_eq!(recordings.len(), 2);
+            // what was flushed before the kill is still there
+            let (_, frames) = &recordings[0];
+            assert_eq!(frames[0].received_at_us, 1_714_571_000_000_000);
+            let (_, frames) = &recordings[1];
+            assert_eq!(
+                frames,
+                &[
+                    frame(1_714_571_001_000_000, r#"{"type":"connected"}"#),
+                    frame(1_714_571_001_000_001, r#"{"type":"subscribed"}"#),
+                ]
+            );
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+}
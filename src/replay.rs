@@ -0,0 +1,521 @@
+use std::{
+    collections::BTreeSet,
+    io::{self, BufRead},
+    path::PathBuf,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+use chrono::DateTime;
+use tokio::{sync::mpsc, time::Instant};
+
+use crate::{
+    candles::CandlesFolder,
+    config::CrossedBookPolicy,
+    core_types::{MarketEvent, OrderBookState, UpdateKind},
+    market_state::MarketStateFolder,
+    markets::MarketRegistry,
+    recorder::{read_recording, RecordedFrame},
+    subaccounts::SubaccountFolder,
+    trades::TradesFolder,
+    upstream::{
+        Command, OrderBookFolder, OrderBookStream, UpstreamError, UpstreamFeed, UpstreamHandle,
+    },
+    upstream_types::{IncomingMessage, MarketStatus, PerpetualMarket, Topic},
+};
+
+/// Frames read ahead of the one being played.
+const FRAME_BUFFER: usize = 1024;
+
+/// How fast recorded frames are played back.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReplaySpeed {
+    /// As fast as the books can be folded.
+    Max,
+    /// A multiple of the recorded pace, 1 being real time.
+    Factor(f64),
+}
+
+impl FromStr for ReplaySpeed {
+    type Err = String;
+
+    /// Accepts `max`, or a factor like `1`, `0.5` or `10x`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "max" {
+            return Ok(ReplaySpeed::Max);
+        }
+        let invalid = || {
+            format!(
+                "invalid replay speed '{}', expected a factor like 1 or 10x, or max",
+                s
+            )
+        };
+        let factor: f64 = s.trim_end_matches('x').parse().map_err(|_| invalid())?;
+        if !(factor.is_finite() && factor > 0.0) {
+            return Err(invalid());
+        }
+        Ok(ReplaySpeed::Factor(factor))
+    }
+}
+
+/// Parses an RFC 3339 timestamp like `2024-05-01T13:30:00Z` into microseconds
+/// since the unix epoch, the resolution of recordings.
+pub fn parse_timestamp(s: &str) -> Result<u64, String> {
+    let time =
+        DateTime::parse_from_rfc3339(s).map_err(|e| format!("invalid timestamp '{}': {}", s, e))?;
+    u64::try_from(time.timestamp_micros())
+        .map_err(|_| format!("timestamp '{}' predates the unix epoch", s))
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReplayOptions {
+    /// Recordings, or directories of them.
+    pub paths: Vec<PathBuf>,
+    pub speed: ReplaySpeed,
+    /// Frames recorded before this are folded right away, without being paced.
+    pub from_us: Option<u64>,
+}
+
+/// The recordings to replay, in order. Directories stand for the recordings
+/// in them sorted by name, which sorts hourly files by time.
+fn recordings(paths: &[PathBuf]) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for path in paths {
+        if !path.is_dir() {
+            files.push(path.clone());
+            continue;
+        }
+        let mut found = Vec::new();
+        for entry in std::fs::read_dir(path)? {
+            let file = entry?.path();
+            let name = file.file_name().unwrap_or_default().to_string_lossy();
+            if name.ends_with(".ndjson") || name.ends_with(".ndjson.zst") {
+                found.push(file);
+            }
+        }
+        found.sort();
+        files.extend(found);
+    }
+    Ok(files)
+}
+
+/// Reads the frames of every recording into `frames`, meant for a thread of its own.
+fn read_frames(files: Vec<PathBuf>, frames: mpsc::Sender<RecordedFrame>) {
+    for file in files {
+        let reader = match read_recording(&file) {
+            Ok(reader) => reader,
+            Err(e) => {
+                eprintln!("Reading {} failed: {}", file.display(), e);
+                continue;
+            }
+        };
+        for (i, line) in reader.lines().enumerate() {
+            let line = match line {
+                Ok(line) => line,
+                // e.g. the end of a recording that was still being written
+                Err(e) => {
+                    eprintln!("Reading {} failed at line {}: {}", file.display(), i + 1, e);
+                    break;
+                }
+            };
+            let frame = match serde_json::from_str(&line) {
+                Ok(frame) => frame,
+                Err(e) => {
+                    eprintln!("Skipping line {} of {}: {}", i + 1, file.display(), e);
+                    continue;
+                }
+            };
+            if frames.blocking_send(frame).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Starts playing the recordings back as if they came from the indexer. The
+/// markets met along the way are added to `registry`.
+pub fn spawn(
+    options: &ReplayOptions,
+    network: &str,
+    crossed_book_policy: CrossedBookPolicy,
+    registry: MarketRegistry,
+) -> anyhow::Result<(UpstreamHandle, OrderBookStream)> {
+    let files = recordings(&options.paths).context("Listing the recordings to replay")?;
+    if files.is_empty() {
+        anyhow::bail!("No recordings found to replay");
+    }
+    let (handle, stream, feed) = OrderBookStream::channel();
+    let (frames_tx, frames) = mpsc::channel(FRAME_BUFFER);
+    let _ = std::thread::Builder::new()
+        .name(format!("replay-{}", network))
+        .spawn(move || read_frames(files, frames_tx))
+        .context("Starting the replay")?;
+    let replayer = Replayer {
+        speed: options.speed,
+        from_us: options.from_us,
+        topics: BTreeSet::new(),
+        folder: OrderBookFolder::new(network, crossed_book_policy),
+        trades: TradesFolder::default(),
+        candles: CandlesFolder::default(),
+        markets: MarketStateFolder::default(),
+        subaccounts: SubaccountFolder::default(),
+        registry,
+        feed,
+        anchor: None,
+        recorded_at: SystemTime::UNIX_EPOCH,
+    };
+    tokio::spawn(replayer.run(frames));
+    Ok((handle, stream))
+}
+
+/// Folds recorded frames like the supervisor of a live connection does, only
+/// paced by the recorded receive times.
+struct Replayer {
+    speed: ReplaySpeed,
+    from_us: Option<u64>,
+    /// Topics clients follow, the others are folded but not sent.
+    topics: BTreeSet<Topic>,
+    folder: OrderBookFolder,
+    trades: TradesFolder,
+    candles: CandlesFolder,
+    markets: MarketStateFolder,
+    subaccounts: SubaccountFolder,
+    registry: MarketRegistry,
+    feed: UpstreamFeed,
+    /// A recorded time and the instant it was played at, which paces the
+    /// frames after it.
+    anchor: Option<(u64, Instant)>,
+    /// When the last frame played was received.
+    recorded_at: SystemTime,
+}
+
+impl Replayer {
+    async fn run(mut self, mut frames: mpsc::Receiver<RecordedFrame>) {
+        loop {
+            tokio::select! {
+                frame = frames.recv() => match frame {
+                    Some(frame) => {
+                        if !self.play(frame).await {
+                            return;
+                        }
+                    }
+                    None => break,
+                },
+                command = self.feed.commands.recv() => match command {
+                    Some(command) => {
+                        if !self.apply_command(command).await {
+                            return;
+                        }
+                    }
+                    None => return,
+                },
+            }
+        }
+        // the last books stay around for whoever still wants to look at them
+        eprintln!("Replay finished");
+        while let Some(command) = self.feed.commands.recv().await {
+            if !self.apply_command(command).await {
+                return;
+            }
+        }
+    }
+
+    /// Waits until the frame is due, then folds it. Returns false once nobody
+    /// is listening anymore.
+    async fn play(&mut self, frame: RecordedFrame) -> bool {
+        if let Some(due) = self.due(frame.received_at_us) {
+            let sleep = tokio::time::sleep_until(due);
+            tokio::pin!(sleep);
+            loop {
+                tokio::select! {
+                    _ = &mut sleep => break,
+                    command = self.feed.commands.recv() => match command {
+                        Some(command) => {
+                            if !self.apply_command(command).await {
+                                return false;
+                            }
+                        }
+                        None => return false,
+                    },
+                }
+            }
+        }
+        self.fold(frame).await
+    }
+
+    /// When a frame received at `received_at_us` is to be played, `None` for right away.
+    fn due(&mut self, received_at_us: u64) -> Option<Instant> {
+        if self.from_us.is_some_and(|from| received_at_us < from) {
+            return None;
+        }
+        let ReplaySpeed::Factor(factor) = self.speed else {
+            return None;
+        };
+        let (anchor_us, anchor) = *self
+            .anchor
+            .get_or_insert_with(|| (received_at_us, Instant::now()));
+        let elapsed = Duration::from_micros(received_at_us.saturating_sub(anchor_us));
+        Some(anchor + elapsed.div_f64(factor))
+    }
+
+    async fn fold(&mut self, frame: RecordedFrame) -> bool {
+        self.recorded_at = SystemTime::UNIX_EPOCH + Duration::from_micros(frame.received_at_us);
+        let message = match frame.frame.parse::<IncomingMessage>() {
+            Ok(message) => message,
+            Err(e) => {
+                eprintln!("Skipping a recorded frame: {}", e);
+                return true;
+            }
+        };
+        let topic = match (&message, message.topic()) {
+            (IncomingMessage::Connected(connected), _) => {
+                // the recorded connection got replaced, everything starts over
+                self.folder.begin_connection(&connected.connection_id);
+                self.trades.reset();
+                self.candles.reset();
+                self.markets.reset();
+                self.subaccounts.reset();
+                let reconnect = UpstreamError::Closed("the recording reconnected".to_string());
+                return self.feed.tx.send(Err(reconnect)).await.is_ok();
+            }
+            (_, Some(topic)) => topic,
+            (_, None) => return true,
+        };
+        // errors about a topic are not replayed, it simply gets no more updates
+        let folded = match message {
+            IncomingMessage::Orderbook(message) => self
+                .folder
+                .consume_orderbook_incoming_msg(message)
+                .map(|update| {
+                    update.map(|mut update| {
+                        if let UpdateKind::Snapshot = update.kind {
+                            self.register(&update.book);
+                        }
+                        update.updated_at = self.recorded_at;
+                        MarketEvent::Book(Arc::new(update))
+                    })
+                }),
+            IncomingMessage::Trades(message) => self
+                .trades
+                .consume(message)
+                .map(|update| update.map(|update| MarketEvent::Trades(Arc::new(update)))),
+            IncomingMessage::Candles(message) => self
+                .candles
+                .consume(message)
+                .map(|update| update.map(|update| MarketEvent::Candles(Arc::new(update)))),
+            IncomingMessage::Markets(message) => self
+                .markets
+                .consume(message)
+                .map(|update| update.map(|update| MarketEvent::Markets(Arc::new(update)))),
+            IncomingMessage::Subaccounts(message) => self
+                .subaccounts
+                .consume(message)
+                .map(|update| update.map(|update| MarketEvent::Subaccount(Arc::new(update)))),
+            IncomingMessage::BlockHeight(message) => {
+                self.folder.consume_block_height_msg(message);
+                Ok(None)
+            }
+            IncomingMessage::Connected(_) | IncomingMessage::Error(_) => Ok(None),
+        };
+        let event = match folded {
+            Ok(Some(event)) => event,
+            Ok(None) => return true,
+            Err(e) => {
+                self.forget(&topic);
+                if !self.topics.contains(&topic) {
+                    return true;
+                }
+                let desync = UpstreamError::Desync {
+                    topic,
+                    reason: format!("{:#}", e),
+                };
+                return self.feed.tx.send(Err(desync)).await.is_ok();
+            }
+        };
+        if !self.topics.contains(&topic) {
+            return true;
+        }
+        self.feed.tx.send(Ok(event)).await.is_ok()
+    }
+
+    fn forget(&mut self, topic: &Topic) {
+        match topic {
+            Topic::Orderbook(market) => self.folder.forget(market),
+            Topic::Trades(market) => self.trades.forget(market),
+            Topic::Candles(id) => self.candles.forget(id),
+            Topic::Markets => self.markets.reset(),
+            Topic::Subaccount(id) => self.subaccounts.forget(id),
+            Topic::BlockHeight => {}
+        }
+    }
+
+    /// Lists a market met in the recording. Recordings know nothing of tick
+    /// and step sizes, so they are left unknown.
+    fn register(&self, book: &OrderBookState) {
+        if self.registry.get(&book.market).is_some() {
+            return;
+        }
+        self.registry.insert(PerpetualMarket {
+            ticker: book.market.clone(),
+            status: MarketStatus::Active,
+            tick_size: None,
+            step_size: None,
+            oracle_price: None,
+            initial_margin_fraction: None,
+            maintenance_margin_fraction: None,
+            next_funding_rate: None,
+            open_interest: None,
+            volume_24h: None,
+        });
+    }
+
+    /// Follows the topic from its current state on, returns false once
+    /// nobody is listening anymore.
+    async fn apply_command(&mut self, command: Command) -> bool {
+        match command {
+            Command::Subscribe(topic) => {
+                if !self.topics.insert(topic.clone()) {
+                    return true;
+                }
+                let event = match &topic {
+                    Topic::Orderbook(market) => self.folder.snapshot(market).map(|mut update| {
+                        update.updated_at = self.recorded_at;
+                        MarketEvent::Book(Arc::new(update))
+                    }),
+                    Topic::Trades(market) => self
+                        .trades
+                        .snapshot(market)
+                        .map(|update| MarketEvent::Trades(Arc::new(update))),
+                    Topic::Candles(id) => self
+                        .candles
+                        .snapshot(id)
+                        .map(|update| MarketEvent::Candles(Arc::new(update))),
+                    Topic::Markets => self
+                        .markets
+                        .snapshot()
+                        .map(|update| MarketEvent::Markets(Arc::new(update))),
+                    Topic::Subaccount(id) => self
+                        .subaccounts
+                        .snapshot(id)
+                        .map(|update| MarketEvent::Subaccount(Arc::new(update))),
+                    // only ever folded into the books
+                    Topic::BlockHeight => None,
+                };
+                let Some(event) = event else {
+                    return true;
+                };
+                self.feed.tx.send(Ok(event)).await.is_ok()
+            }
+            Command::Unsubscribe(topic) => {
+                let _ = self.topics.remove(&topic);
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::StreamExt;
+    use rust_decimal::Decimal;
+
+    use crate::upstream_types::Market;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_replay_options() {
+        assert_eq!(ReplaySpeed::from_str("max"), Ok(ReplaySpeed::Max));
+        assert_eq!(ReplaySpeed::from_str("10x"), Ok(ReplaySpeed::Factor(10.0)));
+        assert_eq!(ReplaySpeed::from_str("0.5"), Ok(ReplaySpeed::Factor(0.5)));
+        assert!(ReplaySpeed::from_str("0").is_err());
+        assert!(ReplaySpeed::from_str("fast").is_err());
+
+        assert_eq!(
+            parse_timestamp("2024-05-01T13:59:59.5Z"),
+            Ok(1_714_571_999_500_000)
+        );
+        assert!(parse_timestamp("2024-05-01").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_replay_folds_recorded_frames() {
+        let dir = std::env::temp_dir().join(format!("chester-replay-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let frames = [
+            r#"{"type":"connected","connection_id":"9a75aff4","message_id":0}"#,
+            r#"{"type":"subscribed","connection_id":"9a75aff4","message_id":1,"channel":"v4_orderbook","id":"ETH-USD","contents":{"bids":[{"price":"3040.6","size":"0.658"}],"asks":[{"price":"3073.1","size":"0.022"}]}}"#,
+            r#"{"type":"channel_batch_data","connection_id":"9a75aff4","message_id":2,"id":"ETH-USD","channel":"v4_orderbook","version":"1.0.0","contents":[{"bids":[["3040.65","1"]]}]}"#,
+            r#"{"type":"channel_batch_data","connection_id":"9a75aff4","message_id":3,"id":"ETH-USD","channel":"v4_orderbook","version":"1.0.0","contents":[{"bids":[["3040.7","2"]]}]}"#,
+        ];
+        let recording: String = frames
+            .iter()
+            .enumerate()
+            .map(|(i, frame)| {
+                let frame = RecordedFrame {
+                    received_at_us: 1_714_571_999_000_000 + i as u64 * 1_000_000,
+                    frame: frame.to_string(),
+                };
+                serde_json::to_string(&frame).unwrap() + "\n"
+            })
+            .collect();
+        std::fs::write(dir.join("mainnet-2024-05-01T13.ndjson"), recording).unwrap();
+
+        let options = ReplayOptions {
+            paths: vec![dir.clone()],
+            speed: ReplaySpeed::Factor(2.0),
+            // the snapshot is folded right away, the deltas from then on are
+            // paced, the second half a second after the first at twice the speed
+            from_us: Some(1_714_572_001_000_000),
+        };
+        let registry = MarketRegistry::default();
+        let (handle, mut stream) = spawn(
+            &options,
+            "mainnet",
+            CrossedBookPolicy::Flag,
+            registry.clone(),
+        )
+        .unwrap();
+        let eth = Market::from_str("ETH-USD").unwrap();
+        handle.subscribe(Topic::Orderbook(eth.clone()));
+
+        // the reconnect of the recording comes first
+        assert!(matches!(
+            stream.next().await,
+            Some(Err(UpstreamError::Closed(_)))
+        ));
+        let Some(Ok(MarketEvent::Book(update))) = stream.next().await else {
+            panic!("expected a book");
+        };
+        let update = match update.seq {
+            // subscribed before the snapshot got folded
+            1 => match stream.next().await {
+                Some(Ok(MarketEvent::Book(update))) => update,
+                other => panic!("expected a book, got {:?}", other),
+            },
+            // subscribed after, and got the current book as a snapshot
+            _ => update,
+        };
+        assert_eq!(update.seq, 2);
+        assert_eq!(
+            update.book.best_bid(),
+            Some((Decimal::from_str("3040.65").unwrap(), Decimal::ONE))
+        );
+        assert_eq!(
+            update.updated_at,
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_714_572_001)
+        );
+        assert_eq!(registry.check(&eth).unwrap().tick_size, None);
+
+        let received = Instant::now();
+        let Some(Ok(MarketEvent::Book(update))) = stream.next().await else {
+            panic!("expected a book");
+        };
+        assert_eq!(update.seq, 3);
+        assert!(received.elapsed() >= Duration::from_millis(400));
+        assert!(received.elapsed() < Duration::from_millis(1000));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
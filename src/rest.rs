@@ -0,0 +1,186 @@
+use std::time::{Duration, SystemTime};
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    core_types::{BookView, MarketEvent, OrderBookState},
+    downstream::{validate_subscription, BookMode, Channel, SubscriptionOptions},
+    upstream_types::{Block, Market, Topic},
+    AppState,
+};
+
+/// How long a one-shot request waits for the first snapshot of a market
+/// that nobody follows yet.
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Deserialize, Debug)]
+pub struct OrderBookParams {
+    depth: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct OrderBookResponse {
+    message_id: usize,
+    /// Milliseconds since the unix epoch.
+    updated_at: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    block: Option<Block>,
+    #[serde(flatten)]
+    book: OrderBookState,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum SyncStatus {
+    Synced,
+    /// Waiting for a snapshot, either the first one or after a resync.
+    Syncing,
+}
+
+#[derive(Serialize)]
+struct MarketStatusEntry {
+    market: Market,
+    status: SyncStatus,
+    subscribers: usize,
+    message_id: Option<usize>,
+    updated_at: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct MarketsResponse {
+    markets: Vec<MarketStatusEntry>,
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map(|since| since.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+fn error(status: StatusCode, message: String) -> Response {
+    (status, message).into_response()
+}
+
+/// `GET /orderbook/{market}?depth=`, the current book of one market.
+///
+/// Markets nobody follows are subscribed just for the request, so the first
+/// request for them takes as long as the indexer needs for a snapshot.
+pub async fn orderbook(
+    State(state): State<AppState>,
+    Path(market): Path<Market>,
+    Query(params): Query<OrderBookParams>,
+) -> Response {
+    let options = SubscriptionOptions {
+        mode: BookMode::Full,
+        view: BookView {
+            depth: params.depth,
+            group: None,
+        },
+        ..Default::default()
+    };
+    let markets = std::slice::from_ref(&market);
+    if let Err(e) = validate_subscription(&state, Channel::Orderbook, markets, &options) {
+        return error(StatusCode::BAD_REQUEST, e);
+    }
+
+    let mut subscription = state.manager.subscribe(&Topic::Orderbook(market.clone()));
+    let first_book = async {
+        loop {
+            match subscription.recv().await {
+                Some(MarketEvent::Book(update)) => return Ok(update),
+                Some(
+                    MarketEvent::Resyncing { .. }
+                    | MarketEvent::Trades(_)
+                    | MarketEvent::Candles(_)
+                    | MarketEvent::Markets(_)
+                    | MarketEvent::Subaccount(_),
+                ) => continue,
+                Some(MarketEvent::Failed { reason, .. }) => return Err(reason.to_string()),
+                None => return Err("The upstream is gone".to_string()),
+            }
+        }
+    };
+    let update = match tokio::time::timeout(SNAPSHOT_TIMEOUT, first_book).await {
+        Ok(Ok(update)) => update,
+        Ok(Err(reason)) => return error(StatusCode::BAD_GATEWAY, reason),
+        Err(_) => {
+            return error(
+                StatusCode::GATEWAY_TIMEOUT,
+                format!("No snapshot of {} arrived in time", market),
+            )
+        }
+    };
+    Json(OrderBookResponse {
+        message_id: update.seq,
+        updated_at: unix_millis(update.updated_at),
+        block: update.block.clone(),
+        book: update.book.view(&options.view),
+    })
+    .into_response()
+}
+
+/// `GET /markets`, every market currently followed and whether its book is in sync.
+pub async fn markets(State(state): State<AppState>) -> Response {
+    let markets = state
+        .manager
+        .summaries()
+        .into_iter()
+        .map(|summary| MarketStatusEntry {
+            market: summary.market,
+            status: match summary.latest {
+                Some(_) => SyncStatus::Synced,
+                None => SyncStatus::Syncing,
+            },
+            subscribers: summary.subscribers,
+            message_id: summary.latest.as_ref().map(|update| update.seq),
+            updated_at: summary
+                .latest
+                .as_ref()
+                .map(|update| unix_millis(update.updated_at)),
+        })
+        .collect();
+    Json(MarketsResponse { markets }).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use rust_decimal::Decimal;
+
+    use crate::core_types::Offer;
+
+    use super::*;
+
+    #[test]
+    fn test_serialize_orderbook_response() {
+        let book = OrderBookState::construct_from(
+            vec![Offer {
+                price: Decimal::from(101),
+                size: Decimal::ONE,
+            }],
+            vec![],
+            7,
+            Market::from_str("ETH-USD").unwrap(),
+        );
+        let response = OrderBookResponse {
+            message_id: 7,
+            updated_at: unix_millis(SystemTime::UNIX_EPOCH + Duration::from_millis(1500)),
+            block: Some(Block {
+                height: "1000".to_string(),
+                time: "2024-05-01T13:30:00.000Z".to_string(),
+            }),
+            book,
+        };
+        assert_eq!(
+            serde_json::to_string(&response).unwrap(),
+            r#"{"message_id":7,"updated_at":1500,"block":{"height":"1000","time":"2024-05-01T13:30:00.000Z"},"market":"ETH-USD","asks":[["101","1"]],"bids":[]}"#
+        );
+    }
+}
@@ -0,0 +1,119 @@
+use std::{convert::Infallible, str::FromStr};
+
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::{
+        sse::{Event, KeepAlive},
+        IntoResponse, Response, Sse,
+    },
+};
+use axum_extra::extract::Query;
+use tokio::sync::mpsc;
+
+use crate::{
+    downstream::{too_far_behind, Outbound, ResumePoint, Subscriptions, OUTBOUND_BUFFER},
+    metrics::METRICS,
+    AppState, WSParams,
+};
+
+/// The subscriptions of one SSE client, counted as connected while they live.
+struct SseClient {
+    network: String,
+    _subscriptions: Subscriptions,
+}
+
+impl SseClient {
+    fn new(network: &str, subscriptions: Subscriptions) -> Self {
+        METRICS.clients.with_label_values(&[network]).inc();
+        Self {
+            network: network.to_string(),
+            _subscriptions: subscriptions,
+        }
+    }
+}
+
+impl Drop for SseClient {
+    fn drop(&mut self) {
+        METRICS.clients.with_label_values(&[&self.network]).dec();
+    }
+}
+
+/// `GET /sse?market=..`, the books of the websocket endpoint as Server-Sent Events.
+///
+/// Every book event carries an id, a client reconnecting with it as
+/// `Last-Event-ID` is not sent the book of that market again. The other
+/// markets start over with their latest book.
+pub async fn handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(params): Query<WSParams>,
+) -> Response {
+    let (keys, options) = match params.validate(&state) {
+        Ok(validated) => validated,
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
+    if keys.is_empty() {
+        return (StatusCode::BAD_REQUEST, "No market given").into_response();
+    }
+    let resume = match headers.get("last-event-id").map(|id| id.to_str()) {
+        None => None,
+        Some(Ok(id)) => match ResumePoint::from_str(id) {
+            Ok(resume) => Some(resume),
+            Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+        },
+        Some(Err(_)) => return (StatusCode::BAD_REQUEST, "Invalid Last-Event-ID").into_response(),
+    };
+
+    let (outbound_tx, outbound) = mpsc::channel(OUTBOUND_BUFFER);
+    let mut subscriptions = Subscriptions::default();
+    for key in keys {
+        subscriptions.add(
+            &state.manager,
+            key,
+            options.clone(),
+            outbound_tx.clone(),
+            resume.clone(),
+        );
+    }
+    let client = SseClient::new(state.manager.network(), subscriptions);
+    let events = futures_util::stream::unfold((outbound, client), |(mut outbound, client)| async {
+        loop {
+            let (message, update, queued_at) = match outbound.recv().await? {
+                Outbound::Message {
+                    message,
+                    update,
+                    queued_at,
+                    ..
+                } => (message, update, queued_at),
+                // the failed status has already been sent, there are no requests to answer
+                Outbound::Failed { .. } => continue,
+                Outbound::Ended { .. } => return None,
+            };
+            if too_far_behind(queued_at) {
+                eprintln!("Disconnecting SSE client: Too far behind");
+                METRICS
+                    .slow_client_disconnects
+                    .with_label_values(&[&client.network])
+                    .inc();
+                return None;
+            }
+            METRICS
+                .bytes_sent
+                .with_label_values(&[&client.network])
+                .inc_by(message.len() as u64);
+            let mut event = Event::default().data(message);
+            if let Some(update) = update {
+                METRICS
+                    .update_latency
+                    .with_label_values(&[&client.network])
+                    .observe(update.received_at.elapsed().as_secs_f64());
+                event = event.id(ResumePoint::event_id(&update));
+            }
+            return Some((Ok::<_, Infallible>(event), (outbound, client)));
+        }
+    });
+    Sse::new(events)
+        .keep_alive(KeepAlive::default().text("keepalive"))
+        .into_response()
+}
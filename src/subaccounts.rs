@@ -0,0 +1,374 @@
+use std::collections::{BTreeMap, VecDeque};
+
+use anyhow::Context;
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::{
+    core_types::OrderBookState,
+    upstream_types::{
+        Market, OrderSide, OrderStatus, PerpetualPosition, PositionStatus, SubaccountFill,
+        SubaccountId, SubaccountOrder, SubaccountsChannelBatchData, SubaccountsIncomingMessages,
+        Transfer,
+    },
+};
+
+/// Fills and transfers kept per subaccount, for clients subscribing later on.
+const RECENT_EVENTS: usize = 100;
+
+/// What is known of a subaccount: its open orders and positions, and the
+/// fills and transfers seen since subscribing to it.
+#[derive(Debug, Default)]
+struct Subaccount {
+    orders: BTreeMap<String, SubaccountOrder>,
+    positions: BTreeMap<Market, PerpetualPosition>,
+    fills: VecDeque<SubaccountFill>,
+    transfers: VecDeque<Transfer>,
+}
+
+/// A subaccount after an upstream message, shared by every client following it.
+#[derive(Debug)]
+pub struct SubaccountUpdate {
+    pub id: SubaccountId,
+    /// The open orders, by id.
+    pub orders: Vec<SubaccountOrder>,
+    /// The open positions, by market.
+    pub positions: Vec<PerpetualPosition>,
+    /// The newest first.
+    pub fills: Vec<SubaccountFill>,
+    /// The newest first.
+    pub transfers: Vec<Transfer>,
+}
+
+/// The part of `recent` newer than `last`, all of it if `last` is not in there.
+fn newer_than<'a, T>(recent: &'a [T], last: &[T], same: impl Fn(&T, &T) -> bool) -> &'a [T] {
+    let Some(newest) = last.first() else {
+        return recent;
+    };
+    match recent.iter().position(|item| same(item, newest)) {
+        Some(seen) => &recent[..seen],
+        None => recent,
+    }
+}
+
+impl SubaccountUpdate {
+    /// The orders of the market resting on the book.
+    pub fn resting_orders<'a>(
+        &'a self,
+        market: &'a Market,
+    ) -> impl Iterator<Item = &'a SubaccountOrder> + 'a {
+        self.orders
+            .iter()
+            .filter(move |order| order.ticker == *market && order.status.is_resting())
+    }
+
+    /// Renders the subaccount for a client, with the fills and transfers
+    /// since `last`, or all of them as a snapshot if there is no `last`.
+    /// `None` if nothing changed.
+    pub fn json_since(&self, last: Option<&SubaccountUpdate>) -> Option<String> {
+        let (fills, transfers) = match last {
+            None => (&self.fills[..], &self.transfers[..]),
+            Some(last) => (
+                newer_than(&self.fills, &last.fills, |a, b| a.id == b.id),
+                newer_than(&self.transfers, &last.transfers, |a, b| a == b),
+            ),
+        };
+        let unchanged =
+            last.is_some_and(|last| last.orders == self.orders && last.positions == self.positions);
+        if unchanged && fills.is_empty() && transfers.is_empty() {
+            return None;
+        }
+        let message = SubaccountMessage {
+            subaccount: &self.id,
+            snapshot: last.is_none(),
+            orders: &self.orders,
+            positions: &self.positions,
+            fills,
+            transfers,
+        };
+        Some(serde_json::to_string(&message).expect("subaccounts should serialize"))
+    }
+}
+
+/// A subaccount as sent to clients, `{"type":"subaccount",...}`. Orders and
+/// positions are always whole and replace what the client had, fills and
+/// transfers are only the new ones unless it is a snapshot.
+#[derive(Serialize)]
+#[serde(tag = "type", rename = "subaccount")]
+struct SubaccountMessage<'a> {
+    subaccount: &'a SubaccountId,
+    snapshot: bool,
+    orders: &'a [SubaccountOrder],
+    positions: &'a [PerpetualPosition],
+    fills: &'a [SubaccountFill],
+    transfers: &'a [Transfer],
+}
+
+/// Keeps the state of every subaccount subscribed to.
+#[derive(Debug, Default)]
+pub struct SubaccountFolder {
+    subaccounts: BTreeMap<SubaccountId, Subaccount>,
+}
+
+impl SubaccountFolder {
+    pub fn reset(&mut self) {
+        self.subaccounts.clear();
+    }
+
+    pub fn forget(&mut self, id: &SubaccountId) {
+        let _ = self.subaccounts.remove(id);
+    }
+
+    /// The subaccount, if its subscription has been seen yet.
+    pub fn snapshot(&self, id: &SubaccountId) -> Option<SubaccountUpdate> {
+        let subaccount = self.subaccounts.get(id)?;
+        Some(SubaccountUpdate {
+            id: id.clone(),
+            orders: subaccount.orders.values().cloned().collect(),
+            positions: subaccount.positions.values().cloned().collect(),
+            fills: subaccount.fills.iter().cloned().collect(),
+            transfers: subaccount.transfers.iter().cloned().collect(),
+        })
+    }
+
+    /// Folds the message into the subaccount, returning what it now is.
+    pub fn consume(
+        &mut self,
+        msg: SubaccountsIncomingMessages,
+    ) -> anyhow::Result<Option<SubaccountUpdate>> {
+        let id = match msg {
+            SubaccountsIncomingMessages::Subscribed(subscribed) => {
+                let mut subaccount = Subaccount::default();
+                for (market, position) in subscribed.contents.subaccount.open_perpetual_positions {
+                    let _ = subaccount.positions.insert(market, position);
+                }
+                for order in subscribed.contents.orders {
+                    subaccount.apply_order(order);
+                }
+                let _ = self.subaccounts.insert(subscribed.id.clone(), subaccount);
+                subscribed.id
+            }
+            SubaccountsIncomingMessages::ChannelData(data) => {
+                return self.consume_batch(data.into())
+            }
+            SubaccountsIncomingMessages::ChannelBatchData(batch) => {
+                return self.consume_batch(batch)
+            }
+            SubaccountsIncomingMessages::Unsubscribed(unsubscribed) => {
+                self.forget(&unsubscribed.id);
+                return Ok(None);
+            }
+        };
+        Ok(self.snapshot(&id))
+    }
+
+    fn consume_batch(
+        &mut self,
+        batch: SubaccountsChannelBatchData,
+    ) -> anyhow::Result<Option<SubaccountUpdate>> {
+        let subaccount = self.subaccounts.get_mut(&batch.id).context(format!(
+            "The subaccount {} has not seen a snapshot yet, got an update",
+            batch.id
+        ))?;
+        for contents in batch.contents {
+            for position in contents.perpetual_positions {
+                if position.status == PositionStatus::Open {
+                    let _ = subaccount
+                        .positions
+                        .insert(position.market.clone(), position);
+                } else {
+                    let _ = subaccount.positions.remove(&position.market);
+                }
+            }
+            for order in contents.orders {
+                subaccount.apply_order(order);
+            }
+            for fill in contents.fills {
+                subaccount.fills.push_front(fill);
+            }
+            if let Some(transfer) = contents.transfers {
+                subaccount.transfers.push_front(transfer);
+            }
+        }
+        subaccount.fills.truncate(RECENT_EVENTS);
+        subaccount.transfers.truncate(RECENT_EVENTS);
+        Ok(self.snapshot(&batch.id))
+    }
+}
+
+impl Subaccount {
+    /// Keeps the order while it is open, whether resting or waiting for its trigger.
+    fn apply_order(&mut self, order: SubaccountOrder) {
+        if order.status.is_resting() || order.status == OrderStatus::Untriggered {
+            let _ = self.orders.insert(order.id.clone(), order);
+        } else {
+            let _ = self.orders.remove(&order.id);
+        }
+    }
+}
+
+/// A book with the resting orders of a subaccount, sent as
+/// `{"type":"ladder",...}`. Its levels are `[price, size, own]`, `own` being
+/// the part of the size that is the subaccount's. Own orders the book does
+/// not show yet are levels of their own.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename = "ladder")]
+pub struct Ladder {
+    pub market: Market,
+    pub subaccount: SubaccountId,
+    pub asks: Vec<(Decimal, Decimal, Decimal)>,
+    pub bids: Vec<(Decimal, Decimal, Decimal)>,
+}
+
+impl OrderBookState {
+    /// The book with the resting orders of the subaccount laid over it.
+    pub fn ladder(&self, id: &SubaccountId, subaccount: Option<&SubaccountUpdate>) -> Ladder {
+        let mut own: BTreeMap<(OrderSide, Decimal), Decimal> = BTreeMap::new();
+        for order in subaccount
+            .into_iter()
+            .flat_map(|subaccount| subaccount.resting_orders(&self.market))
+        {
+            *own.entry((order.side, order.price)).or_default() += order.remaining();
+        }
+        let side = |book: &BTreeMap<Decimal, Decimal>, side| {
+            let mut levels: BTreeMap<Decimal, (Decimal, Decimal)> = book
+                .iter()
+                .map(|(price, size)| (*price, (*size, Decimal::ZERO)))
+                .collect();
+            for ((_, price), size) in own.iter().filter(|((of, _), _)| *of == side) {
+                levels.entry(*price).or_default().1 += size;
+            }
+            levels
+                .into_iter()
+                .map(|(price, (size, own))| (price, size, own.normalize()))
+                .collect::<Vec<_>>()
+        };
+        let mut bids = side(&self.bids, OrderSide::Buy);
+        bids.reverse();
+        Ladder {
+            market: self.market.clone(),
+            subaccount: id.clone(),
+            asks: side(&self.asks, OrderSide::Sell),
+            bids,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use crate::{core_types::Offer, upstream_types::IncomingMessage};
+
+    use super::*;
+
+    const ID: &str = "dydx1abc/0";
+
+    fn frame(kind: &str, message_id: usize, contents: &str) -> String {
+        format!(
+            r#"{{"type":"{}","connection_id":"c","message_id":{},"channel":"v4_subaccounts","id":"{}","contents":{}}}"#,
+            kind, message_id, ID, contents
+        )
+    }
+
+    fn order(id: &str, side: &str, price: &str, filled: &str, status: &str) -> String {
+        format!(
+            r#"{{"id":"{}","subaccountId":"s","clientId":"1","clobPairId":"1","side":"{}","size":"2","totalFilled":"{}","price":"{}","type":"LIMIT","status":"{}","timeInForce":"GTT","postOnly":false,"reduceOnly":false,"ticker":"ETH-USD"}}"#,
+            id, side, filled, price, status
+        )
+    }
+
+    fn consume(
+        folder: &mut SubaccountFolder,
+        frame: &str,
+    ) -> anyhow::Result<Option<SubaccountUpdate>> {
+        let Ok(IncomingMessage::Subaccounts(message)) = IncomingMessage::from_str(frame) else {
+            panic!("expected a subaccounts message");
+        };
+        folder.consume(message)
+    }
+
+    fn dec(s: &str) -> Decimal {
+        Decimal::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn test_fold_subaccount() {
+        let mut folder = SubaccountFolder::default();
+        let subscribed = frame(
+            "subscribed",
+            1,
+            &format!(
+                r#"{{"subaccount":{{"address":"dydx1abc","subaccountNumber":0,"equity":"1000","openPerpetualPositions":{{"ETH-USD":{{"market":"ETH-USD","status":"OPEN","side":"LONG","size":"3","maxSize":"3","entryPrice":"99","realizedPnl":"0","unrealizedPnl":"3","netFunding":"0"}}}}}},"orders":[{},{}]}}"#,
+                order("a", "BUY", "99", "0.5", "OPEN"),
+                order("b", "SELL", "105", "0", "OPEN")
+            ),
+        );
+        let first = consume(&mut folder, &subscribed).unwrap().unwrap();
+        assert_eq!(first.id, SubaccountId::from_str(ID).unwrap());
+        assert_eq!((first.orders.len(), first.positions.len()), (2, 1));
+
+        let update = frame(
+            "channel_data",
+            2,
+            &format!(
+                r#"{{"orders":[{}],"fills":[{{"id":"f1","subaccountId":"s","side":"SELL","liquidity":"MAKER","type":"LIMIT","clobPairId":"1","orderId":"b","size":"2","price":"105","quoteAmount":"210","eventId":"e","transactionHash":"t","createdAt":"2024-05-01T13:30:00.000Z","createdAtHeight":"1000","ticker":"ETH-USD","fee":"0.01"}}],"perpetualPositions":[{{"market":"ETH-USD","status":"CLOSED","side":"LONG","size":"0","entryPrice":"99","exitPrice":"105"}}]}}"#,
+                order("b", "SELL", "105", "2", "FILLED")
+            ),
+        );
+        let second = consume(&mut folder, &update).unwrap().unwrap();
+        assert_eq!(second.orders.len(), 1);
+        assert!(second.positions.is_empty());
+        let rendered = second.json_since(Some(&first)).unwrap();
+        assert!(rendered.starts_with(r#"{"type":"subaccount","subaccount":"dydx1abc/0","snapshot":false,"orders":[{"id":"a","client_id":"1","ticker":"ETH-USD","side":"BUY","size":"2","price":"99","total_filled":"0.5","status":"OPEN","#));
+        assert!(rendered.ends_with(r#""positions":[],"fills":[{"id":"f1","ticker":"ETH-USD","side":"SELL","liquidity":"MAKER","type":"LIMIT","order_id":"b","size":"2","price":"105","fee":"0.01","created_at":"2024-05-01T13:30:00.000Z","created_at_height":"1000"}],"transfers":[]}"#));
+        assert_eq!(second.json_since(Some(&second)), None);
+
+        let other = frame("channel_data", 3, r#"{"orders":[]}"#).replace(ID, "dydx1def/0");
+        assert!(consume(&mut folder, &other).is_err());
+    }
+
+    #[test]
+    fn test_ladder() {
+        let offer = |price: &str, size: &str| Offer {
+            price: dec(price),
+            size: dec(size),
+        };
+        let book = OrderBookState::construct_from(
+            vec![offer("101", "1"), offer("102", "2")],
+            vec![offer("99", "3"), offer("98", "5")],
+            1,
+            Market::from_str("ETH-USD").unwrap(),
+        );
+        let id = SubaccountId::from_str(ID).unwrap();
+        let mut folder = SubaccountFolder::default();
+        let subscribed = frame(
+            "subscribed",
+            1,
+            &format!(
+                r#"{{"subaccount":{{"address":"dydx1abc","subaccountNumber":0}},"orders":[{},{},{}]}}"#,
+                order("a", "BUY", "99", "0.5", "OPEN"),
+                order("b", "SELL", "103", "0", "BEST_EFFORT_OPENED"),
+                order("c", "SELL", "101", "0", "UNTRIGGERED")
+            ),
+        );
+        let subaccount = consume(&mut folder, &subscribed).unwrap().unwrap();
+        let ladder = book.ladder(&id, Some(&subaccount));
+        assert_eq!(
+            ladder.bids,
+            vec![
+                (dec("99"), dec("3"), dec("1.5")),
+                (dec("98"), dec("5"), Decimal::ZERO)
+            ]
+        );
+        assert_eq!(
+            ladder.asks,
+            vec![
+                (dec("101"), dec("1"), Decimal::ZERO),
+                (dec("102"), dec("2"), Decimal::ZERO),
+                (dec("103"), Decimal::ZERO, dec("2"))
+            ]
+        );
+        assert_eq!(book.ladder(&id, None).bids[0].2, Decimal::ZERO);
+    }
+}
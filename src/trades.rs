@@ -0,0 +1,225 @@
+use std::collections::{BTreeMap, VecDeque};
+
+use anyhow::Context;
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::{
+    analytics::Side,
+    upstream_types::{self, Market, OrderSide, TradesIncomingMessages},
+};
+
+/// Trades kept per market, about as many as the indexer sends when subscribing.
+const RECENT_TRADES: usize = 100;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Trade {
+    pub id: String,
+    pub side: Side,
+    pub size: Decimal,
+    pub price: Decimal,
+    pub created_at: String,
+    pub liquidation: bool,
+}
+
+impl From<upstream_types::Trade> for Trade {
+    fn from(trade: upstream_types::Trade) -> Self {
+        Self {
+            liquidation: trade.is_liquidation(),
+            id: trade.id,
+            side: match trade.side {
+                OrderSide::Buy => Side::Buy,
+                OrderSide::Sell => Side::Sell,
+            },
+            size: trade.size,
+            price: trade.price,
+            created_at: trade.created_at,
+        }
+    }
+}
+
+/// The recent trades of one market after an upstream message, shared by
+/// every client following them.
+#[derive(Debug)]
+pub struct TradesUpdate {
+    pub market: Market,
+    /// The newest first.
+    pub recent: Vec<Trade>,
+}
+
+impl TradesUpdate {
+    /// The trades that came after those of `last`, `None` if that is too
+    /// long ago to tell.
+    pub fn since(&self, last: &TradesUpdate) -> Option<&[Trade]> {
+        let Some(newest) = last.recent.first() else {
+            return (self.recent.len() < RECENT_TRADES).then_some(&self.recent[..]);
+        };
+        let seen = self.recent.iter().position(|trade| trade.id == newest.id)?;
+        Some(&self.recent[..seen])
+    }
+
+    /// Renders the trades that came after those of `last` for a client, the
+    /// recent trades as a snapshot if there is no `last` to build on.
+    /// `None` if there is nothing new.
+    pub fn json_since(&self, last: Option<&TradesUpdate>) -> Option<String> {
+        let message = match last.map(|last| self.since(last)) {
+            Some(Some([])) => return None,
+            Some(Some(trades)) => TradesMessage {
+                market: &self.market,
+                snapshot: false,
+                trades,
+            },
+            Some(None) | None => TradesMessage {
+                market: &self.market,
+                snapshot: true,
+                trades: &self.recent,
+            },
+        };
+        Some(serde_json::to_string(&message).expect("trades should serialize"))
+    }
+}
+
+/// Trades as sent to clients, `{"type":"trades",...}`. A snapshot has every
+/// recent trade and replaces whatever the client had.
+#[derive(Serialize)]
+#[serde(tag = "type", rename = "trades")]
+struct TradesMessage<'a> {
+    market: &'a Market,
+    snapshot: bool,
+    trades: &'a [Trade],
+}
+
+/// Keeps the recent trades of every market subscribed to.
+#[derive(Debug, Default)]
+pub struct TradesFolder {
+    recent: BTreeMap<Market, VecDeque<Trade>>,
+}
+
+impl TradesFolder {
+    pub fn reset(&mut self) {
+        self.recent.clear();
+    }
+
+    pub fn forget(&mut self, market: &Market) {
+        let _ = self.recent.remove(market);
+    }
+
+    /// The recent trades of a market, if it has seen its subscription yet.
+    pub fn snapshot(&self, market: &Market) -> Option<TradesUpdate> {
+        let recent = self.recent.get(market)?;
+        Some(TradesUpdate {
+            market: market.clone(),
+            recent: recent.iter().cloned().collect(),
+        })
+    }
+
+    /// Folds the message into the recent trades, returning them if they changed.
+    pub fn consume(&mut self, msg: TradesIncomingMessages) -> anyhow::Result<Option<TradesUpdate>> {
+        let market = match msg {
+            TradesIncomingMessages::Subscribed(subscribed) => {
+                let mut trades: VecDeque<Trade> = subscribed
+                    .contents
+                    .trades
+                    .into_iter()
+                    .map(Trade::from)
+                    .collect();
+                trades.truncate(RECENT_TRADES);
+                let _ = self.recent.insert(subscribed.market.clone(), trades);
+                subscribed.market
+            }
+            TradesIncomingMessages::ChannelData(data) => return self.consume_batch(data.into()),
+            TradesIncomingMessages::ChannelBatchData(batch) => return self.consume_batch(batch),
+            TradesIncomingMessages::Unsubscribed(unsubscribed) => {
+                self.forget(&unsubscribed.market);
+                return Ok(None);
+            }
+        };
+        Ok(self.snapshot(&market))
+    }
+
+    fn consume_batch(
+        &mut self,
+        batch: upstream_types::TradesChannelBatchData,
+    ) -> anyhow::Result<Option<TradesUpdate>> {
+        let recent = self.recent.get_mut(&batch.market).context(format!(
+            "The trades of {:?} have not seen a snapshot yet, got new trades",
+            batch.market
+        ))?;
+        let mut changed = false;
+        for piece in batch.contents {
+            for trade in piece.trades.into_iter().rev() {
+                recent.push_front(trade.into());
+                changed = true;
+            }
+        }
+        recent.truncate(RECENT_TRADES);
+        if !changed {
+            return Ok(None);
+        }
+        Ok(self.snapshot(&batch.market))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use crate::upstream_types::IncomingMessage;
+
+    use super::*;
+
+    fn consume(folder: &mut TradesFolder, frame: &str) -> Option<TradesUpdate> {
+        let Ok(IncomingMessage::Trades(message)) = IncomingMessage::from_str(frame) else {
+            panic!("expected a trades message");
+        };
+        folder.consume(message).unwrap()
+    }
+
+    fn ids(trades: &[Trade]) -> Vec<&str> {
+        trades.iter().map(|trade| trade.id.as_str()).collect()
+    }
+
+    #[test]
+    fn test_fold_trades() {
+        let mut folder = TradesFolder::default();
+        let batch = r#"{"type":"channel_batch_data","connection_id":"c","message_id":2,"id":"ETH-USD","channel":"v4_trades","version":"2.1.0","contents":[{"trades":[{"id":"c","size":"1","price":"3051","side":"BUY","createdAt":"2024-05-01T13:30:01Z"}]},{"trades":[{"id":"e","size":"1","price":"3052","side":"SELL","createdAt":"2024-05-01T13:30:02Z"},{"id":"d","size":"1","price":"3052","side":"SELL","createdAt":"2024-05-01T13:30:02Z"}]}]}"#;
+        let Ok(IncomingMessage::Trades(message)) = IncomingMessage::from_str(batch) else {
+            panic!("expected a trades message");
+        };
+        assert!(folder.consume(message).is_err());
+
+        let first = consume(
+            &mut folder,
+            r#"{"type":"subscribed","connection_id":"c","message_id":1,"channel":"v4_trades","id":"ETH-USD","contents":{"trades":[{"id":"b","size":"0.5","price":"3050","side":"BUY","createdAt":"2024-05-01T13:30:00Z"},{"id":"a","size":"2","price":"3049","side":"SELL","createdAt":"2024-05-01T13:29:58Z","type":"LIQUIDATED"}]}}"#,
+        )
+        .unwrap();
+        assert_eq!(ids(&first.recent), ["b", "a"]);
+        assert!(first.recent[1].liquidation);
+        assert_eq!(
+            first.json_since(None).unwrap(),
+            r#"{"type":"trades","market":"ETH-USD","snapshot":true,"trades":[{"id":"b","side":"buy","size":"0.5","price":"3050","created_at":"2024-05-01T13:30:00Z","liquidation":false},{"id":"a","side":"sell","size":"2","price":"3049","created_at":"2024-05-01T13:29:58Z","liquidation":true}]}"#
+        );
+
+        let second = consume(&mut folder, batch).unwrap();
+        assert_eq!(ids(&second.recent), ["e", "d", "c", "b", "a"]);
+        assert_eq!(ids(second.since(&first).unwrap()), ["e", "d", "c"]);
+        assert_eq!(second.json_since(Some(&second)), None);
+
+        // a client missing too many trades starts over
+        let mut last = second;
+        for seq in 3..(3 + RECENT_TRADES) {
+            let frame = format!(
+                r#"{{"type":"channel_data","connection_id":"c","message_id":{},"id":"ETH-USD","channel":"v4_trades","version":"2.1.0","contents":{{"trades":[{{"id":"{}","size":"1","price":"3050","side":"BUY","createdAt":"2024-05-01T13:31:00Z"}}]}}}}"#,
+                seq, seq
+            );
+            last = consume(&mut folder, &frame).unwrap();
+        }
+        assert_eq!(last.recent.len(), RECENT_TRADES);
+        assert!(last.since(&first).is_none());
+        assert!(last
+            .json_since(Some(&first))
+            .unwrap()
+            .contains(r#""snapshot":true"#));
+        assert_eq!(last.recent[0].price, Decimal::from_str("3050").unwrap());
+    }
+}
@@ -1,8 +1,17 @@
-use std::collections::BTreeMap;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+    time::Duration,
+};
 
 use anyhow::Context;
-use tokio::net::TcpStream;
-use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+use rand::Rng;
+use tokio::{net::TcpStream, sync::mpsc};
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{protocol::CloseFrame, Message as WsMessage},
+    MaybeTlsStream, WebSocketStream,
+};
 
 use futures_util::{
     stream::{SplitSink, SplitStream},
@@ -11,144 +20,922 @@ use futures_util::{
 
 // const NETWORK_ID: &str = "dydx-testnet-4";
 use crate::{
-    core_types::OrderBookState,
-    upstream_types::{self, Market},
+    candles::CandlesFolder,
+    config::{CrossedBookPolicy, Network},
+    core_types::{BookChanges, BookUpdate, MarketEvent, OrderBookState},
+    market_state::MarketStateFolder,
+    metrics::METRICS,
+    recorder::Recorder,
+    subaccounts::SubaccountFolder,
+    trades::TradesFolder,
+    upstream_types::{self, Block, IncomingMessage, Market, Topic},
 };
 
-const TESTNET_INDEXER_WS_HOST: &str = "wss://dydx-testnet.imperator.co/v4/ws";
-const PROD_INDEXER_WS_HOST: &str = "wss://indexer.dydx.trade/v4/ws";
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+const STREAM_BUFFER: usize = 64;
+
+type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+type WsRead = SplitStream<WsStream>;
+type WsWrite = SplitSink<WsStream, WsMessage>;
+
+/// Why the upstream could not deliver, either for one market or for all of them.
+#[derive(Debug, Clone, PartialEq)]
+pub enum UpstreamError {
+    /// The websocket could not be opened.
+    Connect(String),
+    /// The indexer did not greet us with a `connected` message.
+    Handshake(String),
+    /// The indexer sent something we do not understand.
+    Protocol(String),
+    /// A topic no longer follows the indexer, it is being refetched.
+    Desync { topic: Topic, reason: String },
+    /// The indexer refused or ended the subscription of a topic, it is dropped.
+    Rejected { topic: Topic, reason: String },
+    /// The connection went away, gracefully or not.
+    Closed(String),
+}
+
+impl UpstreamError {
+    /// The topic affected, `None` if it is the whole connection.
+    pub fn topic(&self) -> Option<&Topic> {
+        match self {
+            UpstreamError::Desync { topic, .. } | UpstreamError::Rejected { topic, .. } => {
+                Some(topic)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for UpstreamError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UpstreamError::Connect(e) => write!(f, "Connecting to the indexer failed: {}", e),
+            UpstreamError::Handshake(e) => write!(f, "Indexer handshake failed: {}", e),
+            UpstreamError::Protocol(e) => write!(f, "Unexpected message from the indexer: {}", e),
+            UpstreamError::Desync { topic, reason } => {
+                write!(f, "The {} are out of sync: {}", topic, reason)
+            }
+            UpstreamError::Rejected { topic, reason } => {
+                write!(f, "The indexer dropped the {}: {}", topic, reason)
+            }
+            UpstreamError::Closed(e) => write!(f, "Indexer connection closed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for UpstreamError {}
+
+fn describe_close(frame: Option<CloseFrame>) -> String {
+    match frame {
+        Some(frame) => format!("close frame {} '{}'", u16::from(frame.code), frame.reason),
+        None => "close frame without a reason".to_string(),
+    }
+}
 
 async fn recv_connected_msg(
-    read: &mut SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>,
-) -> anyhow::Result<upstream_types::Connected> {
-    let got = read.next().await;
-    let msg = match got {
-        None => Err(anyhow::anyhow!(
-            "Upstream connection got closed gracefully before receiving the Connected message"
-        )),
-        Some(Err(e)) => Err(anyhow::anyhow!(e).context(
-            "Upstream connection got closed abruptly before receiving the Connected message",
-        )),
-        Some(Ok(msg)) => Ok(msg),
-    }?;
-    let text = match msg {
-        tokio_tungstenite::tungstenite::Message::Text(t) => t,
-        _ => unimplemented!("Unknown incoming ws message type: {:?}", msg),
-    };
-    let connected: upstream_types::Connected =
-        serde_json::from_str(&text).context("'connected' message must be valid")?;
-    eprintln!("Connected to dydx!");
-    Ok(connected)
-}
-
-async fn send_subscribe_msg(
-    write: &mut SplitSink<
-        WebSocketStream<MaybeTlsStream<TcpStream>>,
-        tokio_tungstenite::tungstenite::Message,
-    >,
-    market: &upstream_types::Market,
-) -> anyhow::Result<()> {
-    let subscribe = upstream_types::Subscribe::new_for_market(market);
-    let subscribe_json = serde_json::to_string(&subscribe)?;
+    read: &mut WsRead,
+    recorder: Option<&Recorder>,
+) -> Result<upstream_types::Connected, UpstreamError> {
+    loop {
+        let text = match read.next().await {
+            None => {
+                return Err(UpstreamError::Handshake(
+                    "the connection got closed before the connected message".to_string(),
+                ))
+            }
+            Some(Err(e)) => return Err(UpstreamError::Handshake(e.to_string())),
+            Some(Ok(WsMessage::Text(text))) => text,
+            Some(Ok(WsMessage::Close(frame))) => {
+                return Err(UpstreamError::Handshake(describe_close(frame)))
+            }
+            Some(Ok(WsMessage::Binary(_))) => {
+                return Err(UpstreamError::Handshake(
+                    "got a binary frame instead of the connected message".to_string(),
+                ))
+            }
+            // Pings are answered by tungstenite itself
+            Some(Ok(WsMessage::Ping(_) | WsMessage::Pong(_) | WsMessage::Frame(_))) => continue,
+        };
+        if let Some(recorder) = recorder {
+            recorder.record(&text);
+        }
+        let connected: upstream_types::Connected = serde_json::from_str(&text)
+            .map_err(|e| UpstreamError::Handshake(format!("invalid connected message: {}", e)))?;
+        eprintln!("Connected to dydx!");
+        return Ok(connected);
+    }
+}
+
+async fn send_subscribe_msg(write: &mut WsWrite, topic: &Topic) -> Result<(), UpstreamError> {
+    let subscribe = upstream_types::Subscribe::new(topic);
+    let subscribe_json = serde_json::to_string(&subscribe).expect("subscribe should serialize");
     write
-        .send(tokio_tungstenite::tungstenite::Message::Text(
-            subscribe_json,
-        ))
-        .await?;
-    eprintln!(
-        "Subscribed to market: {}",
-        serde_json::to_string(&market).unwrap()
-    );
+        .send(WsMessage::Text(subscribe_json))
+        .await
+        .map_err(|e| UpstreamError::Closed(e.to_string()))?;
+    eprintln!("Subscribed to {}", topic);
     Ok(())
 }
 
-#[derive(Debug, Default)]
+async fn send_unsubscribe_msg(write: &mut WsWrite, topic: &Topic) -> Result<(), UpstreamError> {
+    let unsubscribe = upstream_types::Unsubscribe::new(topic);
+    let unsubscribe_json =
+        serde_json::to_string(&unsubscribe).expect("unsubscribe should serialize");
+    write
+        .send(WsMessage::Text(unsubscribe_json))
+        .await
+        .map_err(|e| UpstreamError::Closed(e.to_string()))?;
+    eprintln!("Unsubscribed from {}", topic);
+    Ok(())
+}
+
+async fn connect_and_subscribe(
+    ws_url: &str,
+    topics: &BTreeSet<Topic>,
+    recorder: Option<&Recorder>,
+) -> Result<(WsWrite, WsRead, upstream_types::Connected), UpstreamError> {
+    let (stream, _) = connect_async(ws_url)
+        .await
+        .map_err(|e| UpstreamError::Connect(format!("{}: {}", ws_url, e)))?;
+
+    let (mut write, mut read) = stream.split();
+
+    let connected = recv_connected_msg(&mut read, recorder).await?;
+    for topic in topics.iter() {
+        send_subscribe_msg(&mut write, topic).await?;
+    }
+    Ok((write, read, connected))
+}
+
+/// Exponential backoff with jitter, every delay is picked uniformly between
+/// half and the whole of the current ceiling.
+struct Backoff {
+    ceiling: Duration,
+}
+
+impl Backoff {
+    fn new() -> Self {
+        Self {
+            ceiling: INITIAL_BACKOFF,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.ceiling = INITIAL_BACKOFF;
+    }
+
+    fn next_delay(&mut self) -> Duration {
+        let delay = rand::thread_rng().gen_range(self.ceiling / 2..=self.ceiling);
+        self.ceiling = (self.ceiling * 2).min(MAX_BACKOFF);
+        delay
+    }
+}
+
+#[derive(Debug)]
+pub enum Command {
+    Subscribe(Topic),
+    Unsubscribe(Topic),
+}
+
+/// Changes the set of topics the supervised upstream connection is subscribed to.
+#[derive(Clone, Debug)]
+pub struct UpstreamHandle {
+    commands: mpsc::UnboundedSender<Command>,
+}
+
+impl UpstreamHandle {
+    pub fn subscribe(&self, topic: Topic) {
+        let _ = self.commands.send(Command::Subscribe(topic));
+    }
+
+    pub fn unsubscribe(&self, topic: Topic) {
+        let _ = self.commands.send(Command::Unsubscribe(topic));
+    }
+}
+
+enum SessionEnd {
+    /// The downstream side dropped the stream, nobody is listening anymore.
+    ConsumerGone,
+    /// The upstream connection is unusable and has to be reestablished.
+    Upstream(UpstreamError),
+}
+
+/// Owns the upstream connection and everything that has to survive a reconnect.
+struct Supervisor {
+    ws_url: String,
+    /// Names the upstream in metrics.
+    network: String,
+    topics: BTreeSet<Topic>,
+    /// Subscribed topics still waiting for their `subscribed` snapshot, any
+    /// delta for them predates the snapshot and is dropped.
+    pending: BTreeSet<Topic>,
+    /// The `message_id` of the last message on the current connection, they
+    /// are consecutive across all topics.
+    last_message_id: usize,
+    folder: OrderBookFolder,
+    trades: TradesFolder,
+    candles: CandlesFolder,
+    markets: MarketStateFolder,
+    subaccounts: SubaccountFolder,
+    /// Gets every text frame received, before it is parsed.
+    recorder: Option<Recorder>,
+    backoff: Backoff,
+    commands: mpsc::UnboundedReceiver<Command>,
+    tx: mpsc::Sender<Result<MarketEvent, UpstreamError>>,
+}
+
+impl Supervisor {
+    /// Keeps an upstream connection alive for as long as someone reads from `tx`.
+    async fn run(mut self) {
+        loop {
+            let (mut write, mut read, connected) =
+                match connect_and_subscribe(&self.ws_url, &self.topics, self.recorder.as_ref())
+                    .await
+                {
+                    Ok(connection) => connection,
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        METRICS
+                            .upstream_reconnects
+                            .with_label_values(&[&self.network])
+                            .inc();
+                        if self.report(e).await && self.wait_before_reconnect().await {
+                            continue;
+                        }
+                        return;
+                    }
+                };
+            self.last_message_id = connected.message_id;
+            self.pending = self.topics.clone();
+            self.folder.begin_connection(&connected.connection_id);
+            self.trades.reset();
+            self.candles.reset();
+            self.markets.reset();
+            self.subaccounts.reset();
+            METRICS
+                .upstream_connections
+                .with_label_values(&[&self.network])
+                .inc();
+            let connected = METRICS
+                .upstream_connected
+                .with_label_values(&[&self.network]);
+            connected.set(1);
+            let end = self.run_session(&mut write, &mut read).await;
+            connected.set(0);
+            match end {
+                SessionEnd::ConsumerGone => {
+                    let _ = write.close().await;
+                    return;
+                }
+                SessionEnd::Upstream(e) => {
+                    eprintln!("Upstream session ended: {}", e);
+                    METRICS
+                        .upstream_reconnects
+                        .with_label_values(&[&self.network])
+                        .inc();
+                    self.folder.reset();
+                    self.trades.reset();
+                    self.candles.reset();
+                    self.markets.reset();
+                    self.subaccounts.reset();
+                    let _ = write.close().await;
+                    if !self.report(e).await {
+                        return;
+                    }
+                }
+            }
+            if !self.wait_before_reconnect().await {
+                return;
+            }
+        }
+    }
+
+    /// Sleeps for the next backoff delay while still keeping track of the
+    /// wanted topics. Returns false if nobody is listening anymore.
+    async fn wait_before_reconnect(&mut self) -> bool {
+        let delay = self.backoff.next_delay();
+        eprintln!("Reconnecting to dydx in {:?}", delay);
+        let sleep = tokio::time::sleep(delay);
+        tokio::pin!(sleep);
+        loop {
+            tokio::select! {
+                _ = &mut sleep => return true,
+                _ = self.tx.closed() => return false,
+                command = self.commands.recv() => match command {
+                    None => return false,
+                    Some(Command::Subscribe(topic)) => {
+                        if let Topic::Orderbook(_) = topic {
+                            let _ = self.topics.insert(Topic::BlockHeight);
+                        }
+                        let _ = self.topics.insert(topic);
+                    }
+                    Some(Command::Unsubscribe(topic)) => {
+                        let _ = self.topics.remove(&topic);
+                        if !self.follows_books() {
+                            let _ = self.topics.remove(&Topic::BlockHeight);
+                        }
+                    }
+                },
+            }
+        }
+    }
+
+    /// Pumps frames of one upstream connection into `tx` until either side goes away.
+    async fn run_session(&mut self, write: &mut WsWrite, read: &mut WsRead) -> SessionEnd {
+        loop {
+            let frame = tokio::select! {
+                frame = read.next() => frame,
+                command = self.commands.recv() => {
+                    let applied = match command {
+                        None => return SessionEnd::ConsumerGone,
+                        Some(command) => self.apply_command(write, command).await,
+                    };
+                    if let Err(e) = applied {
+                        return SessionEnd::Upstream(e);
+                    }
+                    continue;
+                }
+                _ = self.tx.closed() => return SessionEnd::ConsumerGone,
+            };
+            let payload_json = match frame {
+                None => {
+                    return SessionEnd::Upstream(UpstreamError::Closed(
+                        "the stream ended".to_string(),
+                    ))
+                }
+                Some(Err(e)) => return SessionEnd::Upstream(UpstreamError::Closed(e.to_string())),
+                Some(Ok(WsMessage::Text(t))) => t,
+                Some(Ok(WsMessage::Close(frame))) => {
+                    return SessionEnd::Upstream(UpstreamError::Closed(describe_close(frame)))
+                }
+                Some(Ok(WsMessage::Binary(_))) => {
+                    return SessionEnd::Upstream(UpstreamError::Protocol(
+                        "got a binary frame".to_string(),
+                    ))
+                }
+                // Pings are answered by tungstenite itself
+                Some(Ok(WsMessage::Ping(_) | WsMessage::Pong(_) | WsMessage::Frame(_))) => continue,
+            };
+            if let Some(recorder) = &self.recorder {
+                recorder.record(&payload_json);
+            }
+            let message = match payload_json.parse::<IncomingMessage>() {
+                Ok(message) => message,
+                Err(e) => {
+                    METRICS
+                        .parse_failures
+                        .with_label_values(&[&self.network])
+                        .inc();
+                    return SessionEnd::Upstream(UpstreamError::Protocol(format!(
+                        "invalid message: {}",
+                        e
+                    )));
+                }
+            };
+            if let Err(end) = self.handle_message(write, message).await {
+                return end;
+            }
+        }
+    }
+
+    /// Folds one message of the connection and passes on what it changed.
+    ///
+    /// The indexer numbers its messages per connection rather than per
+    /// subscription, so a skipped `message_id` cannot be pinned on a market:
+    /// every topic with state that may have missed an update is resynced.
+    /// A delta that does not apply to its book does name its market, and
+    /// only that one is resynced.
+    async fn handle_message(
+        &mut self,
+        write: &mut WsWrite,
+        message: IncomingMessage,
+    ) -> Result<(), SessionEnd> {
+        let message_id = message.message_id();
+        if message_id != self.last_message_id + 1 {
+            let reason = format!(
+                "Expected message {} but got {}",
+                self.last_message_id + 1,
+                message_id
+            );
+            // a lost block is simply replaced by the next one
+            let topics = self.topics.clone();
+            for topic in topics.into_iter().filter(|t| *t != Topic::BlockHeight) {
+                self.resync(write, topic, &reason).await?;
+            }
+        }
+        self.last_message_id = message_id;
+
+        let topic = match (&message, message.topic()) {
+            (_, Some(topic)) => topic,
+            (IncomingMessage::Error(error), None) => {
+                // not about a subscription, e.g. a rate limit, there is nobody to tell
+                eprintln!("The indexer reported an error: {}", error.message);
+                return Ok(());
+            }
+            (_, None) => {
+                return Err(SessionEnd::Upstream(UpstreamError::Protocol(
+                    "got a second connected message".to_string(),
+                )))
+            }
+        };
+        METRICS
+            .upstream_messages
+            .with_label_values(&[&self.network, topic.market().map_or("", Market::as_str)])
+            .inc();
+        if !self.topics.contains(&topic) {
+            // Leftovers of a topic we have already unsubscribed from
+            return Ok(());
+        }
+        if let IncomingMessage::Error(error) = &message {
+            return self.reject(write, topic, &error.message).await;
+        }
+        // our own unsubscribes only happen while resyncing, when the topic is pending
+        if message.is_unsubscribed() && !self.pending.contains(&topic) {
+            return self
+                .reject(write, topic, "The indexer ended the subscription")
+                .await;
+        }
+        if self.pending.contains(&topic) {
+            if !message.is_subscribed() {
+                return Ok(());
+            }
+            let _ = self.pending.remove(&topic);
+        }
+        let folded = match message {
+            IncomingMessage::Orderbook(message) => self
+                .folder
+                .consume_orderbook_incoming_msg(message)
+                .map(|update| update.map(|update| MarketEvent::Book(Arc::new(update)))),
+            IncomingMessage::Trades(message) => self
+                .trades
+                .consume(message)
+                .map(|update| update.map(|update| MarketEvent::Trades(Arc::new(update)))),
+            IncomingMessage::Candles(message) => self
+                .candles
+                .consume(message)
+                .map(|update| update.map(|update| MarketEvent::Candles(Arc::new(update)))),
+            IncomingMessage::Markets(message) => self
+                .markets
+                .consume(message)
+                .map(|update| update.map(|update| MarketEvent::Markets(Arc::new(update)))),
+            IncomingMessage::Subaccounts(message) => self
+                .subaccounts
+                .consume(message)
+                .map(|update| update.map(|update| MarketEvent::Subaccount(Arc::new(update)))),
+            IncomingMessage::BlockHeight(message) => {
+                self.folder.consume_block_height_msg(message);
+                Ok(None)
+            }
+            IncomingMessage::Connected(_) | IncomingMessage::Error(_) => Ok(None),
+        };
+        let event = match folded {
+            Ok(Some(event)) => event,
+            Ok(None) => return Ok(()),
+            Err(e) => return self.resync(write, topic, &format!("{:#}", e)).await,
+        };
+        self.backoff.reset();
+        self.emit(event).await
+    }
+
+    fn forget(&mut self, topic: &Topic) {
+        match topic {
+            Topic::Orderbook(market) => self.folder.forget(market),
+            Topic::Trades(market) => self.trades.forget(market),
+            Topic::Candles(id) => self.candles.forget(id),
+            Topic::Markets => self.markets.reset(),
+            Topic::Subaccount(id) => self.subaccounts.forget(id),
+            // the last block stays the latest known until a newer one comes
+            Topic::BlockHeight => {}
+        }
+    }
+
+    /// Refetches the snapshot of a single topic, which is found out of sync.
+    async fn resync(
+        &mut self,
+        write: &mut WsWrite,
+        topic: Topic,
+        reason: &str,
+    ) -> Result<(), SessionEnd> {
+        eprintln!("Resyncing {}: {}", topic, reason);
+        self.forget(&topic);
+        let _ = self.pending.insert(topic.clone());
+        send_unsubscribe_msg(write, &topic)
+            .await
+            .map_err(SessionEnd::Upstream)?;
+        send_subscribe_msg(write, &topic)
+            .await
+            .map_err(SessionEnd::Upstream)?;
+        let desync = UpstreamError::Desync {
+            topic,
+            reason: reason.to_string(),
+        };
+        if self.report(desync).await {
+            Ok(())
+        } else {
+            Err(SessionEnd::ConsumerGone)
+        }
+    }
+
+    /// Drops a topic the indexer will not serve, reporting why. The blocks
+    /// go with the last book, like when it is unsubscribed from.
+    async fn reject(
+        &mut self,
+        write: &mut WsWrite,
+        topic: Topic,
+        reason: &str,
+    ) -> Result<(), SessionEnd> {
+        eprintln!("Dropping {}: {}", topic, reason);
+        let _ = self.topics.remove(&topic);
+        let _ = self.pending.remove(&topic);
+        self.forget(&topic);
+        if !self.follows_books() {
+            self.unsubscribe(write, Topic::BlockHeight)
+                .await
+                .map_err(SessionEnd::Upstream)?;
+        }
+        let rejected = UpstreamError::Rejected {
+            topic,
+            reason: reason.to_string(),
+        };
+        if self.report(rejected).await {
+            Ok(())
+        } else {
+            Err(SessionEnd::ConsumerGone)
+        }
+    }
+
+    async fn emit(&mut self, event: MarketEvent) -> Result<(), SessionEnd> {
+        self.tx
+            .send(Ok(event))
+            .await
+            .map_err(|_| SessionEnd::ConsumerGone)
+    }
+
+    /// Passes the error on to the consumer, returns false if nobody is listening.
+    async fn report(&mut self, error: UpstreamError) -> bool {
+        self.tx.send(Err(error)).await.is_ok()
+    }
+
+    /// Changes the subscriptions of the connection. The blocks of the chain
+    /// are followed for as long as there are books to tag with them, and
+    /// subscribed to before the first book so that it already has one.
+    async fn apply_command(
+        &mut self,
+        write: &mut WsWrite,
+        command: Command,
+    ) -> Result<(), UpstreamError> {
+        match command {
+            Command::Subscribe(topic) => {
+                if let Topic::Orderbook(_) = topic {
+                    self.subscribe(write, Topic::BlockHeight).await?;
+                }
+                self.subscribe(write, topic).await?;
+            }
+            Command::Unsubscribe(topic) => {
+                self.unsubscribe(write, topic).await?;
+                if !self.follows_books() {
+                    self.unsubscribe(write, Topic::BlockHeight).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn subscribe(&mut self, write: &mut WsWrite, topic: Topic) -> Result<(), UpstreamError> {
+        if !self.topics.contains(&topic) {
+            send_subscribe_msg(write, &topic).await?;
+            let _ = self.pending.insert(topic.clone());
+            let _ = self.topics.insert(topic);
+        }
+        Ok(())
+    }
+
+    async fn unsubscribe(
+        &mut self,
+        write: &mut WsWrite,
+        topic: Topic,
+    ) -> Result<(), UpstreamError> {
+        if self.topics.remove(&topic) {
+            self.forget(&topic);
+            let _ = self.pending.remove(&topic);
+            send_unsubscribe_msg(write, &topic).await?;
+        }
+        Ok(())
+    }
+
+    fn follows_books(&self) -> bool {
+        self.topics
+            .iter()
+            .any(|topic| matches!(topic, Topic::Orderbook(_)))
+    }
+}
+
+#[derive(Debug)]
 pub struct OrderBookFolder {
     orderbooks: BTreeMap<Market, OrderBookState>,
+    /// The upstream connection the books are folded from.
+    connection_id: Arc<str>,
+    /// The latest block of the chain, kept across connections as it is only
+    /// ever replaced by a newer one.
+    block: Option<Block>,
+    /// Names the upstream in metrics.
+    network: String,
+    crossed_book_policy: CrossedBookPolicy,
 }
 
 impl OrderBookFolder {
+    pub fn new(network: &str, crossed_book_policy: CrossedBookPolicy) -> Self {
+        Self {
+            orderbooks: BTreeMap::new(),
+            connection_id: "".into(),
+            block: None,
+            network: network.to_string(),
+            crossed_book_policy,
+        }
+    }
+
+    /// Forgets every orderbook, so that the next message for any market has to
+    /// be a fresh snapshot.
+    pub fn reset(&mut self) {
+        self.orderbooks.clear();
+    }
+
+    /// Starts over for a new upstream connection.
+    pub fn begin_connection(&mut self, connection_id: &str) {
+        self.reset();
+        self.connection_id = connection_id.into();
+    }
+
+    pub fn forget(&mut self, market: &Market) {
+        let _ = self.orderbooks.remove(market);
+    }
+
+    /// Tags the updates that follow with the newest block of the message.
+    pub fn consume_block_height_msg(&mut self, msg: upstream_types::BlockHeightIncomingMessages) {
+        if let Some(block) = msg.into_block() {
+            self.block = Some(block);
+        }
+    }
+
+    /// Where an update of the current connection and block came from.
+    fn stamp(&self, update: BookUpdate) -> BookUpdate {
+        update
+            .on_connection(self.connection_id.clone())
+            .at_block(self.block.clone())
+    }
+
+    /// The current book of a market as a snapshot, if there is one.
+    pub fn snapshot(&self, market: &Market) -> Option<BookUpdate> {
+        let orderbook = self.orderbooks.get(market)?;
+        Some(self.stamp(BookUpdate::snapshot(orderbook.clone())))
+    }
+
     pub fn consume_subscribed_msg(
         &mut self,
         subscribed: upstream_types::Subscribed,
-    ) -> anyhow::Result<String> {
+    ) -> anyhow::Result<BookUpdate> {
         let market = subscribed.market.clone();
-        let orderbook = <upstream_types::Subscribed as Into<OrderBookState>>::into(subscribed);
-        let serialized = serde_json::to_string(&orderbook)?;
+        let mut orderbook = <upstream_types::Subscribed as Into<OrderBookState>>::into(subscribed);
+        // resnapshotting a crossed snapshot would only bring the same one back
+        let policy = match self.crossed_book_policy {
+            CrossedBookPolicy::Resync => CrossedBookPolicy::Flag,
+            policy => policy,
+        };
+        Self::handle_crossing(&self.network, &mut orderbook, false, policy, None)?;
+        let update = self.stamp(BookUpdate::snapshot(orderbook.clone()));
         let _ = self.orderbooks.insert(market, orderbook);
-        Ok(serialized)
+        Ok(update)
     }
 
     pub fn consume_channel_batch_msg(
         &mut self,
         batch: upstream_types::ChannelBatchData,
-    ) -> anyhow::Result<String> {
+    ) -> anyhow::Result<BookUpdate> {
         let orderbook = self.orderbooks.get_mut(&batch.market).context(format!(
             "The orderbook for {:?} has not seen a snapshot yet, got delta update",
             batch.market
         ))?;
-        batch
-            .update_orderbook(orderbook)
-            .context("updating orderbook in consume_channel_batch_msg")?;
-        serde_json::to_string(&orderbook).context("Serializing orderbook has failed")
+        let prev_seq = orderbook.epoch();
+        let was_crossed = orderbook.crossing().is_some();
+        let mut changes = match batch.update_orderbook(orderbook) {
+            Ok(changes) => changes,
+            Err(e) => {
+                METRICS
+                    .epoch_errors
+                    .with_label_values(&[&self.network, orderbook.market.as_str()])
+                    .inc();
+                return Err(e.context("updating orderbook in consume_channel_batch_msg"));
+            }
+        };
+        let policy = self.crossed_book_policy;
+        Self::handle_crossing(
+            &self.network,
+            orderbook,
+            was_crossed,
+            policy,
+            Some(&mut changes),
+        )?;
+        let update = BookUpdate::delta(orderbook.clone(), prev_seq, changes);
+        Ok(self.stamp(update))
     }
 
+    /// Applies the crossed book policy to a freshly updated book, counting
+    /// every time it goes from healthy to crossed or locked. Levels trimmed
+    /// away are added to `changes`, so that deltas remove them as well.
+    fn handle_crossing(
+        network: &str,
+        orderbook: &mut OrderBookState,
+        was_crossed: bool,
+        policy: CrossedBookPolicy,
+        changes: Option<&mut BookChanges>,
+    ) -> anyhow::Result<()> {
+        orderbook.crossed = None;
+        let Some(crossing) = orderbook.crossing() else {
+            return Ok(());
+        };
+        if !was_crossed {
+            METRICS
+                .crossed_books
+                .with_label_values(&[network, orderbook.market.as_str(), crossing.kind.as_str()])
+                .inc();
+        }
+        match policy {
+            CrossedBookPolicy::PassThrough => {}
+            CrossedBookPolicy::Flag => orderbook.crossed = Some(crossing),
+            CrossedBookPolicy::Trim => {
+                let removed = orderbook.trim_crossed();
+                if let Some(changes) = changes {
+                    changes.extend(removed);
+                }
+                // whatever could not be trimmed is still worth flagging
+                orderbook.crossed = orderbook.crossing();
+            }
+            CrossedBookPolicy::Resync => anyhow::bail!(
+                "The book is {} with bid {} and ask {}",
+                crossing.kind.as_str(),
+                crossing.best_bid,
+                crossing.best_ask
+            ),
+        }
+        Ok(())
+    }
+
+    /// Folds the message into the orderbooks, returning the update of the
+    /// affected market if its book has changed.
     pub fn consume_orderbook_incoming_msg(
         &mut self,
         msg: upstream_types::OrderbookIncomingMessages,
-    ) -> anyhow::Result<String> {
+    ) -> anyhow::Result<Option<BookUpdate>> {
         match msg {
             upstream_types::OrderbookIncomingMessages::ChannelBatchData(batch) => {
-                self.consume_channel_batch_msg(batch)
+                self.consume_channel_batch_msg(batch).map(Some)
             }
             upstream_types::OrderbookIncomingMessages::Subscribed(subscribed) => {
-                self.consume_subscribed_msg(subscribed)
+                self.consume_subscribed_msg(subscribed).map(Some)
+            }
+            upstream_types::OrderbookIncomingMessages::ChannelData(data) => {
+                self.consume_channel_batch_msg(data.into()).map(Some)
+            }
+            upstream_types::OrderbookIncomingMessages::Unsubscribed(unsubscribed) => {
+                self.forget(&unsubscribed.market);
+                Ok(None)
             }
         }
     }
 }
 
+/// Reads folded orderbook updates from a supervised upstream connection.
+///
+/// The connection lives in a background task which reconnects with jittered
+/// exponential backoff whenever the indexer drops us, redoing the handshake and
+/// resubscribing to every market. Per-market state is reset on every reconnect,
+/// so the first items after a reconnect are fresh snapshots.
+///
+/// A market whose book is found out of sync, because of a skipped
+/// `message_id` or a delta that does not apply, is resubscribed on its own and
+/// reported as `UpstreamError::Desync` until its new snapshot arrives. Other
+/// errors concern the whole connection, which is reestablished after them.
 pub struct OrderBookStream {
-    read: SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>,
-    folder: OrderBookFolder,
+    rx: mpsc::Receiver<Result<MarketEvent, UpstreamError>>,
 }
 
-impl OrderBookStream {
-    pub async fn subscribe(markets: &[Market]) -> anyhow::Result<Self> {
-        let (stream, _) = connect_async(PROD_INDEXER_WS_HOST)
-            .await
-            .context("Failed to connect")?;
+/// The other end of an `UpstreamHandle` and an `OrderBookStream`, for
+/// whatever produces the books.
+pub struct UpstreamFeed {
+    pub commands: mpsc::UnboundedReceiver<Command>,
+    pub tx: mpsc::Sender<Result<MarketEvent, UpstreamError>>,
+}
 
-        let (mut write, mut read) = stream.split();
+impl OrderBookStream {
+    pub fn channel() -> (UpstreamHandle, Self, UpstreamFeed) {
+        let (commands_tx, commands) = mpsc::unbounded_channel();
+        let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+        (
+            UpstreamHandle {
+                commands: commands_tx,
+            },
+            Self { rx },
+            UpstreamFeed { commands, tx },
+        )
+    }
 
-        let _ = recv_connected_msg(&mut read).await?;
-        for m in markets.iter() {
-            send_subscribe_msg(&mut write, m).await?;
-        }
-        Ok(Self {
-            read,
-            folder: OrderBookFolder::default(),
-        })
+    /// Starts the supervised connection without any markets, they are added
+    /// and removed through the returned handle.
+    pub fn spawn(
+        network: &Network,
+        crossed_book_policy: CrossedBookPolicy,
+        recorder: Option<Recorder>,
+    ) -> (UpstreamHandle, Self) {
+        let (handle, stream, UpstreamFeed { commands, tx }) = Self::channel();
+        let supervisor = Supervisor {
+            ws_url: network.ws_url.clone(),
+            network: network.name.clone(),
+            topics: BTreeSet::new(),
+            pending: BTreeSet::new(),
+            last_message_id: 0,
+            folder: OrderBookFolder::new(&network.name, crossed_book_policy),
+            trades: TradesFolder::default(),
+            candles: CandlesFolder::default(),
+            markets: MarketStateFolder::default(),
+            subaccounts: SubaccountFolder::default(),
+            recorder,
+            backoff: Backoff::new(),
+            commands,
+            tx,
+        };
+        tokio::spawn(supervisor.run());
+        (handle, stream)
     }
 }
 
 impl Stream for OrderBookStream {
-    type Item = anyhow::Result<String>;
+    type Item = Result<MarketEvent, UpstreamError>;
 
     fn poll_next(
         mut self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Option<Self::Item>> {
-        let frame = futures_util::ready!(self.read.poll_next_unpin(cx))
-            .context("The stream should be unending")?;
-        let payload_json = match frame {
-            Err(e) => unimplemented!("received error during orderbook updates: {}", e),
-            Ok(tokio_tungstenite::tungstenite::Message::Text(t)) => t,
-            Ok(_) => unimplemented!("received nontext message during orderbook updates"),
+        self.rx.poll_recv(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    fn consume(folder: &mut OrderBookFolder, frame: &str) -> anyhow::Result<Option<BookUpdate>> {
+        let Ok(IncomingMessage::Orderbook(message)) = IncomingMessage::from_str(frame) else {
+            panic!("expected an orderbook message");
+        };
+        folder.consume_orderbook_incoming_msg(message)
+    }
+
+    #[test]
+    fn test_deltas_need_a_book_to_apply_to() {
+        let mut folder = OrderBookFolder::new("test", CrossedBookPolicy::PassThrough);
+        folder.begin_connection("c");
+        let batch = |message_id: usize, market: &str| {
+            format!(
+                r#"{{"type":"channel_batch_data","connection_id":"c","message_id":{},"id":"{}","channel":"v4_orderbook","version":"1.0.0","contents":[{{"bids":[["99","2"]]}}]}}"#,
+                message_id, market
+            )
         };
-        let message: upstream_types::OrderbookIncomingMessages =
-            serde_json::from_str(&payload_json)?;
-        let snapshot: String = self.folder.consume_orderbook_incoming_msg(message)?;
-        std::task::Poll::Ready(Some(Ok(snapshot)))
+        // before the snapshot there is nothing to apply it to
+        assert!(consume(&mut folder, &batch(1, "ETH-USD")).is_err());
+
+        let subscribed = r#"{"type":"subscribed","connection_id":"c","message_id":2,"channel":"v4_orderbook","id":"ETH-USD","contents":{"bids":[{"price":"99","size":"1"}],"asks":[{"price":"101","size":"1"}]}}"#;
+        let snapshot = consume(&mut folder, subscribed).unwrap().unwrap();
+        assert_eq!(snapshot.seq, 2);
+        let delta = consume(&mut folder, &batch(3, "ETH-USD")).unwrap().unwrap();
+        assert!(delta.follows(2));
+        assert_eq!(delta.book.best_bid(), Some((99.into(), 2.into())));
+
+        // the other market has not seen its snapshot, this one is unaffected
+        assert!(consume(&mut folder, &batch(4, "BTC-USD")).is_err());
+        assert!(folder
+            .snapshot(&Market::from_str("ETH-USD").unwrap())
+            .is_some());
+
+        // a resync starts the market over
+        folder.forget(&Market::from_str("ETH-USD").unwrap());
+        assert!(consume(&mut folder, &batch(5, "ETH-USD")).is_err());
+    }
+
+    #[test]
+    fn test_backoff_grows_with_jitter_and_caps() {
+        let mut backoff = Backoff::new();
+        let mut ceiling = INITIAL_BACKOFF;
+        for _ in 0..16 {
+            let delay = backoff.next_delay();
+            assert!(delay >= ceiling / 2 && delay <= ceiling);
+            ceiling = (ceiling * 2).min(MAX_BACKOFF);
+        }
+        assert_eq!(backoff.ceiling, MAX_BACKOFF);
+        backoff.reset();
+        assert!(backoff.next_delay() <= INITIAL_BACKOFF);
     }
 }
@@ -1,3 +1,5 @@
+use std::{collections::BTreeMap, str::FromStr};
+
 use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
 
@@ -6,147 +8,381 @@ use crate::core_types::{self, OrderBookState};
 #[derive(Deserialize, Debug, PartialEq)]
 #[serde(tag = "type")]
 pub struct Connected {
-    connection_id: String,
-    message_id: usize,
+    pub connection_id: String,
+    pub message_id: usize,
 }
 
-#[derive(Deserialize, Serialize, Debug, PartialEq)]
+const MAX_TICKER_LEN: usize = 32;
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Hash)]
 pub enum SocketChannel {
     #[serde(rename = "v4_orderbook")]
     Orderbook,
+    #[serde(rename = "v4_trades")]
+    Trades,
+    #[serde(rename = "v4_candles")]
+    Candles,
+    #[serde(rename = "v4_markets")]
+    Markets,
+    #[serde(rename = "v4_subaccounts")]
+    Subaccounts,
+    #[serde(rename = "v4_block_height")]
+    BlockHeight,
+}
+
+impl SocketChannel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SocketChannel::Orderbook => "v4_orderbook",
+            SocketChannel::Trades => "v4_trades",
+            SocketChannel::Candles => "v4_candles",
+            SocketChannel::Markets => "v4_markets",
+            SocketChannel::Subaccounts => "v4_subaccounts",
+            SocketChannel::BlockHeight => "v4_block_height",
+        }
+    }
+}
+
+/// A market ticker like `ETH-USD`.
+///
+/// Only the shape of the ticker is validated here, whether the indexer actually
+/// lists the market is up to the `MarketRegistry`.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone, Eq, PartialOrd, Ord, Hash)]
+#[serde(try_from = "String", into = "String")]
+pub struct Market(String);
+
+impl Market {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl TryFrom<String> for Market {
+    type Error = String;
+
+    fn try_from(ticker: String) -> Result<Self, Self::Error> {
+        let well_formed = ticker.len() <= MAX_TICKER_LEN
+            && ticker.split('-').count() >= 2
+            && ticker.split('-').all(|part| {
+                !part.is_empty()
+                    && part
+                        .chars()
+                        .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit())
+            });
+        if well_formed {
+            Ok(Self(ticker))
+        } else {
+            Err(format!("'{}' is not a valid market ticker", ticker))
+        }
+    }
+}
+
+impl FromStr for Market {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::try_from(s.to_string())
+    }
 }
 
-#[allow(clippy::enum_variant_names)]
-#[derive(Deserialize, Serialize, Debug, PartialEq, Clone, Eq, PartialOrd, Ord)]
-pub enum Market {
-    #[serde(rename = "AAVE-USD")]
-    AaveUsd,
-    #[serde(rename = "ADA-USD")]
-    AdaUsd,
-    #[serde(rename = "AEVO-USD")]
-    AevoUsd,
-    #[serde(rename = "AGIX-USD")]
-    AgixUsd,
-    #[serde(rename = "ALGO-USD")]
-    AlgoUsd,
-    #[serde(rename = "APE-USD")]
-    ApeUsd,
-    #[serde(rename = "APT-USD")]
-    AptUsd,
-    #[serde(rename = "ARB-USD")]
-    ArbUsd,
-    #[serde(rename = "ARKM-USD")]
-    ArkmUsd,
-    #[serde(rename = "ASTR-USD")]
-    AstrUsd,
-    #[serde(rename = "ATOM-USD")]
-    AtomUsd,
-    #[serde(rename = "AVAX-USD")]
-    AvaxUsd,
-    #[serde(rename = "AXL-USD")]
-    AxlUsd,
-    #[serde(rename = "BCH-USD")]
-    BchUsd,
-    #[serde(rename = "BONK-USD")]
-    BonkUsd,
-    #[serde(rename = "BLUR-USD")]
-    BlurUsd,
-    #[serde(rename = "BNB-USD")]
-    BnbUsd,
-    #[serde(rename = "CHZ-USD")]
-    ChzUsd,
-    #[serde(rename = "BTC-USD")]
-    BtcUsd,
-    #[serde(rename = "COMP-USD")]
-    CompUsd,
-    #[serde(rename = "CRV-USD")]
-    CrvUsd,
-    #[serde(rename = "DOGE-USD")]
-    DogeUsd,
-    #[serde(rename = "DOT-USD")]
-    DotUsd,
-    #[serde(rename = "DYM-USD")]
-    DymUsd,
-    #[serde(rename = "EOS-USD")]
-    EosUsd,
-    #[serde(rename = "ETC-USD")]
-    EtcUsd,
-    #[serde(rename = "ETH-USD")]
-    EthUsd,
-    #[serde(rename = "FET-USD")]
-    FetUsd,
-    #[serde(rename = "FIL-USD")]
-    FilUsd,
-    #[serde(rename = "GRT-USD")]
-    GrtUsd,
-    #[serde(rename = "HBAR-USD")]
-    HbarUsd,
-    #[serde(rename = "ICP-USD")]
-    IcpUsd,
-    #[serde(rename = "IMX-USD")]
-    ImxUsd,
-    #[serde(rename = "INJ-USD")]
-    InjUsd,
-    #[serde(rename = "JTO-USD")]
-    JtoUsd,
-    #[serde(rename = "JUP-USD")]
-    JupUsd,
-    #[serde(rename = "LDO-USD")]
-    LdoUsd,
-    #[serde(rename = "LINK-USD")]
-    LinkUsd,
-    #[serde(rename = "LTC-USD")]
-    LtcUsd,
-    #[serde(rename = "MANA-USD")]
-    ManaUsd,
-    #[serde(rename = "MATIC-USD")]
-    MaticUsd,
-    #[serde(rename = "MKR-USD")]
-    MkrUsd,
-    #[serde(rename = "NEAR-USD")]
-    NearUsd,
-    #[serde(rename = "ORDI-USD")]
-    OrdiUsd,
-    #[serde(rename = "OP-USD")]
-    OpUsd,
-    #[serde(rename = "PEPE-USD")]
-    PepeUsd,
-    #[serde(rename = "PYTH-USD")]
-    PythUsd,
-    #[serde(rename = "RNDR-USD")]
-    RndrUsd,
-    #[serde(rename = "RUNE-USD")]
-    RuneUsd,
-    #[serde(rename = "SEI-USD")]
-    SeiUsd,
-    #[serde(rename = "SHIB-USD")]
-    ShibUsd,
-    #[serde(rename = "SNX-USD")]
-    SnxUsd,
-    #[serde(rename = "SOL-USD")]
-    SolUsd,
-    #[serde(rename = "STRK-USD")]
-    StrkUsd,
-    #[serde(rename = "STX-USD")]
-    StxUsd,
-    #[serde(rename = "SUI-USD")]
-    SuiUsd,
-    #[serde(rename = "TIA-USD")]
-    TiaUsd,
-    #[serde(rename = "TRX-USD")]
-    TrxUsd,
-    #[serde(rename = "UNI-USD")]
-    UniUsd,
-    #[serde(rename = "WLD-USD")]
-    WldUsd,
-    #[serde(rename = "WOO-USD")]
-    WooUsd,
-    #[serde(rename = "XLM-USD")]
-    XlmUsd,
-    #[serde(rename = "XRP-USD")]
-    XrpUsd,
-    #[serde(rename = "DYDX-USD")]
-    DydxUsd,
+impl From<Market> for String {
+    fn from(market: Market) -> Self {
+        market.0
+    }
+}
+
+impl std::fmt::Display for Market {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// What a subscription of the indexer websocket is to, e.g. the book of a
+/// market. Every topic is subscribed at most once per connection.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Topic {
+    Orderbook(Market),
+    Trades(Market),
+    Candles(CandlesId),
+    /// Every market at once, so without an id.
+    Markets,
+    Subaccount(SubaccountId),
+    /// The chain's blocks, followed for as long as the connection lasts.
+    BlockHeight,
+}
+
+impl Topic {
+    pub fn channel(&self) -> SocketChannel {
+        match self {
+            Topic::Orderbook(_) => SocketChannel::Orderbook,
+            Topic::Trades(_) => SocketChannel::Trades,
+            Topic::Candles(_) => SocketChannel::Candles,
+            Topic::Markets => SocketChannel::Markets,
+            Topic::Subaccount(_) => SocketChannel::Subaccounts,
+            Topic::BlockHeight => SocketChannel::BlockHeight,
+        }
+    }
+
+    /// The `id` of the subscription, `None` for the markets and blocks.
+    pub fn id(&self) -> Option<String> {
+        match self {
+            Topic::Orderbook(market) | Topic::Trades(market) => Some(market.to_string()),
+            Topic::Candles(id) => Some(id.to_string()),
+            Topic::Markets | Topic::BlockHeight => None,
+            Topic::Subaccount(id) => Some(id.to_string()),
+        }
+    }
+
+    /// The market the topic is about, `None` if it is about all of them.
+    pub fn market(&self) -> Option<&Market> {
+        match self {
+            Topic::Orderbook(market) | Topic::Trades(market) => Some(market),
+            Topic::Candles(id) => Some(&id.market),
+            Topic::Markets | Topic::Subaccount(_) | Topic::BlockHeight => None,
+        }
+    }
+
+    /// The topic of a subscription as the indexer names it, if it is one we know.
+    pub fn new(channel: SocketChannel, id: Option<&str>) -> Option<Self> {
+        Some(match channel {
+            SocketChannel::Orderbook => Topic::Orderbook(Market::from_str(id?).ok()?),
+            SocketChannel::Trades => Topic::Trades(Market::from_str(id?).ok()?),
+            SocketChannel::Candles => Topic::Candles(CandlesId::from_str(id?).ok()?),
+            SocketChannel::Markets => Topic::Markets,
+            SocketChannel::Subaccounts => Topic::Subaccount(SubaccountId::from_str(id?).ok()?),
+            SocketChannel::BlockHeight => Topic::BlockHeight,
+        })
+    }
+}
+
+impl std::fmt::Display for Topic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Topic::Orderbook(market) => write!(f, "{} orderbook", market),
+            Topic::Trades(market) => write!(f, "{} trades", market),
+            Topic::Candles(id) => write!(f, "{} {} candles", id.market, id.resolution),
+            Topic::Markets => write!(f, "markets"),
+            Topic::Subaccount(id) => write!(f, "subaccount {}", id),
+            Topic::BlockHeight => write!(f, "block height"),
+        }
+    }
+}
+
+/// How long a candle lasts.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Hash)]
+pub enum CandleResolution {
+    #[serde(rename = "1MIN")]
+    OneMinute,
+    #[serde(rename = "5MINS")]
+    FiveMinutes,
+    #[serde(rename = "15MINS")]
+    FifteenMinutes,
+    #[serde(rename = "30MINS")]
+    ThirtyMinutes,
+    #[serde(rename = "1HOUR")]
+    OneHour,
+    #[serde(rename = "4HOURS")]
+    FourHours,
+    #[serde(rename = "1DAY")]
+    OneDay,
+}
+
+impl CandleResolution {
+    pub const ALL: [CandleResolution; 7] = [
+        CandleResolution::OneMinute,
+        CandleResolution::FiveMinutes,
+        CandleResolution::FifteenMinutes,
+        CandleResolution::ThirtyMinutes,
+        CandleResolution::OneHour,
+        CandleResolution::FourHours,
+        CandleResolution::OneDay,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CandleResolution::OneMinute => "1MIN",
+            CandleResolution::FiveMinutes => "5MINS",
+            CandleResolution::FifteenMinutes => "15MINS",
+            CandleResolution::ThirtyMinutes => "30MINS",
+            CandleResolution::OneHour => "1HOUR",
+            CandleResolution::FourHours => "4HOURS",
+            CandleResolution::OneDay => "1DAY",
+        }
+    }
+}
+
+impl FromStr for CandleResolution {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|resolution| resolution.as_str() == s)
+            .ok_or_else(|| format!("Unknown candle resolution: {}", s))
+    }
+}
+
+impl std::fmt::Display for CandleResolution {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The id of a `v4_candles` subscription, `MARKET/RESOLUTION` like `ETH-USD/1MIN`.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(try_from = "String", into = "String")]
+pub struct CandlesId {
+    pub market: Market,
+    pub resolution: CandleResolution,
+}
+
+impl TryFrom<String> for CandlesId {
+    type Error = String;
+
+    fn try_from(id: String) -> Result<Self, Self::Error> {
+        id.parse()
+    }
+}
+
+impl FromStr for CandlesId {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (market, resolution) = s
+            .split_once('/')
+            .ok_or_else(|| format!("Invalid candles id: {}", s))?;
+        Ok(Self {
+            market: market.parse()?,
+            resolution: resolution.parse()?,
+        })
+    }
+}
+
+impl From<CandlesId> for String {
+    fn from(id: CandlesId) -> Self {
+        id.to_string()
+    }
+}
+
+impl std::fmt::Display for CandlesId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.market, self.resolution)
+    }
+}
+
+/// The id of a `v4_subaccounts` subscription, `ADDRESS/NUMBER` like
+/// `dydx1qx2.../0`.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(try_from = "String", into = "String")]
+pub struct SubaccountId {
+    pub address: String,
+    pub number: u32,
+}
+
+impl TryFrom<String> for SubaccountId {
+    type Error = String;
+
+    fn try_from(id: String) -> Result<Self, Self::Error> {
+        id.parse()
+    }
+}
+
+impl FromStr for SubaccountId {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid subaccount: {}", s);
+        let (address, number) = s.split_once('/').ok_or_else(invalid)?;
+        // bech32, so lowercase letters and digits only
+        let valid_address = !address.is_empty()
+            && address.len() <= 90
+            && address
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit());
+        if !valid_address {
+            return Err(invalid());
+        }
+        Ok(Self {
+            address: address.to_string(),
+            number: number.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+impl From<SubaccountId> for String {
+    fn from(id: SubaccountId) -> Self {
+        id.to_string()
+    }
+}
+
+impl std::fmt::Display for SubaccountId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.address, self.number)
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum MarketStatus {
+    Active,
+    Paused,
+    CancelOnly,
+    PostOnly,
+    Initializing,
+    FinalSettlement,
+    #[serde(other)]
+    Unknown,
+}
+
+/// A market as listed by the indexer's `/perpetualMarkets` endpoint.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PerpetualMarket {
+    pub ticker: Market,
+    pub status: MarketStatus,
+    /// Unknown for the markets of a replay, recordings carry no sizes.
+    pub tick_size: Option<Decimal>,
+    pub step_size: Option<Decimal>,
+    pub oracle_price: Option<Decimal>,
+    pub initial_margin_fraction: Option<Decimal>,
+    pub maintenance_margin_fraction: Option<Decimal>,
+    pub next_funding_rate: Option<Decimal>,
+    pub open_interest: Option<Decimal>,
+    #[serde(rename = "volume24H")]
+    pub volume_24h: Option<Decimal>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PerpetualMarkets {
+    #[serde(deserialize_with = "valid_markets")]
+    pub markets: BTreeMap<Market, PerpetualMarket>,
+}
+
+/// Reads a map keyed by ticker, skipping the entries that are not valid
+/// markets rather than failing on them, as the indexer may list anything.
+fn valid_markets<'de, D, T>(deserializer: D) -> Result<BTreeMap<Market, T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: serde::de::DeserializeOwned,
+{
+    let entries = BTreeMap::<String, serde_json::Value>::deserialize(deserializer)?;
+    let mut markets = BTreeMap::new();
+    for (ticker, value) in entries {
+        let market = match Market::from_str(&ticker) {
+            Ok(market) => market,
+            Err(e) => {
+                eprintln!("Skipping market: {}", e);
+                continue;
+            }
+        };
+        match serde_json::from_value(value) {
+            Ok(value) => {
+                let _ = markets.insert(market, value);
+            }
+            Err(e) => eprintln!("Skipping market {}: {}", market, e),
+        }
+    }
+    Ok(markets)
 }
 
 #[derive(Deserialize, Debug, PartialEq)]
@@ -161,25 +397,15 @@ pub struct ContentPiece {
     pub bids: Option<Vec<Offer>>,
 }
 
-#[derive(Deserialize, Debug)]
-#[serde(untagged)]
-pub enum Contents {
-    Multi(Vec<ContentPiece>),
-    Single(ContentPiece),
-}
-
 #[derive(Deserialize, Debug)]
 #[serde(tag = "type", rename_all = "snake_case", rename = "subscribed")]
 pub struct Subscribed {
-    pub connection_id: String,
     pub message_id: usize,
-    pub channel: SocketChannel,
     #[serde(rename = "id")]
     pub market: Market,
     pub contents: ContentPiece,
 }
 
-// #[allow(clippy::from_over_into)]
 impl From<Subscribed> for OrderBookState {
     fn from(val: Subscribed) -> Self {
         let asks: Option<Vec<core_types::Offer>> = val.contents.asks.map(|v4asks| {
@@ -211,16 +437,17 @@ impl From<Subscribed> for OrderBookState {
 
 #[derive(Deserialize, Debug)]
 pub struct ChannelBatchData {
-    pub connection_id: String,
     pub message_id: usize,
-    pub channel: SocketChannel,
     #[serde(rename = "id")]
     pub market: Market,
     pub contents: Vec<ContentPiece>,
 }
 
 impl ChannelBatchData {
-    pub fn update_orderbook(self, orderbook: &mut OrderBookState) -> anyhow::Result<()> {
+    pub fn update_orderbook(
+        self,
+        orderbook: &mut OrderBookState,
+    ) -> anyhow::Result<core_types::BookChanges> {
         let mut asks: Vec<core_types::Offer> = Vec::default();
         let mut bids: Vec<core_types::Offer> = Vec::default();
         for piece in self.contents {
@@ -241,46 +468,846 @@ impl ChannelBatchData {
     }
 }
 
+/// An unbatched update, as sent for subscriptions with `batched: false`.
+#[derive(Deserialize, Debug)]
+pub struct ChannelData {
+    pub message_id: usize,
+    #[serde(rename = "id")]
+    pub market: Market,
+    pub contents: ContentPiece,
+}
+
+impl From<ChannelData> for ChannelBatchData {
+    fn from(data: ChannelData) -> Self {
+        Self {
+            message_id: data.message_id,
+            market: data.market,
+            contents: vec![data.contents],
+        }
+    }
+}
+
+/// Something the indexer did not like, e.g. a subscription to an unknown
+/// market or too many messages. `channel` and `id` are only there when the
+/// error concerns a subscription.
+#[derive(Deserialize, Debug)]
+pub struct ErrorMessage {
+    pub message_id: usize,
+    pub message: String,
+    pub channel: Option<SocketChannel>,
+    pub id: Option<String>,
+}
+
+impl ErrorMessage {
+    /// The subscription the error is about, if any.
+    pub fn topic(&self) -> Option<Topic> {
+        Topic::new(self.channel?, self.id.as_deref())
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Unsubscribed {
+    pub message_id: usize,
+    #[serde(rename = "id")]
+    pub market: Market,
+}
+
 #[derive(Deserialize, Debug)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum OrderbookIncomingMessages {
     Subscribed(Subscribed),
-    // Error,
-    // ChannelData,
+    ChannelData(ChannelData),
     ChannelBatchData(ChannelBatchData),
-    // PING
+    Unsubscribed(Unsubscribed),
+}
+
+impl OrderbookIncomingMessages {
+    pub fn market(&self) -> &Market {
+        match self {
+            OrderbookIncomingMessages::Subscribed(subscribed) => &subscribed.market,
+            OrderbookIncomingMessages::ChannelData(data) => &data.market,
+            OrderbookIncomingMessages::ChannelBatchData(batch) => &batch.market,
+            OrderbookIncomingMessages::Unsubscribed(unsubscribed) => &unsubscribed.market,
+        }
+    }
+
+    pub fn message_id(&self) -> usize {
+        match self {
+            OrderbookIncomingMessages::Subscribed(subscribed) => subscribed.message_id,
+            OrderbookIncomingMessages::ChannelData(data) => data.message_id,
+            OrderbookIncomingMessages::ChannelBatchData(batch) => batch.message_id,
+            OrderbookIncomingMessages::Unsubscribed(unsubscribed) => unsubscribed.message_id,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum OrderSide {
+    Buy,
+    Sell,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum TradeType {
+    Limit,
+    Market,
+    Liquidated,
+    Deleveraged,
+    #[serde(other)]
+    Unknown,
+}
+
+/// A trade of the `v4_trades` channel, the side being the taker's.
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Trade {
+    pub id: String,
+    pub side: OrderSide,
+    pub size: Decimal,
+    pub price: Decimal,
+    /// An RFC 3339 timestamp like `2024-05-01T13:30:00.123Z`.
+    pub created_at: String,
+    #[serde(rename = "type")]
+    pub trade_type: Option<TradeType>,
+    /// Only sent by older indexers, newer ones have a `LIQUIDATED` type instead.
+    #[serde(default)]
+    pub liquidation: bool,
+}
+
+impl Trade {
+    pub fn is_liquidation(&self) -> bool {
+        self.liquidation || self.trade_type == Some(TradeType::Liquidated)
+    }
+}
+
+/// Trades, the newest first.
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct TradesContents {
+    pub trades: Vec<Trade>,
+}
+
+/// The recent trades of a market, as they are when subscribing.
+#[derive(Deserialize, Debug)]
+pub struct TradesSubscribed {
+    pub message_id: usize,
+    #[serde(rename = "id")]
+    pub market: Market,
+    pub contents: TradesContents,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TradesChannelBatchData {
+    pub message_id: usize,
+    #[serde(rename = "id")]
+    pub market: Market,
+    /// Oldest first, unlike the trades in each of them.
+    pub contents: Vec<TradesContents>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TradesChannelData {
+    pub message_id: usize,
+    #[serde(rename = "id")]
+    pub market: Market,
+    pub contents: TradesContents,
+}
+
+impl From<TradesChannelData> for TradesChannelBatchData {
+    fn from(data: TradesChannelData) -> Self {
+        Self {
+            message_id: data.message_id,
+            market: data.market,
+            contents: vec![data.contents],
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TradesIncomingMessages {
+    Subscribed(TradesSubscribed),
+    ChannelData(TradesChannelData),
+    ChannelBatchData(TradesChannelBatchData),
+    Unsubscribed(Unsubscribed),
+}
+
+impl TradesIncomingMessages {
+    pub fn market(&self) -> &Market {
+        match self {
+            TradesIncomingMessages::Subscribed(subscribed) => &subscribed.market,
+            TradesIncomingMessages::ChannelData(data) => &data.market,
+            TradesIncomingMessages::ChannelBatchData(batch) => &batch.market,
+            TradesIncomingMessages::Unsubscribed(unsubscribed) => &unsubscribed.market,
+        }
+    }
+
+    pub fn message_id(&self) -> usize {
+        match self {
+            TradesIncomingMessages::Subscribed(subscribed) => subscribed.message_id,
+            TradesIncomingMessages::ChannelData(data) => data.message_id,
+            TradesIncomingMessages::ChannelBatchData(batch) => batch.message_id,
+            TradesIncomingMessages::Unsubscribed(unsubscribed) => unsubscribed.message_id,
+        }
+    }
+}
+
+/// A candle of the `v4_candles` channel. Sent to clients as it is, in snake case.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
+#[serde(rename_all(deserialize = "camelCase", serialize = "snake_case"))]
+pub struct Candle {
+    /// An RFC 3339 timestamp like `2024-05-01T13:30:00.000Z`.
+    pub started_at: String,
+    pub open: Decimal,
+    pub high: Decimal,
+    pub low: Decimal,
+    pub close: Decimal,
+    pub base_token_volume: Decimal,
+    pub usd_volume: Decimal,
+    pub trades: u64,
+    pub starting_open_interest: Decimal,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub orderbook_mid_price_open: Option<Decimal>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub orderbook_mid_price_close: Option<Decimal>,
+}
+
+/// Candles, the newest first.
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct CandlesContents {
+    pub candles: Vec<Candle>,
+}
+
+/// The recent candles of a market, as they are when subscribing.
+#[derive(Deserialize, Debug)]
+pub struct CandlesSubscribed {
+    pub message_id: usize,
+    pub id: CandlesId,
+    pub contents: CandlesContents,
+}
+
+/// Updates of the current candle, or the first of a new one.
+#[derive(Deserialize, Debug)]
+pub struct CandlesChannelBatchData {
+    pub message_id: usize,
+    pub id: CandlesId,
+    /// Oldest first.
+    pub contents: Vec<Candle>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CandlesChannelData {
+    pub message_id: usize,
+    pub id: CandlesId,
+    pub contents: Candle,
+}
+
+impl From<CandlesChannelData> for CandlesChannelBatchData {
+    fn from(data: CandlesChannelData) -> Self {
+        Self {
+            message_id: data.message_id,
+            id: data.id,
+            contents: vec![data.contents],
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CandlesUnsubscribed {
+    pub message_id: usize,
+    pub id: CandlesId,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CandlesIncomingMessages {
+    Subscribed(CandlesSubscribed),
+    ChannelData(CandlesChannelData),
+    ChannelBatchData(CandlesChannelBatchData),
+    Unsubscribed(CandlesUnsubscribed),
+}
+
+impl CandlesIncomingMessages {
+    pub fn id(&self) -> &CandlesId {
+        match self {
+            CandlesIncomingMessages::Subscribed(subscribed) => &subscribed.id,
+            CandlesIncomingMessages::ChannelData(data) => &data.id,
+            CandlesIncomingMessages::ChannelBatchData(batch) => &batch.id,
+            CandlesIncomingMessages::Unsubscribed(unsubscribed) => &unsubscribed.id,
+        }
+    }
+
+    pub fn message_id(&self) -> usize {
+        match self {
+            CandlesIncomingMessages::Subscribed(subscribed) => subscribed.message_id,
+            CandlesIncomingMessages::ChannelData(data) => data.message_id,
+            CandlesIncomingMessages::ChannelBatchData(batch) => batch.message_id,
+            CandlesIncomingMessages::Unsubscribed(unsubscribed) => unsubscribed.message_id,
+        }
+    }
+}
+
+/// The markets as they are when subscribing, like the `/perpetualMarkets` endpoint has them.
+#[derive(Deserialize, Debug)]
+pub struct MarketsSubscribed {
+    pub message_id: usize,
+    pub contents: PerpetualMarkets,
+}
+
+/// What changed of a market's trading, only the fields that did are there.
+#[derive(Deserialize, Debug, PartialEq, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct TradingUpdate {
+    pub status: Option<MarketStatus>,
+    pub oracle_price: Option<Decimal>,
+    pub next_funding_rate: Option<Decimal>,
+    pub open_interest: Option<Decimal>,
+    #[serde(rename = "volume24H")]
+    pub volume_24h: Option<Decimal>,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct OraclePriceUpdate {
+    pub oracle_price: Decimal,
+}
+
+/// Updates of some of the markets, either of their trading or of their
+/// oracle prices.
+#[derive(Deserialize, Debug, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct MarketsContents {
+    #[serde(default, deserialize_with = "valid_markets")]
+    pub trading: BTreeMap<Market, TradingUpdate>,
+    #[serde(default, deserialize_with = "valid_markets")]
+    pub oracle_prices: BTreeMap<Market, OraclePriceUpdate>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct MarketsChannelBatchData {
+    pub message_id: usize,
+    /// Oldest first.
+    pub contents: Vec<MarketsContents>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct MarketsChannelData {
+    pub message_id: usize,
+    pub contents: MarketsContents,
+}
+
+impl From<MarketsChannelData> for MarketsChannelBatchData {
+    fn from(data: MarketsChannelData) -> Self {
+        Self {
+            message_id: data.message_id,
+            contents: vec![data.contents],
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct MarketsUnsubscribed {
+    pub message_id: usize,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MarketsIncomingMessages {
+    Subscribed(MarketsSubscribed),
+    ChannelData(MarketsChannelData),
+    ChannelBatchData(MarketsChannelBatchData),
+    Unsubscribed(MarketsUnsubscribed),
+}
+
+impl MarketsIncomingMessages {
+    pub fn message_id(&self) -> usize {
+        match self {
+            MarketsIncomingMessages::Subscribed(subscribed) => subscribed.message_id,
+            MarketsIncomingMessages::ChannelData(data) => data.message_id,
+            MarketsIncomingMessages::ChannelBatchData(batch) => batch.message_id,
+            MarketsIncomingMessages::Unsubscribed(unsubscribed) => unsubscribed.message_id,
+        }
+    }
+}
+
+/// A block of the chain, as far as the indexer has processed it.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone)]
+pub struct Block {
+    /// A decimal string, like every height the indexer sends. Named
+    /// `blockHeight` in updates but `height` when subscribing.
+    #[serde(alias = "blockHeight")]
+    pub height: String,
+    pub time: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct BlockHeightSubscribed {
+    pub message_id: usize,
+    pub contents: Block,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct BlockHeightChannelData {
+    pub message_id: usize,
+    pub contents: Block,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct BlockHeightChannelBatchData {
+    pub message_id: usize,
+    /// Oldest first.
+    pub contents: Vec<Block>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct BlockHeightUnsubscribed {
+    pub message_id: usize,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum BlockHeightIncomingMessages {
+    Subscribed(BlockHeightSubscribed),
+    ChannelData(BlockHeightChannelData),
+    ChannelBatchData(BlockHeightChannelBatchData),
+    Unsubscribed(BlockHeightUnsubscribed),
+}
+
+impl BlockHeightIncomingMessages {
+    pub fn message_id(&self) -> usize {
+        match self {
+            BlockHeightIncomingMessages::Subscribed(subscribed) => subscribed.message_id,
+            BlockHeightIncomingMessages::ChannelData(data) => data.message_id,
+            BlockHeightIncomingMessages::ChannelBatchData(batch) => batch.message_id,
+            BlockHeightIncomingMessages::Unsubscribed(unsubscribed) => unsubscribed.message_id,
+        }
+    }
+
+    /// The newest block the message tells of, if any.
+    pub fn into_block(self) -> Option<Block> {
+        match self {
+            BlockHeightIncomingMessages::Subscribed(subscribed) => Some(subscribed.contents),
+            BlockHeightIncomingMessages::ChannelData(data) => Some(data.contents),
+            BlockHeightIncomingMessages::ChannelBatchData(batch) => {
+                batch.contents.into_iter().last()
+            }
+            BlockHeightIncomingMessages::Unsubscribed(_) => None,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum PositionSide {
+    Long,
+    Short,
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum PositionStatus {
+    Open,
+    Closed,
+    Liquidated,
+    #[serde(other)]
+    Unknown,
+}
+
+/// A perpetual position of a subaccount. Sent to clients as it is, in snake case.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
+#[serde(rename_all(deserialize = "camelCase", serialize = "snake_case"))]
+pub struct PerpetualPosition {
+    pub market: Market,
+    pub status: PositionStatus,
+    pub side: PositionSide,
+    pub size: Decimal,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_size: Option<Decimal>,
+    pub entry_price: Decimal,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exit_price: Option<Decimal>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub realized_pnl: Option<Decimal>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unrealized_pnl: Option<Decimal>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub net_funding: Option<Decimal>,
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum OrderStatus {
+    Open,
+    Filled,
+    Canceled,
+    BestEffortCanceled,
+    BestEffortOpened,
+    Untriggered,
+    #[serde(other)]
+    Unknown,
+}
+
+impl OrderStatus {
+    /// Whether an order of this status rests on the book.
+    pub fn is_resting(self) -> bool {
+        matches!(self, OrderStatus::Open | OrderStatus::BestEffortOpened)
+    }
+}
+
+/// An order of a subaccount, in whatever state the last update left it.
+/// Sent to clients as it is, in snake case.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
+#[serde(rename_all(deserialize = "camelCase", serialize = "snake_case"))]
+pub struct SubaccountOrder {
+    pub id: String,
+    pub client_id: String,
+    pub ticker: Market,
+    pub side: OrderSide,
+    pub size: Decimal,
+    pub price: Decimal,
+    #[serde(default)]
+    pub total_filled: Decimal,
+    pub status: OrderStatus,
+    /// Like `LIMIT` or `STOP_LIMIT`.
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub order_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub time_in_force: Option<String>,
+    #[serde(default)]
+    pub post_only: bool,
+    #[serde(default)]
+    pub reduce_only: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<String>,
+}
+
+impl SubaccountOrder {
+    /// What is left of the order to be filled.
+    pub fn remaining(&self) -> Decimal {
+        (self.size - self.total_filled).max(Decimal::ZERO)
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Liquidity {
+    Taker,
+    Maker,
+}
+
+/// A fill of an order of a subaccount. Sent to clients as it is, in snake case.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
+#[serde(rename_all(deserialize = "camelCase", serialize = "snake_case"))]
+pub struct SubaccountFill {
+    pub id: String,
+    pub ticker: Market,
+    pub side: OrderSide,
+    pub liquidity: Liquidity,
+    /// Like `LIMIT` or `LIQUIDATED`.
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub fill_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub order_id: Option<String>,
+    pub size: Decimal,
+    pub price: Decimal,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fee: Option<Decimal>,
+    /// An RFC 3339 timestamp like `2024-05-01T13:30:00.123Z`.
+    pub created_at: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created_at_height: Option<String>,
+}
+
+/// One end of a transfer, a subaccount or an outside address.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
+#[serde(rename_all(deserialize = "camelCase", serialize = "snake_case"))]
+pub struct TransferAccount {
+    pub address: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subaccount_number: Option<u32>,
+}
+
+/// Funds moved in or out of a subaccount. Sent to clients as it is, in snake case.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
+#[serde(rename_all(deserialize = "camelCase", serialize = "snake_case"))]
+pub struct Transfer {
+    pub sender: TransferAccount,
+    pub recipient: TransferAccount,
+    /// The asset, like `USDC`.
+    pub symbol: String,
+    pub size: Decimal,
+    /// Like `DEPOSIT` or `TRANSFER_OUT`.
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub transfer_type: Option<String>,
+    pub transaction_hash: String,
+    pub created_at: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created_at_height: Option<String>,
+}
+
+/// The subaccount as it is when subscribing, of which only the open
+/// positions are kept.
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SubaccountSnapshot {
+    #[serde(default)]
+    pub open_perpetual_positions: BTreeMap<Market, PerpetualPosition>,
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct SubaccountSubscribedContents {
+    pub subaccount: SubaccountSnapshot,
+    /// The orders that are still open.
+    #[serde(default)]
+    pub orders: Vec<SubaccountOrder>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SubaccountsSubscribed {
+    pub message_id: usize,
+    pub id: SubaccountId,
+    pub contents: SubaccountSubscribedContents,
+}
+
+/// What changed of a subaccount, a transfer coming on its own.
+#[derive(Deserialize, Debug, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct SubaccountContents {
+    #[serde(default)]
+    pub perpetual_positions: Vec<PerpetualPosition>,
+    #[serde(default)]
+    pub orders: Vec<SubaccountOrder>,
+    #[serde(default)]
+    pub fills: Vec<SubaccountFill>,
+    pub transfers: Option<Transfer>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SubaccountsChannelBatchData {
+    pub message_id: usize,
+    pub id: SubaccountId,
+    /// Oldest first.
+    pub contents: Vec<SubaccountContents>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SubaccountsChannelData {
+    pub message_id: usize,
+    pub id: SubaccountId,
+    pub contents: SubaccountContents,
+}
+
+impl From<SubaccountsChannelData> for SubaccountsChannelBatchData {
+    fn from(data: SubaccountsChannelData) -> Self {
+        Self {
+            message_id: data.message_id,
+            id: data.id,
+            contents: vec![data.contents],
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SubaccountsUnsubscribed {
+    pub message_id: usize,
+    pub id: SubaccountId,
+}
+
+// only ever held while being folded
+#[allow(clippy::large_enum_variant)]
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SubaccountsIncomingMessages {
+    Subscribed(SubaccountsSubscribed),
+    ChannelData(SubaccountsChannelData),
+    ChannelBatchData(SubaccountsChannelBatchData),
+    Unsubscribed(SubaccountsUnsubscribed),
+}
+
+impl SubaccountsIncomingMessages {
+    pub fn id(&self) -> &SubaccountId {
+        match self {
+            SubaccountsIncomingMessages::Subscribed(subscribed) => &subscribed.id,
+            SubaccountsIncomingMessages::ChannelData(data) => &data.id,
+            SubaccountsIncomingMessages::ChannelBatchData(batch) => &batch.id,
+            SubaccountsIncomingMessages::Unsubscribed(unsubscribed) => &unsubscribed.id,
+        }
+    }
+
+    pub fn message_id(&self) -> usize {
+        match self {
+            SubaccountsIncomingMessages::Subscribed(subscribed) => subscribed.message_id,
+            SubaccountsIncomingMessages::ChannelData(data) => data.message_id,
+            SubaccountsIncomingMessages::ChannelBatchData(batch) => batch.message_id,
+            SubaccountsIncomingMessages::Unsubscribed(unsubscribed) => unsubscribed.message_id,
+        }
+    }
+}
+
+/// Any message of the indexer websocket. Messages of a subscription are told
+/// apart by their channel, which `serde` cannot do on its own.
+#[derive(Debug)]
+pub enum IncomingMessage {
+    Connected(Connected),
+    /// About a subscription, or about the whole connection.
+    Error(ErrorMessage),
+    Orderbook(OrderbookIncomingMessages),
+    Trades(TradesIncomingMessages),
+    Candles(CandlesIncomingMessages),
+    Markets(MarketsIncomingMessages),
+    Subaccounts(SubaccountsIncomingMessages),
+    BlockHeight(BlockHeightIncomingMessages),
+}
+
+impl FromStr for IncomingMessage {
+    type Err = serde_json::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        #[derive(Deserialize)]
+        #[serde(rename_all = "snake_case")]
+        enum Kind {
+            Connected,
+            Error,
+            #[serde(other)]
+            OfChannel,
+        }
+        #[derive(Deserialize)]
+        struct Envelope {
+            #[serde(rename = "type")]
+            kind: Kind,
+            channel: Option<SocketChannel>,
+        }
+        let envelope: Envelope = serde_json::from_str(s)?;
+        Ok(match (envelope.kind, envelope.channel) {
+            (Kind::Connected, _) => IncomingMessage::Connected(serde_json::from_str(s)?),
+            (Kind::Error, _) => IncomingMessage::Error(serde_json::from_str(s)?),
+            (Kind::OfChannel, Some(SocketChannel::Orderbook)) => {
+                IncomingMessage::Orderbook(serde_json::from_str(s)?)
+            }
+            (Kind::OfChannel, Some(SocketChannel::Trades)) => {
+                IncomingMessage::Trades(serde_json::from_str(s)?)
+            }
+            (Kind::OfChannel, Some(SocketChannel::Candles)) => {
+                IncomingMessage::Candles(serde_json::from_str(s)?)
+            }
+            (Kind::OfChannel, Some(SocketChannel::Markets)) => {
+                IncomingMessage::Markets(serde_json::from_str(s)?)
+            }
+            (Kind::OfChannel, Some(SocketChannel::Subaccounts)) => {
+                IncomingMessage::Subaccounts(serde_json::from_str(s)?)
+            }
+            (Kind::OfChannel, Some(SocketChannel::BlockHeight)) => {
+                IncomingMessage::BlockHeight(serde_json::from_str(s)?)
+            }
+            (Kind::OfChannel, None) => return Err(serde::de::Error::missing_field("channel")),
+        })
+    }
+}
+
+impl IncomingMessage {
+    pub fn message_id(&self) -> usize {
+        match self {
+            IncomingMessage::Connected(connected) => connected.message_id,
+            IncomingMessage::Error(error) => error.message_id,
+            IncomingMessage::Orderbook(message) => message.message_id(),
+            IncomingMessage::Trades(message) => message.message_id(),
+            IncomingMessage::Candles(message) => message.message_id(),
+            IncomingMessage::Markets(message) => message.message_id(),
+            IncomingMessage::Subaccounts(message) => message.message_id(),
+            IncomingMessage::BlockHeight(message) => message.message_id(),
+        }
+    }
+
+    /// The subscription the message is about, `None` for connection-wide messages.
+    pub fn topic(&self) -> Option<Topic> {
+        match self {
+            IncomingMessage::Connected(_) => None,
+            IncomingMessage::Error(error) => error.topic(),
+            IncomingMessage::Orderbook(message) => Some(Topic::Orderbook(message.market().clone())),
+            IncomingMessage::Trades(message) => Some(Topic::Trades(message.market().clone())),
+            IncomingMessage::Candles(message) => Some(Topic::Candles(message.id().clone())),
+            IncomingMessage::Markets(_) => Some(Topic::Markets),
+            IncomingMessage::Subaccounts(message) => Some(Topic::Subaccount(message.id().clone())),
+            IncomingMessage::BlockHeight(_) => Some(Topic::BlockHeight),
+        }
+    }
+
+    /// Whether the message starts a subscription with the current state of its topic.
+    pub fn is_subscribed(&self) -> bool {
+        matches!(
+            self,
+            IncomingMessage::Orderbook(OrderbookIncomingMessages::Subscribed(_))
+                | IncomingMessage::Trades(TradesIncomingMessages::Subscribed(_))
+                | IncomingMessage::Candles(CandlesIncomingMessages::Subscribed(_))
+                | IncomingMessage::Markets(MarketsIncomingMessages::Subscribed(_))
+                | IncomingMessage::Subaccounts(SubaccountsIncomingMessages::Subscribed(_))
+                | IncomingMessage::BlockHeight(BlockHeightIncomingMessages::Subscribed(_))
+        )
+    }
+
+    pub fn is_unsubscribed(&self) -> bool {
+        matches!(
+            self,
+            IncomingMessage::Orderbook(OrderbookIncomingMessages::Unsubscribed(_))
+                | IncomingMessage::Trades(TradesIncomingMessages::Unsubscribed(_))
+                | IncomingMessage::Candles(CandlesIncomingMessages::Unsubscribed(_))
+                | IncomingMessage::Markets(MarketsIncomingMessages::Unsubscribed(_))
+                | IncomingMessage::Subaccounts(SubaccountsIncomingMessages::Unsubscribed(_))
+                | IncomingMessage::BlockHeight(BlockHeightIncomingMessages::Unsubscribed(_))
+        )
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(tag = "type", rename_all = "snake_case", rename = "subscribe")]
 pub struct Subscribe {
     pub channel: SocketChannel,
-    #[serde(rename = "id")]
-    pub market: Market,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
     pub batched: bool,
 }
 
 impl Subscribe {
-    pub fn new_for_market(market: &Market) -> Self {
+    pub fn new(topic: &Topic) -> Self {
         Self {
-            channel: SocketChannel::Orderbook,
+            channel: topic.channel(),
+            id: topic.id(),
             batched: true,
-            market: market.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case", rename = "unsubscribe")]
+pub struct Unsubscribe {
+    pub channel: SocketChannel,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+}
+
+impl Unsubscribe {
+    pub fn new(topic: &Topic) -> Self {
+        Self {
+            channel: topic.channel(),
+            id: topic.id(),
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::str::FromStr;
-
     use super::*;
     use serde_json::from_str;
 
+    fn market(ticker: &str) -> Market {
+        Market::from_str(ticker).expect("valid ticker")
+    }
+
     #[test]
     fn test_parse_connected() {
         let incoming = r#"{"type":"connected","connection_id":"9a75aff4-923a-4f43-9197-81eefceaacd1","message_id":0}"#;
-        let connected: Connected = from_str(&incoming).expect("should be valid");
+        let connected: Connected = from_str(incoming).expect("should be valid");
         assert_eq!(
             connected.connection_id,
             String::from_str("9a75aff4-923a-4f43-9197-81eefceaacd1").unwrap()
@@ -292,13 +1319,8 @@ mod tests {
     fn test_parse_subscribed() {
         let incoming = r#"{"type":"subscribed","connection_id":"9a75aff4-923a-4f43-9197-81eefceaacd1","message_id":1,"channel":"v4_orderbook","id":"ETH-USD","contents":{"bids":[{"price":"3040.6","size":"0.658"},{"price":"3009.8","size":"6.645"},{"price":"3000","size":"0.006"},{"price":"2600","size":"0.007"},{"price":"2567","size":"0.03"},{"price":"2556","size":"0.029"},{"price":"2000","size":"0.015"},{"price":"1000","size":"0.12"},{"price":"356","size":"0.05"},{"price":"334.3","size":"0.002"},{"price":"332.4","size":"0.03"},{"price":"256","size":"0.136"},{"price":"33","size":"0.303"},{"price":"15","size":"11.196"}],"asks":[{"price":"3073.1","size":"0.022"},{"price":"3076.1","size":"0.022"},{"price":"3079.2","size":"0.022"},{"price":"3102.1","size":"0.645"},{"price":"3132.7","size":"6.384"},{"price":"3560","size":"0.009"}]}}"#;
         let subscribed: Subscribed = from_str(incoming).expect("should be valid");
-        assert_eq!(
-            subscribed.connection_id,
-            String::from_str("9a75aff4-923a-4f43-9197-81eefceaacd1").unwrap()
-        );
         assert_eq!(subscribed.message_id, 1);
-        assert_eq!(subscribed.channel, SocketChannel::Orderbook);
-        assert_eq!(subscribed.market, Market::EthUsd);
+        assert_eq!(subscribed.market, market("ETH-USD"));
         assert_eq!(subscribed.contents.asks.expect("asks exists").len(), 6);
         assert_eq!(subscribed.contents.bids.expect("bids exists").len(), 14);
     }
@@ -307,13 +1329,8 @@ mod tests {
     fn test_parse_channel_batch_data() {
         let incoming = r#"{"type":"channel_batch_data","connection_id":"9a75aff4-923a-4f43-9197-81eefceaacd1","message_id":2,"id":"ETH-USD","channel":"v4_orderbook","version":"1.0.0","contents":[{"asks":[["3102.1","0"]]},{"asks":[["3101.4","0.645"]]},{"bids":[["3040.6","0"]]},{"bids":[["3040","0.658"]]}]}"#;
         let subscribed: ChannelBatchData = from_str(incoming).expect("should be valid");
-        assert_eq!(
-            subscribed.connection_id,
-            String::from_str("9a75aff4-923a-4f43-9197-81eefceaacd1").unwrap()
-        );
         assert_eq!(subscribed.message_id, 2);
-        assert_eq!(subscribed.channel, SocketChannel::Orderbook);
-        assert_eq!(subscribed.market, Market::EthUsd);
+        assert_eq!(subscribed.market, market("ETH-USD"));
         assert_eq!(subscribed.contents.len(), 4);
         assert_eq!(
             subscribed.contents[3],
@@ -326,4 +1343,213 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn test_parse_incoming_messages() {
+        let incoming = r#"{"type":"subscribed","connection_id":"9a75aff4-923a-4f43-9197-81eefceaacd1","message_id":1,"channel":"v4_orderbook","id":"ETH-USD","contents":{"bids":[{"price":"3040.6","size":"0.658"}]}}"#;
+        let message = IncomingMessage::from_str(incoming).expect("should be valid");
+        assert!(message.is_subscribed());
+        assert_eq!(message.topic(), Some(Topic::Orderbook(market("ETH-USD"))));
+
+        let incoming = r#"{"type":"unsubscribed","connection_id":"9a75aff4-923a-4f43-9197-81eefceaacd1","message_id":7,"channel":"v4_orderbook","id":"BTC-USD"}"#;
+        let message = IncomingMessage::from_str(incoming).expect("should be valid");
+        assert!(message.is_unsubscribed());
+        assert_eq!(message.topic(), Some(Topic::Orderbook(market("BTC-USD"))));
+
+        let incoming = r#"{"type":"connected","connection_id":"9a75aff4-923a-4f43-9197-81eefceaacd1","message_id":0}"#;
+        let message = IncomingMessage::from_str(incoming).expect("should be valid");
+        assert!(matches!(message, IncomingMessage::Connected(_)));
+        assert_eq!(message.topic(), None);
+
+        let incoming = r#"{"type":"channel_data","connection_id":"9a75aff4-923a-4f43-9197-81eefceaacd1","message_id":3,"id":"ETH-USD","channel":"v4_orderbook","version":"1.0.0","contents":{"asks":[["3102.1","0"]]}}"#;
+        let message = IncomingMessage::from_str(incoming).expect("should be valid");
+        let IncomingMessage::Orderbook(OrderbookIncomingMessages::ChannelData(data)) = message
+        else {
+            panic!("expected channel data, got {:?}", message);
+        };
+        let batch = ChannelBatchData::from(data);
+        assert_eq!(batch.contents.len(), 1);
+        assert_eq!(batch.message_id, 3);
+
+        let incoming = r#"{"type":"error","message":"Invalid subscribe message: Invalid id: LUNA-USD","connection_id":"9a75aff4-923a-4f43-9197-81eefceaacd1","message_id":4,"channel":"v4_trades","id":"LUNA-USD"}"#;
+        let message = IncomingMessage::from_str(incoming).expect("should be valid");
+        assert_eq!(message.topic(), Some(Topic::Trades(market("LUNA-USD"))));
+        assert_eq!(message.message_id(), 4);
+
+        let incoming = r#"{"type":"error","message":"Too many requests","connection_id":"9a75aff4-923a-4f43-9197-81eefceaacd1","message_id":5}"#;
+        let message = IncomingMessage::from_str(incoming).expect("should be valid");
+        assert!(matches!(message, IncomingMessage::Error(_)));
+        assert_eq!(message.topic(), None);
+
+        let incoming = r#"{"type":"subscribed","connection_id":"9a75aff4-923a-4f43-9197-81eefceaacd1","message_id":6,"id":"ETH-USD","contents":{}}"#;
+        assert!(IncomingMessage::from_str(incoming).is_err());
+    }
+
+    #[test]
+    fn test_parse_trades() {
+        let incoming = r#"{"type":"subscribed","connection_id":"9a75aff4-923a-4f43-9197-81eefceaacd1","message_id":1,"channel":"v4_trades","id":"ETH-USD","contents":{"trades":[{"id":"8ee6d90d-272d-5edd-bf0f-2e4d6ae3d3b7","size":"0.5","price":"3050.1","side":"BUY","createdAt":"2024-05-01T13:30:00.123Z","createdAtHeight":"13412345","type":"LIMIT"},{"id":"1f2e3d4c-272d-5edd-bf0f-2e4d6ae3d3b7","size":"2","price":"3049","side":"SELL","createdAt":"2024-05-01T13:29:58.001Z","type":"LIQUIDATED"}]}}"#;
+        let message = IncomingMessage::from_str(incoming).expect("should be valid");
+        assert_eq!(message.topic(), Some(Topic::Trades(market("ETH-USD"))));
+        let IncomingMessage::Trades(TradesIncomingMessages::Subscribed(subscribed)) = message
+        else {
+            panic!("expected recent trades, got {:?}", message);
+        };
+        let trades = subscribed.contents.trades;
+        assert_eq!(trades.len(), 2);
+        assert_eq!(trades[0].side, OrderSide::Buy);
+        assert_eq!(trades[0].price, Decimal::from_str("3050.1").unwrap());
+        assert_eq!(trades[0].created_at, "2024-05-01T13:30:00.123Z");
+        assert!(!trades[0].is_liquidation());
+        assert!(trades[1].is_liquidation());
+
+        let incoming = r#"{"type":"channel_batch_data","connection_id":"9a75aff4-923a-4f43-9197-81eefceaacd1","message_id":2,"id":"ETH-USD","channel":"v4_trades","version":"2.1.0","contents":[{"trades":[{"id":"a","size":"1","price":"3051","side":"BUY","createdAt":"2024-05-01T13:30:01Z","liquidation":true}]},{"trades":[]}]}"#;
+        let message = IncomingMessage::from_str(incoming).expect("should be valid");
+        let IncomingMessage::Trades(TradesIncomingMessages::ChannelBatchData(batch)) = message
+        else {
+            panic!("expected a batch of trades, got {:?}", message);
+        };
+        assert_eq!(batch.contents.len(), 2);
+        assert!(batch.contents[0].trades[0].is_liquidation());
+        assert_eq!(batch.contents[0].trades[0].trade_type, None);
+    }
+
+    #[test]
+    fn test_parse_candles() {
+        let incoming = r#"{"type":"subscribed","connection_id":"9a75aff4-923a-4f43-9197-81eefceaacd1","message_id":1,"channel":"v4_candles","id":"ETH-USD/1MIN","contents":{"candles":[{"startedAt":"2024-05-01T13:30:00.000Z","ticker":"ETH-USD","resolution":"1MIN","low":"3049","high":"3052.5","open":"3050","close":"3051.2","baseTokenVolume":"12.5","usdVolume":"38140.5","trades":42,"startingOpenInterest":"9876.5","orderbookMidPriceOpen":"3050.05","orderbookMidPriceClose":"3051.15","id":"b4e5a2e1"}]}}"#;
+        let message = IncomingMessage::from_str(incoming).expect("should be valid");
+        let id = CandlesId {
+            market: market("ETH-USD"),
+            resolution: CandleResolution::OneMinute,
+        };
+        assert_eq!(message.topic(), Some(Topic::Candles(id.clone())));
+        let IncomingMessage::Candles(CandlesIncomingMessages::Subscribed(subscribed)) = message
+        else {
+            panic!("expected recent candles, got {:?}", message);
+        };
+        let candle = &subscribed.contents.candles[0];
+        assert_eq!(candle.high, Decimal::from_str("3052.5").unwrap());
+        assert_eq!(candle.trades, 42);
+
+        let incoming = r#"{"type":"channel_data","connection_id":"9a75aff4-923a-4f43-9197-81eefceaacd1","message_id":2,"id":"ETH-USD/1MIN","channel":"v4_candles","version":"1.0.0","contents":{"startedAt":"2024-05-01T13:30:00.000Z","ticker":"ETH-USD","resolution":"1MIN","low":"3049","high":"3053","open":"3050","close":"3053","baseTokenVolume":"13","usdVolume":"39666","trades":43,"startingOpenInterest":"9876.5"}}"#;
+        let message = IncomingMessage::from_str(incoming).expect("should be valid");
+        let IncomingMessage::Candles(CandlesIncomingMessages::ChannelData(data)) = message else {
+            panic!("expected a candle, got {:?}", message);
+        };
+        assert_eq!(data.id, id);
+        assert_eq!(data.contents.orderbook_mid_price_open, None);
+
+        assert_eq!(
+            Topic::new(SocketChannel::Candles, Some("BTC-USD/4HOURS")),
+            Some(Topic::Candles(CandlesId {
+                market: market("BTC-USD"),
+                resolution: CandleResolution::FourHours,
+            }))
+        );
+        assert_eq!(Topic::new(SocketChannel::Candles, Some("BTC-USD")), None);
+        assert_eq!(
+            Topic::new(SocketChannel::Candles, Some("BTC-USD/2MINS")),
+            None
+        );
+    }
+
+    #[test]
+    fn test_parse_block_height() {
+        let incoming = r#"{"type":"subscribed","connection_id":"9a75aff4-923a-4f43-9197-81eefceaacd1","message_id":1,"channel":"v4_block_height","contents":{"height":"13412345","time":"2024-05-01T13:30:00.123Z"}}"#;
+        let message = IncomingMessage::from_str(incoming).expect("should be valid");
+        assert!(message.is_subscribed());
+        assert_eq!(message.topic(), Some(Topic::BlockHeight));
+
+        let incoming = r#"{"type":"channel_data","connection_id":"9a75aff4-923a-4f43-9197-81eefceaacd1","message_id":2,"channel":"v4_block_height","version":"1.0.0","contents":{"blockHeight":"13412346","time":"2024-05-01T13:30:01.456Z"}}"#;
+        let IncomingMessage::BlockHeight(message) = IncomingMessage::from_str(incoming).unwrap()
+        else {
+            panic!("expected a block height message");
+        };
+        assert_eq!(
+            message.into_block(),
+            Some(Block {
+                height: "13412346".to_string(),
+                time: "2024-05-01T13:30:01.456Z".to_string(),
+            })
+        );
+        assert_eq!(
+            serde_json::to_string(&Subscribe::new(&Topic::BlockHeight)).unwrap(),
+            r#"{"type":"subscribe","channel":"v4_block_height","batched":true}"#
+        );
+    }
+
+    #[test]
+    fn test_serialize_unsubscribe() {
+        let unsubscribe = Unsubscribe::new(&Topic::Orderbook(market("ETH-USD")));
+        assert_eq!(
+            serde_json::to_string(&unsubscribe).unwrap(),
+            r#"{"type":"unsubscribe","channel":"v4_orderbook","id":"ETH-USD"}"#
+        );
+        let subscribe = Subscribe::new(&Topic::Trades(market("ETH-USD")));
+        assert_eq!(
+            serde_json::to_string(&subscribe).unwrap(),
+            r#"{"type":"subscribe","channel":"v4_trades","id":"ETH-USD","batched":true}"#
+        );
+        let subscribe = Subscribe::new(&Topic::Candles(CandlesId {
+            market: market("ETH-USD"),
+            resolution: CandleResolution::FiveMinutes,
+        }));
+        assert_eq!(
+            serde_json::to_string(&subscribe).unwrap(),
+            r#"{"type":"subscribe","channel":"v4_candles","id":"ETH-USD/5MINS","batched":true}"#
+        );
+        let subscribe = Subscribe::new(&Topic::Markets);
+        assert_eq!(
+            serde_json::to_string(&subscribe).unwrap(),
+            r#"{"type":"subscribe","channel":"v4_markets","batched":true}"#
+        );
+    }
+
+    #[test]
+    fn test_market_validation() {
+        assert_eq!(market("1INCH-USD").as_str(), "1INCH-USD");
+        assert!(Market::from_str("eth-usd").is_err());
+        assert!(Market::from_str("ETHUSD").is_err());
+        assert!(Market::from_str("ETH--USD").is_err());
+        assert!(from_str::<Market>(r#""ETH-USD/1MIN""#).is_err());
+    }
+
+    #[test]
+    fn test_subaccount_id() {
+        let id = SubaccountId::from_str("dydx1qx2w3e/128").unwrap();
+        assert_eq!((id.address.as_str(), id.number), ("dydx1qx2w3e", 128));
+        assert_eq!(id.to_string(), "dydx1qx2w3e/128");
+        assert!(SubaccountId::from_str("dydx1qx2w3e").is_err());
+        assert!(SubaccountId::from_str("dydx1QX2W3E/0").is_err());
+        assert!(SubaccountId::from_str("/0").is_err());
+        assert!(SubaccountId::from_str("dydx1qx2w3e/-1").is_err());
+        assert_eq!(
+            Topic::new(SocketChannel::Subaccounts, Some("dydx1qx2w3e/0")),
+            Some(Topic::Subaccount(SubaccountId {
+                address: "dydx1qx2w3e".to_string(),
+                number: 0,
+            }))
+        );
+        assert_eq!(Topic::new(SocketChannel::Subaccounts, None), None);
+    }
+
+    #[test]
+    fn test_parse_perpetual_markets() {
+        let incoming = r#"{"markets":{"ETH-USD":{"clobPairId":"1","ticker":"ETH-USD","status":"ACTIVE","oraclePrice":"3050.21","priceChange24H":"-12.5","volume24H":"1000","trades24H":10,"nextFundingRate":"0","initialMarginFraction":"0.05","maintenanceMarginFraction":"0.03","openInterest":"100","atomicResolution":-9,"quantumConversionExponent":-9,"tickSize":"0.1","stepSize":"0.001","stepBaseQuantums":1000000,"subticksPerTick":100000,"marketType":"CROSS"},"LUNA-USD":{"ticker":"LUNA-USD","status":"FINAL_SETTLEMENT","tickSize":"0.0001","stepSize":"10"},"NEW-USD":{"ticker":"NEW-USD","status":"SOMETHING_NEW","tickSize":"1","stepSize":"1"},"odd/ticker":{"ticker":"odd/ticker","status":"ACTIVE","tickSize":"1","stepSize":"1"},"BAD-USD":{"ticker":"BAD-USD","status":"ACTIVE"}}}"#;
+        let parsed: PerpetualMarkets = from_str(incoming).expect("should be valid");
+        let eth = &parsed.markets[&market("ETH-USD")];
+        assert_eq!(eth.status, MarketStatus::Active);
+        assert_eq!(eth.tick_size, Some(Decimal::from_str("0.1").unwrap()));
+        assert_eq!(eth.step_size, Some(Decimal::from_str("0.001").unwrap()));
+        assert_eq!(
+            parsed.markets[&market("LUNA-USD")].status,
+            MarketStatus::FinalSettlement
+        );
+        assert_eq!(
+            parsed.markets[&market("NEW-USD")].status,
+            MarketStatus::Unknown
+        );
+        assert_eq!(parsed.markets[&market("BAD-USD")].tick_size, None);
+        // the odd ticker does not spoil the rest
+        assert_eq!(parsed.markets.len(), 4);
+    }
 }
@@ -0,0 +1,836 @@
+//! Runs the chester binary against the mock indexer and checks what its
+//! clients get.
+
+mod mock_indexer;
+
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt};
+use mock_indexer::{MockIndexer, Step};
+use serde_json::{json, Value};
+use tokio::{
+    net::TcpStream,
+    process::{Child, Command},
+};
+use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A chester process, killed when dropped.
+struct Chester {
+    port: u16,
+    _process: Child,
+}
+
+impl Chester {
+    async fn start(indexer: &MockIndexer, args: &[&str]) -> Self {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let process = Command::new(env!("CARGO_BIN_EXE_chester"))
+            .args(["--port", &port.to_string(), "--network", &indexer.network()])
+            .args(args)
+            .kill_on_drop(true)
+            .spawn()
+            .expect("chester should start");
+        // it listens once the markets are loaded
+        tokio::time::timeout(TIMEOUT, async {
+            while TcpStream::connect(("127.0.0.1", port)).await.is_err() {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .expect("chester should listen");
+        Self {
+            port,
+            _process: process,
+        }
+    }
+
+    async fn connect(&self, query: &str) -> Client {
+        let url = format!("ws://127.0.0.1:{}/?{}", self.port, query);
+        let (socket, _) = connect_async(url)
+            .await
+            .expect("chester should accept clients");
+        Client { socket }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("http://127.0.0.1:{}{}", self.port, path)
+    }
+}
+
+struct Client {
+    socket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+}
+
+impl Client {
+    async fn recv(&mut self) -> Value {
+        loop {
+            let message = tokio::time::timeout(TIMEOUT, self.socket.next())
+                .await
+                .expect("a message should arrive in time")
+                .expect("the connection should stay open")
+                .unwrap();
+            match message {
+                Message::Text(text) => return serde_json::from_str(&text).unwrap(),
+                Message::Ping(_) | Message::Pong(_) => continue,
+                other => panic!("unexpected message {:?}", other),
+            }
+        }
+    }
+
+    async fn send(&mut self, request: Value) {
+        self.socket
+            .send(Message::Text(request.to_string()))
+            .await
+            .unwrap();
+    }
+}
+
+fn snapshot(market: &'static str) -> Step {
+    Step::Subscribed {
+        market,
+        bids: vec![("99", "1"), ("98", "2")],
+        asks: vec![("101", "1")],
+    }
+}
+
+fn trade(id: &str, side: &str, price: &str) -> Value {
+    json!({"id": id, "side": side, "size": "1", "price": price, "createdAt": "2024-05-01T13:30:00.000Z"})
+}
+
+fn candle(started_at: &str, close: &str) -> Value {
+    json!({
+        "startedAt": started_at, "ticker": "ETH-USD", "resolution": "1MIN",
+        "low": "99", "high": "101", "open": "100", "close": close,
+        "baseTokenVolume": "2", "usdVolume": "200", "trades": 2, "startingOpenInterest": "50",
+    })
+}
+
+#[tokio::test]
+async fn test_books_reach_clients() {
+    let indexer = MockIndexer::start(vec![vec![
+        snapshot("ETH-USD"),
+        Step::Batch {
+            market: "ETH-USD",
+            bids: vec![("99", "0"), ("99.5", "3")],
+            asks: vec![],
+        },
+    ]])
+    .await;
+    let chester = Chester::start(&indexer, &[]).await;
+    let mut client = chester.connect("market=ETH-USD").await;
+
+    assert_eq!(
+        client.recv().await,
+        json!({"market": "ETH-USD", "asks": [["101", "1"]], "bids": [["99", "1"], ["98", "2"]]})
+    );
+    assert_eq!(
+        client.recv().await,
+        json!({"market": "ETH-USD", "asks": [["101", "1"]], "bids": [["99.5", "3"], ["98", "2"]]})
+    );
+
+    // the last client leaving ends the upstream subscription
+    drop(client);
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let unsubscribed = indexer
+        .received()
+        .iter()
+        .any(|message| message["type"] == "unsubscribe" && message["id"] == "ETH-USD");
+    assert!(unsubscribed);
+    // and so do the blocks, only followed for the books
+    let unsubscribed = indexer
+        .received()
+        .iter()
+        .any(|message| message["type"] == "unsubscribe" && message["channel"] == "v4_block_height");
+    assert!(unsubscribed);
+}
+
+#[tokio::test]
+async fn test_slow_stream_in_delta_mode() {
+    let batch = |bids| Step::Batch {
+        market: "ETH-USD",
+        bids,
+        asks: vec![],
+    };
+    let indexer = MockIndexer::start(vec![vec![
+        snapshot("ETH-USD"),
+        Step::Sleep(Duration::from_millis(200)),
+        batch(vec![("99", "5")]),
+        Step::Sleep(Duration::from_millis(200)),
+        batch(vec![("98", "0")]),
+    ]])
+    .await;
+    let chester = Chester::start(&indexer, &[]).await;
+    let mut client = chester.connect("market=ETH-USD&mode=delta").await;
+
+    let snapshot = client.recv().await;
+    assert_eq!(snapshot["type"], "snapshot");
+    assert_eq!(snapshot["seq"], 1);
+    assert_eq!(
+        client.recv().await,
+        json!({"type": "delta", "market": "ETH-USD", "seq": 2, "prev_seq": 1, "asks": [], "bids": [["99", "5"]]})
+    );
+    assert_eq!(
+        client.recv().await,
+        json!({"type": "delta", "market": "ETH-USD", "seq": 3, "prev_seq": 2, "asks": [], "bids": [["98", "0"]]})
+    );
+}
+
+#[tokio::test]
+async fn test_gap_triggers_a_resync() {
+    let indexer = MockIndexer::start(vec![vec![
+        snapshot("ETH-USD"),
+        Step::Sleep(Duration::from_millis(100)),
+        Step::Gap(1),
+        Step::Batch {
+            market: "ETH-USD",
+            bids: vec![("97", "1")],
+            asks: vec![],
+        },
+        Step::Unsubscribed { market: "ETH-USD" },
+        Step::Subscribed {
+            market: "ETH-USD",
+            bids: vec![("99", "1")],
+            asks: vec![("100", "4")],
+        },
+    ]])
+    .await;
+    let chester = Chester::start(&indexer, &[]).await;
+    let mut client = chester.connect("market=ETH-USD").await;
+
+    assert_eq!(
+        client.recv().await["bids"],
+        json!([["99", "1"], ["98", "2"]])
+    );
+    let status = client.recv().await;
+    assert_eq!(status["type"], "status");
+    assert_eq!(status["status"], "resyncing");
+    // the delta after the gap never makes it into a book
+    assert_eq!(
+        client.recv().await,
+        json!({"market": "ETH-USD", "asks": [["100", "4"]], "bids": [["99", "1"]]})
+    );
+}
+
+#[tokio::test]
+async fn test_derived_channels_hear_of_resyncs() {
+    let indexer = MockIndexer::start(vec![vec![
+        snapshot("ETH-USD"),
+        Step::Sleep(Duration::from_millis(200)),
+        Step::Gap(1),
+        Step::Batch {
+            market: "ETH-USD",
+            bids: vec![("97", "1")],
+            asks: vec![],
+        },
+        Step::Unsubscribed { market: "ETH-USD" },
+        snapshot("ETH-USD"),
+    ]])
+    .await;
+    let chester = Chester::start(&indexer, &[]).await;
+    let mut client = chester.connect("").await;
+    for (id, channel) in [(1, "ticker"), (2, "stats")] {
+        client
+            .send(json!({"op": "subscribe", "id": id, "channel": channel, "market": "ETH-USD"}))
+            .await;
+    }
+
+    // per channel: subscribed, the first message, the status and the same
+    // message again once the book is back
+    let mut counts = std::collections::BTreeMap::new();
+    for _ in 0..8 {
+        let message = client.recv().await;
+        if message["type"] == "status" {
+            assert_eq!(message["status"], "resyncing");
+            assert_eq!(message["market"], "ETH-USD");
+        }
+        *counts
+            .entry(message["type"].as_str().unwrap().to_string())
+            .or_insert(0) += 1;
+    }
+    for kind in ["subscribed", "ticker", "stats", "status"] {
+        assert_eq!(counts.get(kind), Some(&2), "two {} messages", kind);
+    }
+}
+
+#[tokio::test]
+async fn test_deltas_before_the_snapshot_are_dropped() {
+    let batch = |bids| Step::Batch {
+        market: "ETH-USD",
+        bids,
+        asks: vec![],
+    };
+    let indexer = MockIndexer::start(vec![vec![
+        Step::SubscribeReceived { market: "ETH-USD" },
+        batch(vec![("97", "1")]),
+        Step::Send(json!({
+            "type": "subscribed",
+            "channel": "v4_orderbook",
+            "id": "ETH-USD",
+            "contents": {"bids": [{"price": "99", "size": "1"}], "asks": [{"price": "101", "size": "1"}]},
+        })),
+        Step::Sleep(Duration::from_millis(100)),
+        batch(vec![("98", "2")]),
+    ]])
+    .await;
+    let chester = Chester::start(&indexer, &[]).await;
+    let mut client = chester.connect("market=ETH-USD").await;
+
+    // no resync either, the early delta is simply older than the snapshot
+    assert_eq!(client.recv().await["bids"], json!([["99", "1"]]));
+    assert_eq!(
+        client.recv().await["bids"],
+        json!([["99", "1"], ["98", "2"]])
+    );
+    let resubscribed = indexer
+        .received()
+        .iter()
+        .filter(|message| message["type"] == "subscribe" && message["id"] == "ETH-USD")
+        .count();
+    assert_eq!(resubscribed, 1);
+}
+
+#[tokio::test]
+async fn test_reconnect_after_disconnect() {
+    let indexer = MockIndexer::start(vec![
+        vec![
+            snapshot("ETH-USD"),
+            // lets the first book reach the client before the trouble starts
+            Step::Sleep(Duration::from_millis(100)),
+            Step::Disconnect,
+        ],
+        vec![Step::Subscribed {
+            market: "ETH-USD",
+            bids: vec![("95", "1")],
+            asks: vec![("105", "1")],
+        }],
+    ])
+    .await;
+    let chester = Chester::start(&indexer, &[]).await;
+    let mut client = chester.connect("market=ETH-USD").await;
+
+    assert_eq!(
+        client.recv().await["bids"],
+        json!([["99", "1"], ["98", "2"]])
+    );
+    let status = client.recv().await;
+    assert_eq!(status["status"], "resyncing");
+    assert_eq!(client.recv().await["bids"], json!([["95", "1"]]));
+    assert_eq!(indexer.connections(), 2);
+}
+
+#[tokio::test]
+async fn test_crossed_books_are_flagged_or_trimmed() {
+    let crossed = || {
+        vec![vec![Step::Subscribed {
+            market: "ETH-USD",
+            bids: vec![("101", "1"), ("99", "1")],
+            asks: vec![("100", "2"), ("102", "1")],
+        }]]
+    };
+    let indexer = MockIndexer::start(crossed()).await;
+    let chester = Chester::start(&indexer, &[]).await;
+    let mut client = chester.connect("market=ETH-USD").await;
+    let book = client.recv().await;
+    assert_eq!(
+        book["crossed"],
+        json!({"kind": "crossed", "best_bid": "101", "best_ask": "100"})
+    );
+
+    let indexer = MockIndexer::start(crossed()).await;
+    let chester = Chester::start(&indexer, &["--crossed-book-policy", "trim"]).await;
+    let mut client = chester.connect("market=ETH-USD").await;
+    let book = client.recv().await;
+    // a snapshot has no older side, so nothing is trimmed but it is still flagged
+    assert_eq!(book["crossed"]["kind"], "crossed");
+}
+
+#[tokio::test]
+async fn test_rejected_market_fails_its_clients() {
+    let indexer = MockIndexer::start(vec![vec![
+        snapshot("BTC-USD"),
+        Step::Sleep(Duration::from_millis(100)),
+        Step::Error {
+            market: None,
+            message: "Too many requests",
+        },
+        Step::Error {
+            market: Some("BTC-USD"),
+            message: "Invalid subscribe message: Invalid id: BTC-USD",
+        },
+    ]])
+    .await;
+    let chester = Chester::start(&indexer, &[]).await;
+    let mut client = chester.connect("market=BTC-USD").await;
+
+    assert_eq!(client.recv().await["market"], "BTC-USD");
+    let status = client.recv().await;
+    assert_eq!(status["status"], "failed");
+    assert!(status["reason"]
+        .as_str()
+        .unwrap()
+        .contains("Invalid id: BTC-USD"));
+    client.send(json!({"op": "list", "id": 1})).await;
+    assert_eq!(
+        client.recv().await,
+        json!({"type": "markets", "id": 1, "markets": {}})
+    );
+    // the error about no subscription in particular left the connection be
+    assert_eq!(indexer.connections(), 1);
+    // with no book left, there is nothing to follow the blocks for
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let unsubscribed = indexer
+        .received()
+        .iter()
+        .any(|message| message["type"] == "unsubscribe" && message["channel"] == "v4_block_height");
+    assert!(unsubscribed);
+}
+
+#[tokio::test]
+async fn test_second_connected_message_ends_the_session() {
+    let indexer = MockIndexer::start(vec![
+        vec![
+            snapshot("ETH-USD"),
+            Step::Sleep(Duration::from_millis(100)),
+            Step::Send(json!({"type": "connected"})),
+        ],
+        vec![Step::Subscribed {
+            market: "ETH-USD",
+            bids: vec![("95", "1")],
+            asks: vec![("105", "1")],
+        }],
+    ])
+    .await;
+    let chester = Chester::start(&indexer, &[]).await;
+    let mut client = chester.connect("market=ETH-USD").await;
+
+    assert_eq!(
+        client.recv().await["bids"],
+        json!([["99", "1"], ["98", "2"]])
+    );
+    let status = client.recv().await;
+    assert_eq!(status["status"], "resyncing");
+    assert!(status["reason"]
+        .as_str()
+        .unwrap()
+        .contains("second connected message"));
+    assert_eq!(client.recv().await["bids"], json!([["95", "1"]]));
+    assert_eq!(indexer.connections(), 2);
+}
+
+#[tokio::test]
+async fn test_subscribe_over_the_socket_and_rest() {
+    let indexer = MockIndexer::start(vec![vec![snapshot("ETH-USD")]]).await;
+    let chester = Chester::start(&indexer, &[]).await;
+    let mut client = chester.connect("").await;
+
+    client
+        .send(json!({"op": "subscribe", "id": 1, "markets": ["LUNA-USD"]}))
+        .await;
+    let error = client.recv().await;
+    assert_eq!(error["type"], "error");
+    assert_eq!(error["id"], 1);
+
+    client
+        .send(json!({"op": "subscribe", "id": 2, "channel": "ticker", "markets": ["ETH-USD"]}))
+        .await;
+    assert_eq!(
+        client.recv().await,
+        json!({"type": "subscribed", "id": 2, "channel": "ticker", "markets": ["ETH-USD"]})
+    );
+    let ticker = client.recv().await;
+    assert_eq!(ticker["type"], "ticker");
+    assert_eq!(ticker["bid"], "99");
+    assert_eq!(ticker["ask"], "101");
+    // new options need a new subscription
+    client
+        .send(json!({"op": "subscribe", "id": 3, "channel": "ticker", "markets": ["ETH-USD"], "max_rate": 1}))
+        .await;
+    assert_eq!(
+        client.recv().await,
+        json!({"type": "error", "id": 3, "message": "Already subscribed to market: ETH-USD, unsubscribe first"})
+    );
+
+    let book: Value = reqwest::get(chester.url("/orderbook/ETH-USD?depth=1"))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(book["bids"], json!([["99", "1"]]));
+    let markets: Value = reqwest::get(chester.url("/mock/markets"))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(markets["markets"][0]["status"], "synced");
+}
+
+#[tokio::test]
+async fn test_fine_groups_without_a_tick_size() {
+    let indexer = MockIndexer::start(vec![vec![snapshot("SOL-USD")]]).await;
+    let chester = Chester::start(&indexer, &[]).await;
+    // nothing to check the group against, as in a replay
+    let mut client = chester
+        .connect("market=SOL-USD&group=0.0000000000000000000000000001")
+        .await;
+
+    assert_eq!(
+        client.recv().await,
+        json!({"market": "SOL-USD", "asks": [["101", "1"]], "bids": [["99", "1"], ["98", "2"]]})
+    );
+}
+
+#[tokio::test]
+async fn test_trades_alongside_the_book() {
+    let indexer = MockIndexer::start(vec![vec![
+        snapshot("ETH-USD"),
+        Step::TradesSubscribed {
+            market: "ETH-USD",
+            trades: vec![trade("b", "BUY", "100"), trade("a", "SELL", "99")],
+        },
+        Step::Sleep(Duration::from_millis(100)),
+        Step::Trades {
+            market: "ETH-USD",
+            trades: vec![trade("c", "SELL", "99")],
+        },
+    ]])
+    .await;
+    let chester = Chester::start(&indexer, &[]).await;
+    let mut client = chester.connect("market=ETH-USD").await;
+
+    assert_eq!(client.recv().await["market"], "ETH-USD");
+    client
+        .send(json!({"op": "subscribe", "id": 1, "channel": "trades", "markets": ["ETH-USD"]}))
+        .await;
+    assert_eq!(client.recv().await["type"], "subscribed");
+    let recent = client.recv().await;
+    assert_eq!(recent["type"], "trades");
+    assert_eq!(recent["snapshot"], true);
+    assert_eq!(recent["trades"][0]["id"], "b");
+    assert_eq!(recent["trades"][1]["side"], "sell");
+    assert_eq!(
+        client.recv().await,
+        json!({"type": "trades", "market": "ETH-USD", "snapshot": false, "trades": [
+            {"id": "c", "side": "sell", "size": "1", "price": "99", "created_at": "2024-05-01T13:30:00.000Z", "liquidation": false},
+        ]})
+    );
+
+    let subscribed = indexer.received().into_iter().any(|message| {
+        message == json!({"type": "subscribe", "channel": "v4_trades", "id": "ETH-USD", "batched": true})
+    });
+    assert!(subscribed);
+}
+
+#[tokio::test]
+async fn test_candles_by_resolution() {
+    let indexer = MockIndexer::start(vec![vec![
+        Step::CandlesSubscribed {
+            id: "ETH-USD/1MIN",
+            candles: vec![
+                candle("2024-05-01T13:31:00.000Z", "100"),
+                candle("2024-05-01T13:30:00.000Z", "99.5"),
+            ],
+        },
+        Step::Sleep(Duration::from_millis(100)),
+        Step::Candle {
+            id: "ETH-USD/1MIN",
+            candle: candle("2024-05-01T13:31:00.000Z", "100.5"),
+        },
+    ]])
+    .await;
+    let chester = Chester::start(&indexer, &[]).await;
+    let mut client = chester.connect("").await;
+
+    client
+        .send(json!({"op": "subscribe", "id": 1, "channel": "candles", "market": "ETH-USD"}))
+        .await;
+    assert_eq!(client.recv().await["type"], "error");
+    client
+        .send(json!({"op": "subscribe", "id": 2, "channel": "candles", "market": "ETH-USD", "resolution": "1MIN"}))
+        .await;
+    assert_eq!(
+        client.recv().await,
+        json!({"type": "subscribed", "id": 2, "channel": "candles", "markets": ["ETH-USD"], "resolution": "1MIN"})
+    );
+    let recent = client.recv().await;
+    assert_eq!(recent["snapshot"], true);
+    assert_eq!(recent["resolution"], "1MIN");
+    assert_eq!(recent["candles"].as_array().unwrap().len(), 2);
+    assert_eq!(recent["candles"][1]["close"], "99.5");
+    let changed = client.recv().await;
+    assert_eq!(changed["snapshot"], false);
+    assert_eq!(
+        changed["candles"],
+        json!([{
+            "started_at": "2024-05-01T13:31:00.000Z", "open": "100", "high": "101", "low": "99",
+            "close": "100.5", "base_token_volume": "2", "usd_volume": "200", "trades": 2,
+            "starting_open_interest": "50",
+        }])
+    );
+
+    client.send(json!({"op": "list", "id": 3})).await;
+    assert_eq!(
+        client.recv().await,
+        json!({"type": "markets", "id": 3, "markets": {"candles": ["ETH-USD/1MIN"]}})
+    );
+}
+
+#[tokio::test]
+async fn test_markets_and_oracle_price_in_stats() {
+    let indexer = MockIndexer::start(vec![vec![
+        Step::MarketsSubscribed {
+            markets: json!({"ETH-USD": {
+                "ticker": "ETH-USD", "status": "ACTIVE", "tickSize": "0.5", "stepSize": "1",
+                "oraclePrice": "100", "nextFundingRate": "0.0001", "openInterest": "10", "volume24H": "1000",
+            }}),
+        },
+        snapshot("ETH-USD"),
+        Step::Sleep(Duration::from_millis(100)),
+        Step::Markets(json!({"oraclePrices": {"ETH-USD": {"oraclePrice": "80"}}})),
+    ]])
+    .await;
+    let chester = Chester::start(&indexer, &[]).await;
+    let mut client = chester.connect("channel=markets").await;
+
+    assert_eq!(
+        client.recv().await,
+        json!({"type": "markets", "snapshot": true, "markets": {"ETH-USD": {
+            "status": "ACTIVE", "oracle_price": "100", "next_funding_rate": "0.0001",
+            "open_interest": "10", "volume_24h": "1000",
+        }}})
+    );
+    client
+        .send(json!({"op": "subscribe", "id": 1, "channel": "markets", "market": "ETH-USD"}))
+        .await;
+    assert_eq!(client.recv().await["type"], "error");
+    client
+        .send(json!({"op": "subscribe", "id": 2, "channel": "stats", "market": "ETH-USD", "depth_bps": "79228162514264337593543950335"}))
+        .await;
+    assert_eq!(
+        client.recv().await["message"],
+        "depth_bps must be positive and at most 10000"
+    );
+    client
+        .send(json!({"op": "subscribe", "id": 2, "channel": "stats", "market": "ETH-USD"}))
+        .await;
+    assert_eq!(client.recv().await["type"], "subscribed");
+
+    // the book and the oracle price come in separately, so the stats may
+    // first lack the latter
+    let mut changed = None;
+    loop {
+        let message = client.recv().await;
+        match message["type"].as_str() {
+            Some("markets") => changed = Some(message),
+            Some("stats") if message["oracle_price"] == "80" => {
+                assert_eq!(message["mid"], "100");
+                assert_eq!(message["premium_bps"], "2500");
+                break;
+            }
+            Some("stats") => {}
+            _ => panic!("unexpected message {}", message),
+        }
+    }
+    let changed = match changed {
+        Some(changed) => changed,
+        None => client.recv().await,
+    };
+    assert_eq!(changed["snapshot"], false);
+    assert_eq!(changed["markets"]["ETH-USD"]["oracle_price"], "80");
+    assert_eq!(changed["markets"]["ETH-USD"]["open_interest"], "10");
+
+    client.send(json!({"op": "list", "id": 3})).await;
+    assert_eq!(
+        client.recv().await,
+        json!({"type": "markets", "id": 3, "markets": {"markets": [], "stats": ["ETH-USD"]}})
+    );
+}
+
+fn order(id: &str, side: &str, price: &str, status: &str) -> Value {
+    json!({
+        "id": id, "subaccountId": "s", "clientId": "1", "clobPairId": "1", "side": side,
+        "size": "2", "totalFilled": "0", "price": price, "type": "LIMIT", "status": status,
+        "timeInForce": "GTT", "postOnly": false, "reduceOnly": false, "orderFlags": "64",
+        "ticker": "ETH-USD", "updatedAt": "2024-05-01T13:30:00.000Z",
+    })
+}
+
+#[tokio::test]
+async fn test_subaccount_and_ladder() {
+    let indexer = MockIndexer::start(vec![vec![
+        snapshot("ETH-USD"),
+        Step::SubaccountSubscribed {
+            id: "dydx1abc/0",
+            contents: json!({
+                "subaccount": {"address": "dydx1abc", "subaccountNumber": 0, "openPerpetualPositions": {}},
+                "orders": [order("a", "BUY", "99", "OPEN")],
+            }),
+        },
+        Step::Sleep(Duration::from_millis(100)),
+        Step::Subaccount {
+            id: "dydx1abc/0",
+            contents: json!({"orders": [order("b", "SELL", "103", "OPEN")]}),
+        },
+    ]])
+    .await;
+    let chester = Chester::start(&indexer, &["--subaccounts"]).await;
+    let mut client = chester.connect("market=ETH-USD&mode=delta").await;
+    assert_eq!(client.recv().await["type"], "snapshot");
+
+    client
+        .send(json!({"op": "subscribe", "id": 1, "channel": "ladder", "market": "ETH-USD"}))
+        .await;
+    assert_eq!(client.recv().await["type"], "error");
+    client
+        .send(json!({"op": "subscribe", "id": 2, "channel": "subaccount", "subaccount": "dydx1abc/0"}))
+        .await;
+    assert_eq!(
+        client.recv().await,
+        json!({"type": "subscribed", "id": 2, "channel": "subaccount", "markets": [], "subaccount": "dydx1abc/0"})
+    );
+    let first = client.recv().await;
+    assert_eq!(
+        (&first["type"], &first["snapshot"]),
+        (&json!("subaccount"), &json!(true))
+    );
+    assert_eq!(first["orders"][0]["id"], "a");
+    client
+        .send(json!({
+            "op": "subscribe", "id": 3, "channel": "ladder", "market": "ETH-USD", "subaccount": "dydx1abc/0",
+        }))
+        .await;
+    assert_eq!(client.recv().await["type"], "subscribed");
+
+    // the new order shows up in both, in whichever order
+    let mut ladder = None;
+    let mut changed = None;
+    while ladder.is_none() || changed.is_none() {
+        let message = client.recv().await;
+        match message["type"].as_str() {
+            Some("ladder") if message["asks"].as_array().unwrap().len() == 2 => {
+                ladder = Some(message)
+            }
+            Some("ladder") => {}
+            Some("subaccount") => changed = Some(message),
+            _ => panic!("unexpected message {}", message),
+        }
+    }
+    let ladder = ladder.unwrap();
+    assert_eq!(ladder["bids"][0], json!(["99", "1", "2"]));
+    assert_eq!(ladder["asks"][1], json!(["103", "0", "2"]));
+    let changed = changed.unwrap();
+    assert_eq!(changed["snapshot"], false);
+    assert_eq!(changed["orders"].as_array().unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn test_ladders_of_two_subaccounts() {
+    let subscribed = |id, order| Step::SubaccountSubscribed {
+        id,
+        contents: json!({
+            "subaccount": {"address": "dydx1abc", "subaccountNumber": 0, "openPerpetualPositions": {}},
+            "orders": [order],
+        }),
+    };
+    let indexer = MockIndexer::start(vec![vec![
+        snapshot("ETH-USD"),
+        subscribed("dydx1abc/0", order("a", "BUY", "99", "OPEN")),
+        subscribed("dydx1abc/1", order("b", "SELL", "101", "OPEN")),
+    ]])
+    .await;
+    let chester = Chester::start(&indexer, &["--subaccounts"]).await;
+    let mut client = chester.connect("").await;
+
+    // waits for the ladder of the subaccount to have its orders laid over
+    async fn ladder_of(client: &mut Client, subaccount: &str) -> Value {
+        loop {
+            let message = client.recv().await;
+            assert_eq!(message["type"], "ladder", "unexpected message {}", message);
+            let own = |side: &str| {
+                message[side]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .any(|level| level[2] != "0")
+            };
+            if message["subaccount"] == subaccount && (own("bids") || own("asks")) {
+                return message;
+            }
+        }
+    }
+    for (id, subaccount) in [(1, "dydx1abc/0"), (2, "dydx1abc/1")] {
+        client
+            .send(json!({
+                "op": "subscribe", "id": id, "channel": "ladder", "market": "ETH-USD", "subaccount": subaccount,
+            }))
+            .await;
+        assert_eq!(client.recv().await["type"], "subscribed");
+        ladder_of(&mut client, subaccount).await;
+    }
+
+    client
+        .send(json!({"op": "unsubscribe", "id": 3, "channel": "ladder", "market": "ETH-USD"}))
+        .await;
+    assert_eq!(client.recv().await["type"], "error");
+    client
+        .send(json!({
+            "op": "unsubscribe", "id": 4, "channel": "ladder", "market": "ETH-USD", "subaccount": "dydx1abc/0",
+        }))
+        .await;
+    assert_eq!(client.recv().await["type"], "unsubscribed");
+    client.send(json!({"op": "list", "id": 5})).await;
+    assert_eq!(
+        client.recv().await,
+        json!({"type": "markets", "id": 5, "markets": {"ladder": ["ETH-USD"]}})
+    );
+}
+
+#[tokio::test]
+async fn test_subaccounts_off_by_default() {
+    let indexer = MockIndexer::start(vec![vec![snapshot("ETH-USD")]]).await;
+    let chester = Chester::start(&indexer, &[]).await;
+    let mut client = chester.connect("market=ETH-USD&mode=delta").await;
+    assert_eq!(client.recv().await["type"], "snapshot");
+    client
+        .send(json!({"op": "subscribe", "id": 1, "channel": "subaccount", "subaccount": "dydx1abc/0"}))
+        .await;
+    let error = client.recv().await;
+    assert_eq!(error["type"], "error");
+    assert_eq!(error["id"], 1);
+}
+
+#[tokio::test]
+async fn test_books_carry_the_latest_block() {
+    let indexer = MockIndexer::start(vec![vec![
+        Step::BlockHeightSubscribed("1000"),
+        snapshot("ETH-USD"),
+        Step::Block("1001"),
+        Step::Batch {
+            market: "ETH-USD",
+            bids: vec![("99", "3")],
+            asks: vec![],
+        },
+    ]])
+    .await;
+    let chester = Chester::start(&indexer, &[]).await;
+    let mut client = chester.connect("market=ETH-USD&mode=delta").await;
+
+    let snapshot = client.recv().await;
+    assert_eq!(snapshot["type"], "snapshot");
+    assert_eq!(
+        snapshot["block"],
+        json!({"height": "1000", "time": "2024-05-01T13:30:00.000Z"})
+    );
+    let delta = client.recv().await;
+    assert_eq!(delta["type"], "delta");
+    assert_eq!(
+        delta["block"],
+        json!({"height": "1001", "time": "2024-05-01T13:30:01.000Z"})
+    );
+}
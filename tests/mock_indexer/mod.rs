@@ -0,0 +1,427 @@
+//! A scriptable stand-in for the dYdX v4 indexer, serving its websocket and
+//! the `perpetualMarkets` REST endpoint on a random local port.
+//!
+//! Every websocket connection gets a `connected` message and then plays the
+//! next script of the scenario, one `Step` after the other. Message ids are
+//! consecutive per connection unless a `Step::Gap` skips some.
+
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        State,
+    },
+    response::Response,
+    routing::get,
+    Json, Router,
+};
+use futures_util::{stream::SplitStream, SinkExt, StreamExt};
+use serde_json::{json, Value};
+
+/// Price and size, as decimal strings.
+pub type Level = (&'static str, &'static str);
+
+pub enum Step {
+    /// Waits for a subscription to the market, then answers with a snapshot.
+    Subscribed {
+        market: &'static str,
+        bids: Vec<Level>,
+        asks: Vec<Level>,
+    },
+    /// Waits for a subscription to the market without answering it, the
+    /// answer can follow as a `Send`.
+    SubscribeReceived {
+        market: &'static str,
+    },
+    /// Waits for the market to be unsubscribed, then confirms it.
+    Unsubscribed {
+        market: &'static str,
+    },
+    /// Changes to the book of a market, a size of zero removes the level.
+    Batch {
+        market: &'static str,
+        bids: Vec<Level>,
+        asks: Vec<Level>,
+    },
+    /// Waits for a subscription to the trades of the market, then answers
+    /// with the recent ones, newest first.
+    TradesSubscribed {
+        market: &'static str,
+        trades: Vec<Value>,
+    },
+    /// New trades of a market, newest first.
+    Trades {
+        market: &'static str,
+        trades: Vec<Value>,
+    },
+    /// Waits for a subscription to candles like `ETH-USD/1MIN`, then answers
+    /// with the recent ones, newest first.
+    CandlesSubscribed {
+        id: &'static str,
+        candles: Vec<Value>,
+    },
+    /// A new or changed candle.
+    Candle {
+        id: &'static str,
+        candle: Value,
+    },
+    /// Waits for a subscription to the markets, then answers with them, keyed
+    /// by ticker like the `perpetualMarkets` endpoint has them.
+    MarketsSubscribed {
+        markets: Value,
+    },
+    /// Changes to some markets, as `trading` or `oraclePrices`.
+    Markets(Value),
+    /// Waits for a subscription to the subaccount, `address/number`, then
+    /// answers with its `subaccount` and `orders`.
+    SubaccountSubscribed {
+        id: &'static str,
+        contents: Value,
+    },
+    /// Changed orders, positions, fills or a transfer of the subaccount.
+    Subaccount {
+        id: &'static str,
+        contents: Value,
+    },
+    /// Waits for a subscription to the blocks, then answers with the one at
+    /// `height`.
+    BlockHeightSubscribed(&'static str),
+    /// A new block at the given height.
+    Block(&'static str),
+    /// An error message, about a subscription if a market is given.
+    Error {
+        market: Option<&'static str>,
+        message: &'static str,
+    },
+    /// Any other message, its `connection_id` and `message_id` are filled in.
+    Send(Value),
+    /// Skips message ids, as if messages got lost.
+    Gap(usize),
+    Sleep(Duration),
+    /// Closes the connection, the next one plays the next script.
+    Disconnect,
+}
+
+/// What plays out on each connection, in order. Connections beyond the
+/// scripts only get their `connected` message.
+pub type Scenario = Vec<Vec<Step>>;
+
+#[derive(Clone)]
+struct MockState {
+    scripts: Arc<Mutex<std::vec::IntoIter<Vec<Step>>>>,
+    connections: Arc<Mutex<usize>>,
+    received: Arc<Mutex<Vec<Value>>>,
+}
+
+pub struct MockIndexer {
+    pub addr: SocketAddr,
+    state: MockState,
+}
+
+impl MockIndexer {
+    pub async fn start(scenario: Scenario) -> Self {
+        let state = MockState {
+            scripts: Arc::new(Mutex::new(scenario.into_iter())),
+            connections: Arc::default(),
+            received: Arc::default(),
+        };
+        let app = Router::new()
+            .route("/v4/ws", get(upgrade))
+            .route("/v4/perpetualMarkets", get(perpetual_markets))
+            .with_state(state.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        Self { addr, state }
+    }
+
+    /// The network to point chester at, named `mock`.
+    pub fn network(&self) -> String {
+        format!("mock=ws://{}/v4/ws", self.addr)
+    }
+
+    /// Every message clients have sent so far.
+    pub fn received(&self) -> Vec<Value> {
+        self.state.received.lock().unwrap().clone()
+    }
+
+    pub fn connections(&self) -> usize {
+        *self.state.connections.lock().unwrap()
+    }
+}
+
+async fn perpetual_markets() -> Json<Value> {
+    let market = |ticker: &str, status: &str| json!({"ticker": ticker, "status": status, "tickSize": "0.1", "stepSize": "0.001"});
+    Json(json!({"markets": {
+        "ETH-USD": market("ETH-USD", "ACTIVE"),
+        "BTC-USD": market("BTC-USD", "ACTIVE"),
+        "LUNA-USD": market("LUNA-USD", "FINAL_SETTLEMENT"),
+        // listed without sizes, like the markets of a replay
+        "SOL-USD": {"ticker": "SOL-USD", "status": "ACTIVE"},
+    }}))
+}
+
+async fn upgrade(ws: WebSocketUpgrade, State(state): State<MockState>) -> Response {
+    let connection = {
+        let mut connections = state.connections.lock().unwrap();
+        *connections += 1;
+        *connections
+    };
+    let script = state.scripts.lock().unwrap().next().unwrap_or_default();
+    ws.on_upgrade(move |socket| play(socket, connection, script, state.received))
+}
+
+fn levels(levels: &[Level]) -> Vec<Value> {
+    levels
+        .iter()
+        .map(|(price, size)| json!({"price": price, "size": size}))
+        .collect()
+}
+
+fn pairs(levels: &[Level]) -> Vec<Value> {
+    levels
+        .iter()
+        .map(|(price, size)| json!([price, size]))
+        .collect()
+}
+
+/// Reads client messages until `kind` arrives for the channel of the
+/// market, false if the client went away first.
+async fn wait_for(
+    read: &mut SplitStream<WebSocket>,
+    received: &Mutex<Vec<Value>>,
+    kind: &str,
+    channel: &str,
+    id: Option<&str>,
+) -> bool {
+    while let Some(Ok(message)) = read.next().await {
+        let Message::Text(text) = message else {
+            continue;
+        };
+        let value: Value = serde_json::from_str(&text).expect("clients send json");
+        let found = value["type"] == kind
+            && value["channel"] == channel
+            && value.get("id").and_then(Value::as_str) == id;
+        received.lock().unwrap().push(value);
+        if found {
+            return true;
+        }
+    }
+    false
+}
+
+async fn play(
+    socket: WebSocket,
+    connection: usize,
+    script: Vec<Step>,
+    received: Arc<Mutex<Vec<Value>>>,
+) {
+    let connection_id = format!("mock-connection-{}", connection);
+    let (mut write, mut read) = socket.split();
+    let mut message_id = 0;
+    let connected = json!({"type": "connected", "connection_id": connection_id, "message_id": 0});
+    if write
+        .send(Message::Text(connected.to_string()))
+        .await
+        .is_err()
+    {
+        return;
+    }
+    for step in script {
+        let mut message = match step {
+            Step::Subscribed { market, bids, asks } => {
+                if !wait_for(
+                    &mut read,
+                    &received,
+                    "subscribe",
+                    "v4_orderbook",
+                    Some(market),
+                )
+                .await
+                {
+                    return;
+                }
+                json!({
+                    "type": "subscribed",
+                    "channel": "v4_orderbook",
+                    "id": market,
+                    "contents": {"bids": levels(&bids), "asks": levels(&asks)},
+                })
+            }
+            Step::SubscribeReceived { market } => {
+                if !wait_for(
+                    &mut read,
+                    &received,
+                    "subscribe",
+                    "v4_orderbook",
+                    Some(market),
+                )
+                .await
+                {
+                    return;
+                }
+                continue;
+            }
+            Step::Unsubscribed { market } => {
+                if !wait_for(
+                    &mut read,
+                    &received,
+                    "unsubscribe",
+                    "v4_orderbook",
+                    Some(market),
+                )
+                .await
+                {
+                    return;
+                }
+                json!({"type": "unsubscribed", "channel": "v4_orderbook", "id": market})
+            }
+            Step::Batch { market, bids, asks } => json!({
+                "type": "channel_batch_data",
+                "channel": "v4_orderbook",
+                "id": market,
+                "version": "1.0.0",
+                "contents": [{"bids": pairs(&bids), "asks": pairs(&asks)}],
+            }),
+            Step::TradesSubscribed { market, trades } => {
+                if !wait_for(&mut read, &received, "subscribe", "v4_trades", Some(market)).await {
+                    return;
+                }
+                json!({
+                    "type": "subscribed",
+                    "channel": "v4_trades",
+                    "id": market,
+                    "contents": {"trades": trades},
+                })
+            }
+            Step::Trades { market, trades } => json!({
+                "type": "channel_batch_data",
+                "channel": "v4_trades",
+                "id": market,
+                "version": "2.1.0",
+                "contents": [{"trades": trades}],
+            }),
+            Step::CandlesSubscribed { id, candles } => {
+                if !wait_for(&mut read, &received, "subscribe", "v4_candles", Some(id)).await {
+                    return;
+                }
+                json!({
+                    "type": "subscribed",
+                    "channel": "v4_candles",
+                    "id": id,
+                    "contents": {"candles": candles},
+                })
+            }
+            Step::Candle { id, candle } => json!({
+                "type": "channel_data",
+                "channel": "v4_candles",
+                "id": id,
+                "version": "1.0.0",
+                "contents": candle,
+            }),
+            Step::MarketsSubscribed { markets } => {
+                if !wait_for(&mut read, &received, "subscribe", "v4_markets", None).await {
+                    return;
+                }
+                json!({
+                    "type": "subscribed",
+                    "channel": "v4_markets",
+                    "contents": {"markets": markets},
+                })
+            }
+            Step::Markets(contents) => json!({
+                "type": "channel_data",
+                "channel": "v4_markets",
+                "version": "1.0.0",
+                "contents": contents,
+            }),
+            Step::SubaccountSubscribed { id, contents } => {
+                if !wait_for(
+                    &mut read,
+                    &received,
+                    "subscribe",
+                    "v4_subaccounts",
+                    Some(id),
+                )
+                .await
+                {
+                    return;
+                }
+                json!({
+                    "type": "subscribed",
+                    "channel": "v4_subaccounts",
+                    "id": id,
+                    "contents": contents,
+                })
+            }
+            Step::Subaccount { id, contents } => json!({
+                "type": "channel_data",
+                "channel": "v4_subaccounts",
+                "id": id,
+                "version": "3.0.0",
+                "contents": contents,
+            }),
+            Step::BlockHeightSubscribed(height) => {
+                if !wait_for(&mut read, &received, "subscribe", "v4_block_height", None).await {
+                    return;
+                }
+                json!({
+                    "type": "subscribed",
+                    "channel": "v4_block_height",
+                    "contents": {"height": height, "time": "2024-05-01T13:30:00.000Z"},
+                })
+            }
+            Step::Block(height) => json!({
+                "type": "channel_data",
+                "channel": "v4_block_height",
+                "version": "1.0.0",
+                "contents": {"blockHeight": height, "time": "2024-05-01T13:30:01.000Z"},
+            }),
+            Step::Error {
+                market: Some(market),
+                message,
+            } => {
+                json!({"type": "error", "message": message, "channel": "v4_orderbook", "id": market})
+            }
+            Step::Error {
+                market: None,
+                message,
+            } => json!({"type": "error", "message": message}),
+            Step::Send(message) => message,
+            Step::Gap(skipped) => {
+                message_id += skipped;
+                continue;
+            }
+            Step::Sleep(duration) => {
+                tokio::time::sleep(duration).await;
+                continue;
+            }
+            Step::Disconnect => {
+                let _ = write.send(Message::Close(None)).await;
+                return;
+            }
+        };
+        message_id += 1;
+        message["connection_id"] = json!(connection_id);
+        message["message_id"] = json!(message_id);
+        if write
+            .send(Message::Text(message.to_string()))
+            .await
+            .is_err()
+        {
+            return;
+        }
+    }
+    // keeps the connection open, noting what the client still sends
+    while let Some(Ok(message)) = read.next().await {
+        if let Message::Text(text) = message {
+            let value = serde_json::from_str(&text).expect("clients send json");
+            received.lock().unwrap().push(value);
+        }
+    }
+}